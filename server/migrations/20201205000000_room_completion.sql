@@ -0,0 +1,6 @@
+/* unix timestamps in milliseconds. started_at is null for rooms written before
+ * this column existed. */
+alter table rooms add column started_at integer;
+/* both of these are null until the room's puzzle has been solved */
+alter table rooms add column solve_time_ms integer;
+alter table rooms add column solved_by integer;
@@ -0,0 +1,5 @@
+/* 128-bit secrets, stored as blobs for the same reason as rooms.id. These are
+ * null for rooms written before this column existed, and those rooms get new
+ * random keys the next time they're loaded. */
+alter table rooms add column editor_key blob;
+alter table rooms add column viewer_key blob;
@@ -0,0 +1,21 @@
+/* the room's revision when it was written, so that revisions in room_events
+ * keep increasing after the room is loaded again. null for rooms written before
+ * this column existed. */
+alter table rooms add column revision integer;
+/* An append-only log of every change made to a room's board. Replaying a
+ * room's events in seq order rebuilds its board. */
+create table if not exists room_events
+(
+    room_id    blob    not null,
+    seq        integer not null,
+    /* unix timestamp in milliseconds */
+    applied_at integer not null,
+    session_id integer not null,
+    /* null for events that weren't sent with a sync id, like SetBoard */
+    sync_id    integer,
+    /* the room's revision after the event was applied */
+    revision   integer not null,
+    /* a RoomEventKind, serialized as JSON */
+    event      text    not null,
+    primary key (room_id, seq)
+);
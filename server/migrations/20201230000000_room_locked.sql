@@ -0,0 +1,3 @@
+/* Locked rooms can't have their boards changed. Rooms can be locked through
+ * the admin API. */
+alter table rooms add column locked boolean not null default false;
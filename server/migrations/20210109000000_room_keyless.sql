@@ -0,0 +1,5 @@
+/* Rooms written before access keys existed were joined with just their id, and
+ * the random keys they get when they're loaded aren't in anybody's links. Let
+ * them be joined without a key until somebody joins with one of their keys. */
+alter table rooms add column keyless boolean not null default false;
+update rooms set keyless = true where editor_key is null;
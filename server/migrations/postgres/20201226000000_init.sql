@@ -0,0 +1,34 @@
+/* The same layout as the SQLite migrations in the parent directory, all at
+ * once, since there were never any older Postgres databases to migrate. */
+create table if not exists rooms
+(
+    /* 128-bit ids and keys are stored as little-endian bytes, since Postgres
+     * doesn't have an unsigned 128-bit type either. */
+    id            bytea primary key not null,
+    board         bytea             not null,
+    /* unix timestamps in milliseconds */
+    started_at    bigint            not null,
+    /* both of these are null until the room's puzzle has been solved */
+    solve_time_ms bigint,
+    solved_by     bigint,
+    editor_key    bytea             not null,
+    viewer_key    bytea             not null,
+    revision      bigint            not null
+);
+/* An append-only log of every change made to a room's board. Replaying a
+ * room's events in seq order rebuilds its board. */
+create table if not exists room_events
+(
+    room_id    bytea  not null,
+    seq        bigint not null,
+    /* unix timestamp in milliseconds */
+    applied_at bigint not null,
+    session_id bigint not null,
+    /* null for events that weren't sent with a sync id, like SetBoard */
+    sync_id    bigint,
+    /* the room's revision after the event was applied */
+    revision   bigint not null,
+    /* a RoomEventKind, serialized as JSON */
+    event      text   not null,
+    primary key (room_id, seq)
+);
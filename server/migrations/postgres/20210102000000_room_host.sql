@@ -0,0 +1,5 @@
+/* The host key lets a room's host take the role back after reconnecting, and
+ * closed rooms can't be joined anymore. Rooms from before this keep a random
+ * host key until they're written back. */
+alter table rooms add column host_key bytea;
+alter table rooms add column closed boolean not null default false;
@@ -0,0 +1,3 @@
+/* Matches the SQLite migration. Postgres rooms have always had access keys, so
+ * none of them start out keyless. */
+alter table rooms add column keyless boolean not null default false;
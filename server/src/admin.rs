@@ -0,0 +1,345 @@
+//! An API for operators to look into and manage rooms. Every request has to send the token from
+//! `admin.token` as `Authorization: Bearer <token>`. Without it (or if no token is configured),
+//! these routes don't exist.
+//!
+//! - `GET /api/v1/admin/rooms` lists the rooms resident in memory, with their session counts.
+//! - `GET /api/v1/admin/rooms/{room_id}` returns a room's board, reading it from storage if it
+//!   isn't resident.
+//! - `POST /api/v1/admin/writeback` writes every dirty room back to storage.
+//! - `POST /api/v1/admin/rooms/{room_id}/evict` writes a room back and evicts it from memory.
+//!   Rooms with sessions can't be evicted, so kick them first.
+//! - `POST /api/v1/admin/rooms/{room_id}/lock` and `.../unlock` stop or allow changes to the board.
+//! - `POST /api/v1/admin/rooms/{room_id}/kick` disconnects every session in the room, or only
+//!   `?session={session_id}`. Kicked sessions can't be resumed.
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reject::Rejection;
+use warp::{Filter, Reply};
+
+use crate::config::Config;
+use crate::global_state::{Eviction, GlobalState};
+use crate::realtime::InternalErrorReject;
+use crate::room::{Kick, RoomId, RoomState, SessionId};
+use crate::storage::{self, Storage};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomSummary {
+    room_id: String,
+    players: usize,
+    spectators: usize,
+    locked: bool,
+    /// Set if the room has changes that haven't been written back yet.
+    dirty: bool,
+    /// Seconds since a session joined, left, or changed the board.
+    idle_secs: u64,
+}
+
+#[derive(Deserialize)]
+struct KickQuery {
+    session: Option<SessionId>,
+}
+
+pub fn get_filter(
+    config: Arc<Config>,
+    global_state: Arc<GlobalState>,
+    storage: Arc<dyn Storage>,
+) -> BoxedFilter<(impl Reply,)> {
+    let global_state = warp::any().map(move || global_state.clone());
+    let storage = warp::any().map(move || storage.clone());
+
+    let list_rooms = warp::path!("rooms")
+        .and(warp::get())
+        .and(global_state.clone())
+        .and_then(list_rooms);
+    let get_board = warp::path!("rooms" / RoomId)
+        .and(warp::get())
+        .and(global_state.clone())
+        .and(storage.clone())
+        .and_then(|room_id, global_state, storage| async move {
+            let room_state = find_room(&global_state, &storage, room_id).await?;
+            let rs = room_state.lock().await;
+            Result::<_, Rejection>::Ok(warp::reply::json(&rs.board))
+        });
+    let writeback = warp::path!("writeback")
+        .and(warp::post())
+        .and(global_state.clone())
+        .and(storage.clone())
+        .and_then(
+            |global_state: Arc<GlobalState>, storage: Arc<dyn Storage>| async move {
+                let written =
+                    storage::writeback(&*storage, &global_state)
+                        .await
+                        .map_err(|err| {
+                            error!("Admin writeback failed: {}", err);
+                            warp::reject::custom(InternalErrorReject)
+                        })?;
+                info!("Admin writeback wrote {} rooms", written);
+                Result::<_, Rejection>::Ok(warp::reply::json(&serde_json::json!({
+                    "written": written
+                })))
+            },
+        );
+    let evict = warp::path!("rooms" / RoomId / "evict")
+        .and(warp::post())
+        .and(global_state.clone())
+        .and(storage.clone())
+        .and_then(
+            |room_id, global_state: Arc<GlobalState>, storage: Arc<dyn Storage>| async move {
+                let eviction =
+                    global_state
+                        .evict_room(&*storage, &room_id)
+                        .await
+                        .map_err(|err| {
+                            error!("Failed to write back room {} to evict it: {}", room_id, err);
+                            warp::reject::custom(InternalErrorReject)
+                        })?;
+                let status = match eviction {
+                    Eviction::Evicted => StatusCode::NO_CONTENT,
+                    Eviction::NotResident => StatusCode::NOT_FOUND,
+                    Eviction::HasSessions => StatusCode::CONFLICT,
+                };
+                Result::<_, Rejection>::Ok(warp::reply::with_status(warp::reply(), status))
+            },
+        );
+    let lock = warp::path!("rooms" / RoomId / String)
+        .and(warp::post())
+        .and(global_state.clone())
+        .and(storage.clone())
+        .and_then(
+            |room_id, action: String, global_state, storage| async move {
+                let locked = match action.as_str() {
+                    "lock" => true,
+                    "unlock" => false,
+                    _ => return Err(warp::reject::not_found()),
+                };
+                let room_state = find_room(&global_state, &storage, room_id).await?;
+                room_state.lock().await.set_locked(locked);
+                info!("Admin set locked = {} for room {}", locked, room_id);
+                Ok(warp::reply::json(&serde_json::json!({ "locked": locked })))
+            },
+        );
+    let kick = warp::path!("rooms" / RoomId / "kick")
+        .and(warp::post())
+        .and(warp::query::<KickQuery>())
+        .and(global_state)
+        .and(storage)
+        .and_then(
+            |room_id, query: KickQuery, global_state, storage| async move {
+                let room_state = find_room(&global_state, &storage, room_id).await?;
+                let kick = match query.session {
+                    Some(session_id) => Kick::Session(session_id),
+                    None => Kick::Everyone,
+                };
+                room_state.lock().await.kick(kick);
+                info!("Admin kicked {:?} from room {}", kick, room_id);
+                Result::<_, Rejection>::Ok(warp::reply::with_status(
+                    warp::reply(),
+                    StatusCode::NO_CONTENT,
+                ))
+            },
+        );
+
+    warp::path!("api" / "v1" / "admin" / ..)
+        .and(authorize(config.admin.token.clone()))
+        .and(
+            list_rooms
+                .or(get_board)
+                .or(writeback)
+                .or(evict)
+                .or(kick)
+                .or(lock),
+        )
+        .boxed()
+}
+
+/// Rejects requests that don't have the right bearer token with a 404, the same as if the admin
+/// API didn't exist.
+fn authorize(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let expected = token.map(|token| Arc::new(format!("Bearer {}", token)));
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let expected = expected.clone();
+            async move {
+                match (expected, header) {
+                    (Some(expected), Some(header)) if constant_time_eq(&expected, &header) => {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Compares two strings without bailing out early, so the token can't be guessed a character at
+/// a time by timing requests.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn list_rooms(global_state: Arc<GlobalState>) -> Result<impl Reply, Rejection> {
+    let mut rooms = Vec::new();
+    for (room_id, room_state) in global_state.resident_rooms().await {
+        let rs = room_state.lock().await;
+        let (players, spectators) = rs.session_counts();
+        rooms.push(RoomSummary {
+            room_id: room_id.to_string(),
+            players,
+            spectators,
+            locked: rs.locked,
+            dirty: rs.dirty,
+            idle_secs: rs.last_activity.elapsed().as_secs(),
+        });
+    }
+    Ok(warp::reply::json(&rooms))
+}
+
+/// Like `realtime::find_room`, but without needing a key.
+async fn find_room(
+    global_state: &Arc<GlobalState>,
+    storage: &Arc<dyn Storage>,
+    room_id: RoomId,
+) -> Result<Arc<Mutex<RoomState>>, Rejection> {
+    global_state
+        .get_room(storage, &room_id)
+        .await
+        .map_err(|_| warp::reject::custom(InternalErrorReject))?
+        .ok_or_else(warp::reject::not_found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LimitsConfig;
+    use crate::error::SudokuError;
+    use crate::room::SessionRole;
+    use crate::storage::MemoryStorage;
+
+    const TOKEN: &str = "Bearer hunter2";
+
+    fn setup() -> (Arc<GlobalState>, BoxedFilter<(impl Reply,)>) {
+        let config: Config = toml::from_str("[admin]\ntoken = \"hunter2\"").unwrap();
+        let global_state = Arc::new(GlobalState::default());
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let filter = get_filter(Arc::new(config), global_state.clone(), storage);
+        (global_state, filter)
+    }
+
+    #[tokio::test]
+    async fn requires_token() {
+        let (_global_state, filter) = setup();
+        let request = || warp::test::request().path("/api/v1/admin/rooms");
+        assert_eq!(request().reply(&filter).await.status(), 404);
+        assert_eq!(
+            request()
+                .header("authorization", "Bearer hunter3")
+                .reply(&filter)
+                .await
+                .status(),
+            404
+        );
+        assert_eq!(
+            request()
+                .header("authorization", TOKEN)
+                .reply(&filter)
+                .await
+                .status(),
+            200
+        );
+
+        // without a configured token, nothing gets in
+        let filter = get_filter(
+            Arc::new(Config::default()),
+            Arc::new(GlobalState::default()),
+            Arc::new(MemoryStorage::default()),
+        );
+        assert_eq!(
+            request()
+                .header("authorization", "Bearer ")
+                .reply(&filter)
+                .await
+                .status(),
+            404
+        );
+    }
+
+    #[tokio::test]
+    async fn manage_room() {
+        let (global_state, filter) = setup();
+        let room_id = RoomId::random();
+        let room_state = Arc::new(Mutex::new(RoomState::new(
+            room_id,
+            &LimitsConfig::default(),
+        )));
+        global_state.insert_room(room_id, room_state.clone()).await;
+        let session = room_state
+            .lock()
+            .await
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        let request = |method: &str, path: String| {
+            warp::test::request()
+                .method(method)
+                .path(&format!("/api/v1/admin/rooms{}", path))
+                .header("authorization", TOKEN)
+        };
+
+        let response = request("GET", String::new()).reply(&filter).await;
+        let rooms: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(rooms[0]["roomId"], room_id.to_string());
+        assert_eq!(rooms[0]["players"], 1);
+
+        let response = request("GET", format!("/{}", room_id)).reply(&filter).await;
+        assert_eq!(response.status(), 200);
+
+        let response = request("POST", format!("/{}/lock", room_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert!(matches!(
+            room_state
+                .lock()
+                .await
+                .apply_diffs(session.session_id, 1, None, vec![]),
+            Err(SudokuError::RoomLocked)
+        ));
+
+        // rooms with sessions can't be evicted until they're kicked out
+        let response = request("POST", format!("/{}/evict", room_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 409);
+        let mut notice_rx = session.notice_rx;
+        let response = request("POST", format!("/{}/kick", room_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+        // skip over the lock notice
+        notice_rx.recv().await.unwrap();
+        assert!(matches!(
+            notice_rx.recv().await.unwrap(),
+            crate::room::RoomNotice::Kick(Kick::Everyone)
+        ));
+        // the realtime API ends the session once it gets the notice
+        room_state
+            .lock()
+            .await
+            .end_session(session.session_id, SessionRole::Player);
+        drop(room_state);
+        let response = request("POST", format!("/{}/evict", room_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+        assert_eq!(global_state.resident_room_count().await, 0);
+    }
+}
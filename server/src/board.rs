@@ -1,20 +1,43 @@
 use serde::{Deserialize, Serialize};
 
+use crate::color::{Color, ColorBitFlags};
 use crate::digit::{Digit, DigitBitFlags};
 use crate::error::SudokuError;
 
+/// The version byte written at the start of serialized boards. Older versions are migrated when
+/// they're read:
+///
+/// - 0: Written before colors existed. There's no version byte (these are recognized by their
+///   length instead), and squares are only 6 bytes.
+/// - 1: Squares gained 2 bytes of colors.
+/// - 2: Bitflags are little-endian. They used to be native-endian, which we assume matches the
+///   machine reading them.
+#[cfg(feature = "sql")]
+const SQL_BOARD_VERSION: u8 = 2;
+#[cfg(feature = "sql")]
+const SQL_SQUARE_SIZE: usize = 8;
+/// Squares in version 0 boards didn't have colors.
+#[cfg(feature = "sql")]
+const SQL_V0_SQUARE_SIZE: usize = 6;
+/// The offsets of the `u16` bitflags within a serialized square.
+#[cfg(feature = "sql")]
+const SQL_SQUARE_FLAG_OFFSETS: [usize; 3] = [1, 3, 6];
+
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BoardSquare {
     pub number: Option<Digit>,
     pub corners: DigitBitFlags,
     pub centers: DigitBitFlags,
+    #[serde(default)]
+    pub colors: ColorBitFlags,
     pub locked: bool,
 }
 
 impl BoardSquare {
     fn apply(&mut self, diff: &BoardDiffOperation) {
-        if self.locked {
+        // locked squares can only be unlocked
+        if self.locked && !matches!(diff, BoardDiffOperation::SetLocked { .. }) {
             return;
         }
         match *diff {
@@ -55,22 +78,35 @@ impl BoardSquare {
             } => {
                 self.corners = Default::default();
             }
+            BoardDiffOperation::AddColor { color } => {
+                self.colors.insert(color);
+            }
+            BoardDiffOperation::RemoveColor { color } => {
+                self.colors.remove(color);
+            }
+            BoardDiffOperation::ClearColors => {
+                self.colors = Default::default();
+            }
+            BoardDiffOperation::SetLocked { locked } => {
+                self.locked = locked;
+            }
         }
     }
 
     #[cfg(feature = "sql")]
-    pub fn sql_serialize(&self) -> [u8; 6] {
+    pub fn sql_serialize(&self) -> [u8; SQL_SQUARE_SIZE] {
         let number = self.number.map(|v| v.into()).unwrap_or(0);
         let corners = self.corners.sql_serialize();
         let centers = self.centers.sql_serialize();
         let locked = self.locked.into();
+        let colors = self.colors.sql_serialize();
         [
-            number, corners[0], corners[1], centers[0], centers[1], locked,
+            number, corners[0], corners[1], centers[0], centers[1], locked, colors[0], colors[1],
         ]
     }
 
     #[cfg(feature = "sql")]
-    pub fn sql_deserialize(bytes: &[u8; 6]) -> Result<Self, &'static str> {
+    pub fn sql_deserialize(bytes: &[u8; SQL_SQUARE_SIZE]) -> Result<Self, &'static str> {
         use std::convert::TryFrom;
 
         Ok(BoardSquare {
@@ -85,6 +121,7 @@ impl BoardSquare {
                 1 => true,
                 _ => return Err("locked must be 0 or 1"),
             },
+            colors: ColorBitFlags::sql_deserialize([bytes[6], bytes[7]])?,
         })
     }
 }
@@ -96,6 +133,55 @@ pub struct BoardState {
 }
 
 impl BoardState {
+    pub fn from_squares(squares: Vec<BoardSquare>) -> Result<Self, SudokuError> {
+        let board = BoardState { squares };
+        board.validate()?;
+        Ok(board)
+    }
+
+    pub fn squares(&self) -> &[BoardSquare] {
+        &self.squares
+    }
+
+    /// Boards sent by clients are deserialized without any checks, so this should be called
+    /// before one replaces a room's board.
+    pub fn validate(&self) -> Result<(), SudokuError> {
+        if self.squares.len() != 81 {
+            return Err(SudokuError::InvalidSquareCount(self.squares.len()));
+        }
+        Ok(())
+    }
+
+    /// Returns the indexes of every square whose number also appears somewhere else in the same
+    /// row, column, or box, in ascending order. This is what the client highlights as errors.
+    pub fn conflicts(&self) -> Vec<u8> {
+        let mut is_conflict = [false; 81];
+        for (idx, sq) in self.squares.iter().enumerate().take(81) {
+            let number = match sq.number {
+                Some(number) => number,
+                None => continue,
+            };
+            let (row, col, bx) = square_houses(idx);
+            for (other_idx, other_sq) in self.squares.iter().enumerate().take(81).skip(idx + 1) {
+                if other_sq.number != Some(number) {
+                    continue;
+                }
+                let (other_row, other_col, other_bx) = square_houses(other_idx);
+                if row == other_row || col == other_col || bx == other_bx {
+                    is_conflict[idx] = true;
+                    is_conflict[other_idx] = true;
+                }
+            }
+        }
+        (0..81u8).filter(|idx| is_conflict[*idx as usize]).collect()
+    }
+
+    /// Returns true if every square has a number. Combined with `conflicts`, this tells us if the
+    /// puzzle has been solved.
+    pub fn is_filled(&self) -> bool {
+        self.squares.iter().all(|sq| sq.number.is_some())
+    }
+
     pub fn apply(&mut self, diff: &BoardDiff) -> Result<(), SudokuError> {
         if diff.squares.len() > self.squares.len() {
             // not strictly needed, but provide a sanity check
@@ -113,38 +199,63 @@ impl BoardState {
         Ok(())
     }
 
+    /// Serializes the board for the database as a version byte followed by every square.
     #[cfg(feature = "sql")]
-    pub fn sql_serialize(&self) -> [u8; 81 * 6] {
-        use std::convert::TryInto;
-
-        let mut result = Vec::with_capacity(81 * 6);
+    pub fn sql_serialize(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(1 + self.squares.len() * SQL_SQUARE_SIZE);
+        result.push(SQL_BOARD_VERSION);
         for sq in self.squares.iter() {
             result.extend_from_slice(&sq.sql_serialize());
         }
-        // use expect() assuming `self.squares` can't be malformed since it's an internal
-        // datastructure
         result
-            .try_into()
-            .expect("return value of sql_serialize must match the size of the serialized squares")
     }
 
+    /// Reads a board written by `sql_serialize`, or by an older version of it.
     #[cfg(feature = "sql")]
-    pub fn sql_deserialize(bytes: &[u8; 81 * 6]) -> Result<Self, &'static str> {
-        use std::convert::TryInto;
-
-        let squares: Vec<BoardSquare> = bytes
-            .chunks_exact(6)
+    pub fn sql_deserialize(bytes: &[u8]) -> Result<Self, &'static str> {
+        let (version, body) = if bytes.len() == 81 * SQL_V0_SQUARE_SIZE {
+            (0, bytes)
+        } else {
+            match bytes.split_first() {
+                Some((&version, body)) if (1..=SQL_BOARD_VERSION).contains(&version) => {
+                    (version, body)
+                }
+                _ => return Err("unknown board version when deserializing sql"),
+            }
+        };
+        let square_size = if version == 0 {
+            SQL_V0_SQUARE_SIZE
+        } else {
+            SQL_SQUARE_SIZE
+        };
+        if body.len() != 81 * square_size {
+            return Err("expected 81 squares when deserializing sql");
+        }
+        let squares: Vec<BoardSquare> = body
+            .chunks_exact(square_size)
             .map(|b| {
-                BoardSquare::sql_deserialize(b.try_into().or(Err("all squares should be 6 bytes"))?)
+                let mut square = [0; SQL_SQUARE_SIZE];
+                square[..square_size].copy_from_slice(b);
+                if version < 2 {
+                    for &offset in SQL_SQUARE_FLAG_OFFSETS.iter() {
+                        let flags = u16::from_ne_bytes([square[offset], square[offset + 1]]);
+                        square[offset..offset + 2].copy_from_slice(&flags.to_le_bytes());
+                    }
+                }
+                BoardSquare::sql_deserialize(&square)
             })
             .collect::<Result<_, _>>()?;
-        if squares.len() != 81 {
-            return Err("expected 81 squares when deserializing sql");
-        }
         Ok(BoardState { squares })
     }
 }
 
+/// Returns the row, column, and box (numbered left-to-right, top-to-bottom) that the square at
+/// `idx` belongs to.
+pub fn square_houses(idx: usize) -> (usize, usize, usize) {
+    let (row, col) = (idx / 9, idx % 9);
+    (row, col, (row / 3) * 3 + col / 3)
+}
+
 impl Default for BoardState {
     fn default() -> BoardState {
         BoardState {
@@ -153,25 +264,27 @@ impl Default for BoardState {
     }
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BoardDiff {
     pub squares: Vec<u8>,
     pub operation: BoardDiffOperation,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub enum BoardPencilType {
     Centers,
     Corners,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(tag = "fn", rename_all = "camelCase")]
 pub enum BoardDiffOperation {
     #[serde(rename_all = "camelCase")]
-    SetNumber { digit: Option<Digit> },
+    SetNumber {
+        digit: Option<Digit>,
+    },
     #[serde(rename_all = "camelCase")]
     AddPencilMark {
         r#type: BoardPencilType,
@@ -183,13 +296,64 @@ pub enum BoardDiffOperation {
         digit: Digit,
     },
     #[serde(rename_all = "camelCase")]
-    ClearPencilMarks { r#type: BoardPencilType },
+    ClearPencilMarks {
+        r#type: BoardPencilType,
+    },
+    #[serde(rename_all = "camelCase")]
+    AddColor {
+        color: Color,
+    },
+    #[serde(rename_all = "camelCase")]
+    RemoveColor {
+        color: Color,
+    },
+    ClearColors,
+    /// Locks or unlocks squares, regardless of what's in them. This is the only operation that
+    /// applies to locked squares, and only the host can send it.
+    #[serde(rename_all = "camelCase")]
+    SetLocked {
+        locked: bool,
+    },
 }
 
 #[cfg(test)]
 mod tests {
+    use serde_json::json;
+    use std::convert::TryFrom;
+
     use super::*;
 
+    fn set_number(bs: &mut BoardState, squares: Vec<u8>, digit: Digit) {
+        bs.apply(&BoardDiff {
+            squares,
+            operation: BoardDiffOperation::SetNumber { digit: Some(digit) },
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut bs = BoardState::default();
+        assert_eq!(bs.conflicts(), Vec::<u8>::new());
+
+        // different digits in the same row don't conflict, and neither does the same digit in
+        // unrelated squares
+        set_number(&mut bs, vec![0], Digit::D1);
+        set_number(&mut bs, vec![1], Digit::D2);
+        set_number(&mut bs, vec![40], Digit::D1);
+        assert_eq!(bs.conflicts(), Vec::<u8>::new());
+
+        // row
+        set_number(&mut bs, vec![8], Digit::D2);
+        assert_eq!(bs.conflicts(), vec![1, 8]);
+        // column
+        set_number(&mut bs, vec![76], Digit::D1);
+        assert_eq!(bs.conflicts(), vec![1, 8, 40, 76]);
+        // box (but not row or column)
+        set_number(&mut bs, vec![20], Digit::D1);
+        assert_eq!(bs.conflicts(), vec![0, 1, 8, 20, 40, 76]);
+    }
+
     #[test]
     #[cfg(feature = "sql")]
     fn board_state_sql_serialize_deserialize() {
@@ -217,9 +381,131 @@ mod tests {
             },
         })
         .unwrap();
+        bs.apply(&BoardDiff {
+            squares: vec![9, 10],
+            operation: BoardDiffOperation::AddColor {
+                color: Color::try_from(3).unwrap(),
+            },
+        })
+        .unwrap();
         assert_eq!(
             BoardState::sql_deserialize(&bs.sql_serialize()).unwrap(),
             bs
         );
     }
+
+    #[test]
+    #[cfg(feature = "sql")]
+    fn board_state_sql_deserialize_old_versions() {
+        let mut bs = BoardState::default();
+        bs.squares[0].number = Some(Digit::D7);
+        bs.squares[1].locked = true;
+        bs.squares[2].corners = DigitBitFlags::from(vec![Digit::D1, Digit::D9]);
+        bs.squares[3].centers = DigitBitFlags::from(vec![Digit::D8]);
+
+        // version 0 had no version byte or colors, and native-endian bitflags
+        let mut v0 = Vec::new();
+        for sq in bs.squares() {
+            let mut bytes = sq.sql_serialize();
+            for &offset in SQL_SQUARE_FLAG_OFFSETS.iter() {
+                let flags = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+                bytes[offset..offset + 2].copy_from_slice(&flags.to_ne_bytes());
+            }
+            v0.extend_from_slice(&bytes[..SQL_V0_SQUARE_SIZE]);
+        }
+        assert_eq!(BoardState::sql_deserialize(&v0).unwrap(), bs);
+
+        // version 1 added colors, still native-endian
+        bs.squares[4].colors = ColorBitFlags::from(vec![Color::try_from(8).unwrap()]);
+        let mut v1 = vec![1];
+        for sq in bs.squares() {
+            let mut bytes = sq.sql_serialize();
+            for &offset in SQL_SQUARE_FLAG_OFFSETS.iter() {
+                let flags = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+                bytes[offset..offset + 2].copy_from_slice(&flags.to_ne_bytes());
+            }
+            v1.extend_from_slice(&bytes);
+        }
+        assert_eq!(BoardState::sql_deserialize(&v1).unwrap(), bs);
+
+        // the current version is explicitly little-endian, regardless of the machine
+        let current = bs.sql_serialize();
+        assert_eq!(current[0], SQL_BOARD_VERSION);
+        let corners = 1 + 2 * SQL_SQUARE_SIZE + 1;
+        assert_eq!(current[corners..corners + 2], [0b0000_0010, 0b0000_0010]);
+        assert_eq!(BoardState::sql_deserialize(&current).unwrap(), bs);
+
+        assert!(BoardState::sql_deserialize(&[]).is_err());
+        assert!(BoardState::sql_deserialize(&[SQL_BOARD_VERSION, 0]).is_err());
+        let mut unknown_version = current;
+        unknown_version[0] = SQL_BOARD_VERSION + 1;
+        assert!(BoardState::sql_deserialize(&unknown_version).is_err());
+    }
+
+    /// The client's tests check that it serializes the same diffs to this fixture.
+    const CLIENT_DIFFS_FIXTURE: &str =
+        include_str!("../../src/gameLogic/__fixtures__/boardDiffs.json");
+
+    #[test]
+    fn diffs_match_client_fixture() {
+        let fixture: serde_json::Value = serde_json::from_str(CLIENT_DIFFS_FIXTURE).unwrap();
+        let diffs: Vec<BoardDiff> = serde_json::from_value(fixture.clone()).unwrap();
+        assert_eq!(
+            diffs[6],
+            BoardDiff {
+                squares: vec![1],
+                operation: BoardDiffOperation::RemoveColor {
+                    color: Color::try_from(8).unwrap()
+                },
+            }
+        );
+        assert_eq!(serde_json::to_value(&diffs).unwrap(), fixture);
+    }
+
+    #[test]
+    fn color_operations() {
+        let red = Color::try_from(0).unwrap();
+        let blue = Color::try_from(4).unwrap();
+        let op: BoardDiffOperation =
+            serde_json::from_value(json!({"fn": "addColor", "color": 4})).unwrap();
+        assert_eq!(op, BoardDiffOperation::AddColor { color: blue });
+        assert_eq!(
+            serde_json::to_value(BoardDiffOperation::ClearColors).unwrap(),
+            json!({"fn": "clearColors"})
+        );
+        assert!(serde_json::from_value::<BoardDiffOperation>(
+            json!({"fn": "removeColor", "color": 9})
+        )
+        .is_err());
+
+        let mut bs = BoardState::default();
+        for operation in &[
+            BoardDiffOperation::AddColor { color: red },
+            BoardDiffOperation::AddColor { color: blue },
+            BoardDiffOperation::RemoveColor { color: red },
+        ] {
+            bs.apply(&BoardDiff {
+                squares: vec![0],
+                operation: operation.clone(),
+            })
+            .unwrap();
+        }
+        assert_eq!(
+            serde_json::to_value(&bs.squares()[0]).unwrap()["colors"],
+            json!([4])
+        );
+        bs.apply(&BoardDiff {
+            squares: vec![0],
+            operation: BoardDiffOperation::ClearColors,
+        })
+        .unwrap();
+        assert_eq!(bs.squares()[0], BoardSquare::default());
+
+        // squares serialized before colors existed still deserialize
+        let square: BoardSquare = serde_json::from_value(
+            json!({"number": null, "corners": [], "centers": [], "locked": false}),
+        )
+        .unwrap();
+        assert_eq!(square, BoardSquare::default());
+    }
 }
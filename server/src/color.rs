@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// The number of colors a square can be highlighted with. The client decides what each one looks
+/// like.
+pub const COLOR_COUNT: u8 = 9;
+
+/// A color index that's guaranteed to be less than `COLOR_COUNT`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub struct Color(u8);
+
+impl TryFrom<u8> for Color {
+    type Error = &'static str;
+
+    fn try_from(val: u8) -> Result<Color, Self::Error> {
+        if val < COLOR_COUNT {
+            Ok(Color(val))
+        } else {
+            Err("color is out of range")
+        }
+    }
+}
+
+impl From<Color> for u8 {
+    fn from(color: Color) -> u8 {
+        color.0
+    }
+}
+
+/// A set of colors stored as bitflags, like `DigitBitFlags`.
+#[derive(Clone, Copy, Debug, Deserialize, Default, Eq, PartialEq, Serialize)]
+#[serde(into = "Vec<Color>", from = "Vec<Color>")]
+pub struct ColorBitFlags(u16);
+
+impl ColorBitFlags {
+    pub fn difference(&self, other: ColorBitFlags) -> Self {
+        ColorBitFlags(self.0 & !other.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Color> {
+        let flags = *self;
+        (0..COLOR_COUNT)
+            .filter(move |i| (1u16 << i) & flags.0 != 0)
+            .map(Color)
+    }
+
+    pub fn insert(&mut self, value: Color) {
+        self.0 |= 1u16 << value.0;
+    }
+
+    pub fn remove(&mut self, value: Color) {
+        self.0 &= !(1u16 << value.0);
+    }
+
+    #[cfg(feature = "sql")]
+    pub fn sql_serialize(&self) -> [u8; 2] {
+        self.0.to_le_bytes()
+    }
+
+    #[cfg(feature = "sql")]
+    pub fn sql_deserialize(bytes: [u8; 2]) -> Result<Self, &'static str> {
+        let flags = u16::from_le_bytes(bytes);
+        if flags >> COLOR_COUNT != 0 {
+            return Err("color flags are out of range");
+        }
+        Ok(ColorBitFlags(flags))
+    }
+}
+
+impl From<ColorBitFlags> for Vec<Color> {
+    fn from(flags: ColorBitFlags) -> Vec<Color> {
+        flags.iter().collect()
+    }
+}
+
+impl From<Vec<Color>> for ColorBitFlags {
+    fn from(vec: Vec<Color>) -> ColorBitFlags {
+        let mut flags: ColorBitFlags = Default::default();
+        for el in vec {
+            flags.insert(el);
+        }
+        flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn serde() {
+        let flags: ColorBitFlags = serde_json::from_value(json!([0, 8, 3])).unwrap();
+        assert_eq!(serde_json::to_value(flags).unwrap(), json!([0, 3, 8]));
+        assert!(serde_json::from_value::<ColorBitFlags>(json!([COLOR_COUNT])).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "sql")]
+    fn sql_serialize_deserialize() {
+        let flags = ColorBitFlags::from(vec![Color(0), Color(COLOR_COUNT - 1)]);
+        assert_eq!(
+            ColorBitFlags::sql_deserialize(flags.sql_serialize()),
+            Ok(flags)
+        );
+        assert!(ColorBitFlags::sql_deserialize(u16::MAX.to_le_bytes()).is_err());
+    }
+}
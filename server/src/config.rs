@@ -3,9 +3,13 @@ use clap::Clap;
 use fern::colors::{Color, ColoredLevelConfig};
 use serde::Deserialize;
 use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::solver;
 
 #[derive(Clap)]
 #[clap(author, about, version)]
@@ -22,13 +26,33 @@ pub struct Args {
 pub struct Config {
     #[serde(default = "default_listen_addr")]
     pub listen_addr: SocketAddr,
+    /// Where the web client is hosted. Share links for rooms are built relative to this.
+    #[serde(default = "default_public_url")]
+    pub public_url: String,
     #[serde(default)]
     pub logging: LoggingConfig,
     #[serde(default)]
     pub database: DatabaseConfig,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub permissions: PermissionsConfig,
 }
 
 impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.admin.token.as_deref() == Some("") {
+            return Err(ConfigError::EmptyAdminToken);
+        }
+        self.limits.validate()
+    }
+
     pub fn apply_args(&mut self, args: Args) {
         if let Some(listen_addr) = args.listen_addr {
             self.listen_addr = listen_addr;
@@ -64,6 +88,20 @@ impl Default for LoggingConfig {
 pub struct DatabaseConfig {
     #[serde(default = "default_database_uri")]
     pub uri: String,
+    /// How often dirty rooms are flushed back to the database while the server is running. A
+    /// value of zero disables periodic writeback, so rooms are only written back on shutdown.
+    #[serde(default = "default_writeback_interval_secs")]
+    pub writeback_interval_secs: u64,
+}
+
+impl DatabaseConfig {
+    pub fn writeback_interval(&self) -> Option<Duration> {
+        if self.writeback_interval_secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.writeback_interval_secs))
+        }
+    }
 }
 
 impl Default for DatabaseConfig {
@@ -72,14 +110,245 @@ impl Default for DatabaseConfig {
     }
 }
 
+#[derive(Deserialize)]
+pub struct GcConfig {
+    /// How often to look for idle rooms to evict from memory. A value of zero disables garbage
+    /// collection.
+    #[serde(default = "default_gc_interval_secs")]
+    pub interval_secs: u64,
+    /// Rooms with no connected sessions are evicted once they've been idle for this long.
+    #[serde(default = "default_gc_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// If set, idle rooms are evicted (least recently active first) whenever more than this many
+    /// rooms are resident in memory, even if they haven't reached `idle_timeout_secs` yet. Rooms
+    /// with connected sessions are never evicted, so this is a soft limit.
+    #[serde(default)]
+    pub max_resident_rooms: Option<usize>,
+}
+
+impl GcConfig {
+    pub fn interval(&self) -> Option<Duration> {
+        if self.interval_secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.interval_secs))
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct LimitsConfig {
+    /// How many players can be in a single room at once. Every message has to be sent to every
+    /// player, so the work done per room grows quadratically with this.
+    #[serde(default = "default_max_players_per_room")]
+    pub max_players_per_room: usize,
+    /// How many spectators can watch a single room at once. These are counted separately from
+    /// players.
+    #[serde(default = "default_max_spectators_per_room")]
+    pub max_spectators_per_room: usize,
+    /// How long a disconnected session can be resumed for. Its cursor slot stays reserved until
+    /// then. A value of zero disables resumption.
+    #[serde(default = "default_resume_grace_period_secs")]
+    pub resume_grace_period_secs: u64,
+    /// How many diff groups can be queued up for a session before it's considered to have lagged,
+    /// at which point it gets a `FullUpdate` instead.
+    #[serde(default = "default_max_diff_group_queue")]
+    pub max_diff_group_queue: usize,
+    /// The most diffs a client can send in a single group. This needs to be at least as large as
+    /// the largest group the client generates for a single high-level operation.
+    #[serde(default = "default_max_diff_group_size")]
+    pub max_diff_group_size: usize,
+    /// The largest websocket message (in bytes) a client can send.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    /// The largest websocket frame (in bytes) a client can send. Messages can be split across
+    /// frames, so this can't be larger than `max_message_size`.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// How many guesses the solver can make when checking that a new puzzle can be solved, before
+    /// rejecting it as too hard to check. Each guess takes a couple of microseconds.
+    #[serde(default = "default_max_solver_nodes")]
+    pub max_solver_nodes: usize,
+}
+
+impl LimitsConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let nonzero = [
+            ("max_players_per_room", self.max_players_per_room),
+            ("max_diff_group_queue", self.max_diff_group_queue),
+            ("max_diff_group_size", self.max_diff_group_size),
+            ("max_message_size", self.max_message_size),
+            ("max_frame_size", self.max_frame_size),
+            ("max_solver_nodes", self.max_solver_nodes),
+        ];
+        for (name, value) in nonzero.iter() {
+            if *value == 0 {
+                return Err(ConfigError::InvalidLimit(name, "must be greater than zero"));
+            }
+        }
+        if self.max_frame_size > self.max_message_size {
+            return Err(ConfigError::InvalidLimit(
+                "max_frame_size",
+                "can't be larger than max_message_size",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn resume_grace_period(&self) -> Option<Duration> {
+        if self.resume_grace_period_secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.resume_grace_period_secs))
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+pub struct AdminConfig {
+    /// Requests to the admin API have to send this as a bearer token. The admin API is disabled
+    /// if this isn't set.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PermissionsConfig {
+    /// Who can replace a room's whole board with `SetBoardState`.
+    #[serde(default = "default_set_board_permission")]
+    pub set_board: SetBoardPermission,
+}
+
+impl Default for PermissionsConfig {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SetBoardPermission {
+    /// Only the room's host, which is whoever created the room until they hand it to somebody
+    /// else. The role can be reclaimed with the room's host key, which is saved with the room, so
+    /// this survives restarts.
+    #[serde(alias = "creator")]
+    Host,
+    /// Any player (but not spectators).
+    Players,
+}
+
+#[derive(Deserialize)]
+pub struct ShutdownConfig {
+    /// How long to wait for clients to disconnect after they're told that the server is shutting
+    /// down. Rooms are written back once everyone is gone or this runs out, whichever is first.
+    #[serde(default = "default_shutdown_drain_secs")]
+    pub drain_secs: u64,
+    /// Clients are told to reconnect after a random delay of up to this long, so that they don't
+    /// all come back at the same moment.
+    #[serde(default = "default_shutdown_reconnect_spread_secs")]
+    pub reconnect_spread_secs: u64,
+}
+
+impl ShutdownConfig {
+    pub fn drain_period(&self) -> Duration {
+        Duration::from_secs(self.drain_secs)
+    }
+
+    pub fn reconnect_spread(&self) -> Duration {
+        Duration::from_secs(self.reconnect_spread_secs)
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
 fn default_listen_addr() -> SocketAddr {
     "127.0.0.1:9091".parse().unwrap()
 }
 
+fn default_public_url() -> String {
+    "http://localhost:3000/".to_owned()
+}
+
 fn default_database_uri() -> String {
     "sqlite::memory:".to_owned()
 }
 
+fn default_writeback_interval_secs() -> u64 {
+    60
+}
+
+fn default_gc_interval_secs() -> u64 {
+    60
+}
+
+fn default_gc_idle_timeout_secs() -> u64 {
+    30 * 60
+}
+
+fn default_max_players_per_room() -> usize {
+    8
+}
+
+fn default_max_spectators_per_room() -> usize {
+    64
+}
+
+fn default_resume_grace_period_secs() -> u64 {
+    60
+}
+
+fn default_max_diff_group_queue() -> usize {
+    32
+}
+
+fn default_max_diff_group_size() -> usize {
+    8
+}
+
+fn default_max_message_size() -> usize {
+    512 * 1024
+}
+
+fn default_max_frame_size() -> usize {
+    512 * 1024
+}
+
+fn default_max_solver_nodes() -> usize {
+    solver::DEFAULT_MAX_NODES
+}
+
+fn default_set_board_permission() -> SetBoardPermission {
+    SetBoardPermission::Host
+}
+
+fn default_shutdown_drain_secs() -> u64 {
+    10
+}
+
+fn default_shutdown_reconnect_spread_secs() -> u64 {
+    5
+}
+
 impl LoggingConfig {
     pub fn to_dispatch(&self) -> fern::Dispatch {
         let colors = ColoredLevelConfig::new()
@@ -118,6 +387,55 @@ pub fn get_config() -> Result<Config, Box<dyn Error>> {
         println!("Error while reading {}: {}", args.config, err);
         err
     })?;
+    config.validate().map_err(|err| {
+        println!("Error while reading {}: {}", args.config, err);
+        err
+    })?;
     config.apply_args(args);
     Ok(config)
 }
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The name of a `[limits]` option, and why its value isn't allowed.
+    InvalidLimit(&'static str, &'static str),
+    EmptyAdminToken,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidLimit(name, reason) => write!(f, "limits.{} {}", name, reason),
+            ConfigError::EmptyAdminToken => {
+                write!(
+                    f,
+                    "admin.token can't be empty. Leave it out to disable the admin API."
+                )
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_limits() {
+        assert!(Config::default().validate().is_ok());
+        let config: Config = toml::from_str("[limits]\nmax_players_per_room = 30").unwrap();
+        assert!(config.validate().is_ok());
+        let config: Config = toml::from_str("[limits]\nmax_diff_group_queue = 0").unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "limits.max_diff_group_queue must be greater than zero"
+        );
+        let config: Config = toml::from_str("[limits]\nmax_message_size = 1024").unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "limits.max_frame_size can't be larger than max_message_size"
+        );
+    }
+}
@@ -1,8 +1,10 @@
-use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use std::sync::Arc;
 
 use crate::cursors::error::CursorUpdateError;
+use crate::cursors::profile::{CursorColor, InputMode, Profile, ProfileUpdate};
 use crate::cursors::selection::CursorSelection;
-use crate::room::MAX_SESSIONS_PER_ROOM;
 
 type SessionId = u64;
 
@@ -13,41 +15,51 @@ pub struct CursorsMapIndex(usize);
 // (for equality) the results we send to the client.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(super) struct CursorsMap {
-    // MAX_SESSIONS_PER_ROOM is small, so don't use a real map or even waste a heap allocation on a
-    // Vec.
+    // Rooms only hold a few players, so don't use a real map. Slots are added as they're needed,
+    // up to the capacity passed to `new_session`, and are never removed, just emptied.
     //
     // We need to make sure that the iteration order is the same every time so that equality works.
     // Since the entry lives as long as the SessionId does, we don't need to worry about
     // insert/removal changing order.
-    inner: [Option<(SessionId, CursorSelection)>; MAX_SESSIONS_PER_ROOM],
+    inner: Vec<Option<CursorsMapEntry>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct CursorsMapEntry {
+    session_id: SessionId,
+    selection: CursorSelection,
+    // profiles change much less often than selections, so share them between clones of the map
+    profile: Arc<Profile>,
 }
 
 impl CursorsMap {
     pub fn new() -> Self {
-        CursorsMap {
-            inner: Default::default(),
-        }
+        CursorsMap { inner: Vec::new() }
     }
 
+    /// Adds a session to the map, which can hold at most `capacity` sessions at once.
     pub fn new_session(
         &mut self,
         session_id: SessionId,
+        profile: ProfileUpdate,
+        capacity: usize,
     ) -> Result<CursorsMapIndex, CursorUpdateError> {
-        let mut idx = None;
-        // find a free slot and pick that idx
-        for (iter_idx, entry) in self.inner.iter().enumerate() {
-            if entry.is_none() {
-                idx = Some(iter_idx);
-                break;
-            }
-        }
-        match idx {
-            Some(idx) => {
-                self.inner[idx] = Some((session_id, CursorSelection::new()));
-                Ok(CursorsMapIndex(idx))
+        // find a free slot and pick that idx, or add a new slot if there's room
+        let idx = match self.inner.iter().position(|entry| entry.is_none()) {
+            Some(idx) => idx,
+            None if self.inner.len() < capacity => {
+                self.inner.push(None);
+                self.inner.len() - 1
             }
-            None => Err(CursorUpdateError::Full),
-        }
+            None => return Err(CursorUpdateError::Full),
+        };
+        let profile = Profile::new(session_id, profile, &self.taken_colors(None));
+        self.inner[idx] = Some(CursorsMapEntry {
+            session_id,
+            selection: CursorSelection::new(),
+            profile: Arc::new(profile),
+        });
+        Ok(CursorsMapIndex(idx))
     }
 
     pub fn update(
@@ -56,13 +68,39 @@ impl CursorsMap {
         selection: CursorSelection,
     ) -> Result<(), CursorUpdateError> {
         if let Some(ref mut entry) = self.inner[idx.0] {
-            entry.1 = selection;
+            entry.selection = selection;
             Ok(())
         } else {
             Err(CursorUpdateError::InvalidIndex(idx))
         }
     }
 
+    pub fn update_profile(
+        &mut self,
+        idx: CursorsMapIndex,
+        update: ProfileUpdate,
+    ) -> Result<(), CursorUpdateError> {
+        let taken_colors = self.taken_colors(Some(idx));
+        if let Some(ref mut entry) = self.inner[idx.0] {
+            let mut profile = (*entry.profile).clone();
+            profile.apply(entry.session_id, update, &taken_colors);
+            entry.profile = Arc::new(profile);
+            Ok(())
+        } else {
+            Err(CursorUpdateError::InvalidIndex(idx))
+        }
+    }
+
+    /// The colors used by every session except `exclude`.
+    fn taken_colors(&self, exclude: Option<CursorsMapIndex>) -> Vec<CursorColor> {
+        self.inner
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| Some(CursorsMapIndex(*idx)) != exclude)
+            .filter_map(|(_, entry)| entry.as_ref().map(|entry| entry.profile.color))
+            .collect()
+    }
+
     pub fn remove(&mut self, idx: CursorsMapIndex) -> Result<(), CursorUpdateError> {
         let entry = &mut self.inner[idx.0];
         if entry.is_some() {
@@ -73,14 +111,48 @@ impl CursorsMap {
         }
     }
 
-    pub fn into_view(self, idx: CursorsMapIndex) -> CursorsMapView {
+    /// Creates a view for the session at `idx`, which excludes that session's own cursor. A
+    /// view with no `idx` (for a spectator) includes every cursor.
+    pub fn into_view(self, idx: Option<CursorsMapIndex>) -> CursorsMapView {
         CursorsMapView { map: self, idx }
     }
 }
 
 pub struct CursorsMapView {
     pub(super) map: CursorsMap,
-    pub(super) idx: CursorsMapIndex,
+    pub(super) idx: Option<CursorsMapIndex>,
+}
+
+impl CursorsMapView {
+    /// Every player in the room (including the one this view is for), whether or not they have
+    /// anything selected.
+    pub fn roster(&self) -> Vec<RosterEntry> {
+        self.map
+            .inner
+            .iter()
+            .filter_map(|entry| entry.as_ref())
+            .map(|entry| RosterEntry {
+                session_id: entry.session_id,
+                profile: (*entry.profile).clone(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RosterEntry {
+    pub session_id: SessionId,
+    #[serde(flatten)]
+    pub profile: Profile,
+}
+
+#[derive(Serialize)]
+struct CursorView<'a> {
+    selection: &'a CursorSelection,
+    name: &'a str,
+    color: CursorColor,
+    mode: InputMode,
 }
 
 impl Serialize for CursorsMapView {
@@ -89,10 +161,18 @@ impl Serialize for CursorsMapView {
         // map twice, and serde_json probably doesn't get much benefit from a size.
         let mut s_map = serializer.serialize_map(None)?;
         for (idx, entry) in self.map.inner.iter().enumerate() {
-            if idx != self.idx.0 {
-                if let Some((_k, v)) = entry {
-                    if !v.is_empty() {
-                        s_map.serialize_entry(&idx, v)?;
+            if Some(CursorsMapIndex(idx)) != self.idx {
+                if let Some(entry) = entry {
+                    if !entry.selection.is_empty() {
+                        s_map.serialize_entry(
+                            &entry.session_id,
+                            &CursorView {
+                                selection: &entry.selection,
+                                name: &entry.profile.name,
+                                color: entry.profile.color,
+                                mode: entry.profile.mode,
+                            },
+                        )?;
                     }
                 }
             }
@@ -110,9 +190,9 @@ mod tests {
     #[test]
     fn test_empty_view() {
         let mut map = CursorsMap::new();
-        let idx = map.new_session(1234).unwrap();
+        let idx = map.new_session(1234, Default::default(), 8).unwrap();
         assert_eq!(
-            serde_json::to_value(&map.into_view(idx)).unwrap(),
+            serde_json::to_value(&map.into_view(Some(idx))).unwrap(),
             json!({}),
         );
     }
@@ -120,8 +200,17 @@ mod tests {
     #[test]
     fn test_two_clients() {
         let mut map = CursorsMap::new();
-        let idx0 = map.new_session(1234).unwrap();
-        let idx1 = map.new_session(4321).unwrap();
+        let idx0 = map.new_session(1234, Default::default(), 8).unwrap();
+        let idx1 = map
+            .new_session(
+                4321,
+                ProfileUpdate {
+                    name: Some("Bob".to_owned()),
+                    ..Default::default()
+                },
+                8,
+            )
+            .unwrap();
         map.update(
             idx0,
             serde_json::from_value::<CursorSelection>(json!([1, 2, 3])).unwrap(),
@@ -132,32 +221,89 @@ mod tests {
             serde_json::from_value::<CursorSelection>(json!([4, 5, 6])).unwrap(),
         )
         .unwrap();
+        let cursor0 = json!({
+            "selection": [1, 2, 3],
+            "name": "Player 1234",
+            "color": 0,
+            "mode": "normal",
+        });
+        let cursor1 = json!({
+            "selection": [4, 5, 6],
+            "name": "Bob",
+            "color": 1,
+            "mode": "normal",
+        });
         // the view for idx0 shows the results for idx1
         assert_eq!(
-            serde_json::to_value(&map.clone().into_view(idx0)).unwrap(),
-            json!({"1": [4, 5, 6]}),
+            serde_json::to_value(&map.clone().into_view(Some(idx0))).unwrap(),
+            json!({ "4321": cursor1 }),
         );
         // and the view for idx1 shows the results for idx0
         assert_eq!(
-            serde_json::to_value(&map.clone().into_view(idx1)).unwrap(),
-            json!({"0": [1, 2, 3]}),
+            serde_json::to_value(&map.clone().into_view(Some(idx1))).unwrap(),
+            json!({ "1234": cursor0 }),
+        );
+        // and a view without an idx shows both
+        assert_eq!(
+            serde_json::to_value(&map.into_view(None)).unwrap(),
+            json!({ "1234": cursor0, "4321": cursor1 }),
         );
     }
 
     #[test]
     fn test_full_map() {
         let mut map = CursorsMap::new();
-        let valid_sessions: Vec<CursorsMapIndex> = (0..MAX_SESSIONS_PER_ROOM)
-            .map(|session_id| map.new_session(session_id as SessionId).unwrap())
+        let capacity = 30;
+        let valid_sessions: Vec<CursorsMapIndex> = (0..capacity)
+            .map(|session_id| {
+                map.new_session(session_id as SessionId, Default::default(), capacity)
+                    .unwrap()
+            })
             .collect();
         assert!(matches!(
-            map.new_session(1000),
+            map.new_session(1000, Default::default(), capacity),
             Err(CursorUpdateError::Full)
         ));
 
         // if we remove an entry from a full map and then call new_session, we get the index we
         // just removed
         map.remove(valid_sessions[0]).unwrap();
-        assert_eq!(map.new_session(1000).unwrap(), valid_sessions[0],);
+        assert_eq!(
+            map.new_session(1000, Default::default(), capacity).unwrap(),
+            valid_sessions[0],
+        );
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut map = CursorsMap::new();
+        let idx0 = map.new_session(1, Default::default(), 8).unwrap();
+        let idx1 = map.new_session(2, Default::default(), 8).unwrap();
+        // colors are unique, so session 1 can't take session 0's color
+        map.update_profile(
+            idx1,
+            ProfileUpdate {
+                name: Some("  Carol ".to_owned()),
+                color: Some(0),
+                mode: Some(InputMode::Corners),
+            },
+        )
+        .unwrap();
+        // but it can take a free one
+        map.update_profile(
+            idx1,
+            ProfileUpdate {
+                color: Some(5),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&map.into_view(Some(idx0)).roster()).unwrap(),
+            json!([
+                {"sessionId": 1, "name": "Player 1", "color": 0, "mode": "normal"},
+                {"sessionId": 2, "name": "Carol", "color": 5, "mode": "corners"},
+            ]),
+        );
     }
 }
@@ -1,11 +1,12 @@
 //! Exposes an API that wraps over [tokio::sync::watch] to share a map of cursor selections for
 //! every client in the room.
 //!
-//! The underlying map is stored compactly on the stack using bitmasks, so cloning the resulting
-//! value around is cheap-ish.
+//! The underlying map is stored compactly using bitmasks, so cloning the resulting value around is
+//! cheap-ish.
 
 mod error;
 mod map;
+mod profile;
 mod selection;
 
 use log::error;
@@ -14,8 +15,9 @@ use tokio::sync::watch;
 
 use crate::cursors::error::WatchSendErrorWrapper;
 pub use crate::cursors::error::{CursorReceiveError, CursorUpdateError};
-pub use crate::cursors::map::CursorsMapView;
 use crate::cursors::map::{CursorsMap, CursorsMapIndex};
+pub use crate::cursors::map::{CursorsMapView, RosterEntry};
+pub use crate::cursors::profile::ProfileUpdate;
 pub use crate::cursors::selection::CursorSelection;
 
 type SessionId = u64;
@@ -35,19 +37,37 @@ impl Cursors {
         }
     }
 
-    pub fn new_session(&self, session_id: SessionId) -> Result<SessionCursor, CursorUpdateError> {
-        let map_idx = self.inner.apply(|map| map.new_session(session_id))?;
+    /// Creates a cursor for a new session. This fails if there are already `capacity` sessions
+    /// with cursors.
+    pub fn new_session(
+        &self,
+        session_id: SessionId,
+        profile: ProfileUpdate,
+        capacity: usize,
+    ) -> Result<SessionCursor, CursorUpdateError> {
+        let map_idx = self
+            .inner
+            .apply(|map| map.new_session(session_id, profile.clone(), capacity))?;
         Ok(SessionCursor {
             tx: SessionCursorSender {
                 cursors_inner: self.inner.clone(),
                 map_idx,
             },
             rx: SessionCursorReceiver {
-                map_idx,
+                map_idx: Some(map_idx),
                 rx: self.inner.rx.clone(),
             },
         })
     }
+
+    /// Creates a receiver for a session that doesn't have a cursor of its own, and so can see
+    /// every other session's cursor. This doesn't take up a slot in the map, so it can't fail.
+    pub fn new_spectator(&self) -> SessionCursorReceiver {
+        SessionCursorReceiver {
+            map_idx: None,
+            rx: self.inner.rx.clone(),
+        }
+    }
 }
 
 struct CursorsInner {
@@ -82,7 +102,7 @@ pub struct SessionCursorSender {
 }
 
 pub struct SessionCursorReceiver {
-    map_idx: CursorsMapIndex,
+    map_idx: Option<CursorsMapIndex>,
     rx: watch::Receiver<CursorsMap>,
 }
 
@@ -91,6 +111,20 @@ impl SessionCursorSender {
         self.cursors_inner
             .apply(|map| map.update(self.map_idx, selection))
     }
+
+    /// Creates another receiver for this session, e.g. after the original was dropped because the
+    /// client reconnected.
+    pub fn new_receiver(&self) -> SessionCursorReceiver {
+        SessionCursorReceiver {
+            map_idx: Some(self.map_idx),
+            rx: self.cursors_inner.rx.clone(),
+        }
+    }
+
+    pub fn update_profile(&self, profile: ProfileUpdate) -> Result<(), CursorUpdateError> {
+        self.cursors_inner
+            .apply(|map| map.update_profile(self.map_idx, profile.clone()))
+    }
 }
 
 impl SessionCursorReceiver {
@@ -125,14 +159,14 @@ mod tests {
     async fn test_two_clients() {
         let cursors = Cursors::new();
 
-        let mut session0 = cursors.new_session(1000).unwrap();
+        let mut session0 = cursors.new_session(1000, Default::default(), 8).unwrap();
         // we can recv immediately on a new session
         assert_eq!(
             serde_json::to_value(session0.rx.recv().await.unwrap()).unwrap(),
             json!({})
         );
 
-        let mut session1 = cursors.new_session(1001).unwrap();
+        let mut session1 = cursors.new_session(1001, Default::default(), 8).unwrap();
 
         session0
             .tx
@@ -143,12 +177,28 @@ mod tests {
             .update(serde_json::from_value(json!([4, 5, 6])).unwrap())
             .unwrap();
         assert_eq!(
-            serde_json::to_value(session0.rx.recv().await.unwrap()).unwrap(),
-            json!({"1": [4, 5, 6]})
+            serde_json::to_value(session0.rx.recv().await.unwrap()).unwrap()["1001"]["selection"],
+            json!([4, 5, 6])
+        );
+        assert_eq!(
+            serde_json::to_value(session1.rx.recv().await.unwrap()).unwrap()["1000"]["selection"],
+            json!([1, 2, 3])
         );
+    }
+
+    #[tokio::test]
+    async fn test_spectator() {
+        let cursors = Cursors::new();
+        let session = cursors.new_session(1000, Default::default(), 8).unwrap();
+        let mut spectator = cursors.new_spectator();
+        session
+            .tx
+            .update(serde_json::from_value(json!([1, 2, 3])).unwrap())
+            .unwrap();
+        // spectators see every session's cursor
         assert_eq!(
-            serde_json::to_value(session1.rx.recv().await.unwrap()).unwrap(),
-            json!({"0": [1, 2, 3]})
+            serde_json::to_value(spectator.recv().await.unwrap()).unwrap()["1000"]["selection"],
+            json!([1, 2, 3])
         );
     }
 }
@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+
+type SessionId = u64;
+
+/// Display names longer than this (in characters) are truncated.
+pub const MAX_NAME_LENGTH: usize = 24;
+
+/// The number of distinct cursor colors. Every player in a room has a different color until there
+/// are more players than this. After that, new players share the least used color.
+pub const CURSOR_COLOR_COUNT: u8 = 8;
+
+/// An index into the client's cursor color palette.
+pub type CursorColor = u8;
+
+/// The input mode a player's client is currently in, so that other players can tell what they're
+/// about to do.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputMode {
+    Normal,
+    Corners,
+    Centers,
+}
+
+/// How a player shows up to everybody else in the room.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub name: String,
+    pub color: CursorColor,
+    pub mode: InputMode,
+}
+
+/// A change requested by a client. Fields that aren't set are left alone.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileUpdate {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// A preferred color. This is ignored if it's out of range or another player already has it.
+    #[serde(default)]
+    pub color: Option<CursorColor>,
+    #[serde(default)]
+    pub mode: Option<InputMode>,
+}
+
+impl Profile {
+    /// Creates a profile for a new session. `taken_colors` are the colors of the other players in
+    /// the room.
+    pub(super) fn new(
+        session_id: SessionId,
+        update: ProfileUpdate,
+        taken_colors: &[CursorColor],
+    ) -> Self {
+        let mut profile = Profile {
+            name: default_name(session_id),
+            color: least_used_color(taken_colors),
+            mode: InputMode::Normal,
+        };
+        profile.apply(session_id, update, taken_colors);
+        profile
+    }
+
+    pub(super) fn apply(
+        &mut self,
+        session_id: SessionId,
+        update: ProfileUpdate,
+        taken_colors: &[CursorColor],
+    ) {
+        if let Some(name) = update.name {
+            self.name = sanitize_name(&name).unwrap_or_else(|| default_name(session_id));
+        }
+        if let Some(color) = update.color {
+            if color < CURSOR_COLOR_COUNT && !taken_colors.contains(&color) {
+                self.color = color;
+            }
+        }
+        if let Some(mode) = update.mode {
+            self.mode = mode;
+        }
+    }
+}
+
+/// Picks the lowest color that's been taken the fewest times.
+fn least_used_color(taken_colors: &[CursorColor]) -> CursorColor {
+    (0..CURSOR_COLOR_COUNT)
+        .min_by_key(|color| taken_colors.iter().filter(|taken| *taken == color).count())
+        .unwrap_or(0)
+}
+
+fn default_name(session_id: SessionId) -> String {
+    format!("Player {}", session_id)
+}
+
+/// Collapses runs of whitespace, strips control and formatting characters, and truncates the name
+/// to `MAX_NAME_LENGTH` characters. Returns `None` if there's nothing left.
+pub fn sanitize_name(name: &str) -> Option<String> {
+    let words: Vec<String> = name
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|ch| !ch.is_control() && !is_format_char(*ch))
+                .collect()
+        })
+        .filter(|word: &String| !word.is_empty())
+        .collect();
+    let name: String = words.join(" ").chars().take(MAX_NAME_LENGTH).collect();
+    let name = name.trim_end();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+/// Invisible characters that can be used to spoof another player's name or mess with the layout
+/// of the surrounding text, like zero-width spaces and bidi overrides.
+fn is_format_char(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{00AD}'
+            | '\u{061C}'
+            | '\u{180E}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{206F}'
+            | '\u{FEFF}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize() {
+        assert_eq!(
+            sanitize_name("  Alice \t Smith\n"),
+            Some("Alice Smith".to_owned())
+        );
+        assert_eq!(sanitize_name("Bob\u{202E}\u{200B}"), Some("Bob".to_owned()));
+        assert_eq!(sanitize_name("\u{7}\u{200B} "), None);
+        assert_eq!(
+            sanitize_name(&"x".repeat(100)).unwrap().chars().count(),
+            MAX_NAME_LENGTH
+        );
+        // don't leave a trailing space behind after truncating
+        let name = format!("{} b", "a".repeat(MAX_NAME_LENGTH - 1));
+        assert_eq!(sanitize_name(&name), Some("a".repeat(MAX_NAME_LENGTH - 1)));
+    }
+
+    #[test]
+    fn colors() {
+        let preferred = |color| ProfileUpdate {
+            color: Some(color),
+            ..Default::default()
+        };
+        // a free preferred color is honored
+        assert_eq!(Profile::new(1, preferred(3), &[0]).color, 3);
+        // otherwise, the lowest free color is used
+        assert_eq!(Profile::new(1, preferred(0), &[0, 1]).color, 2);
+        assert_eq!(Profile::new(1, preferred(CURSOR_COLOR_COUNT), &[]).color, 0);
+        // once every color is taken, they're shared as evenly as possible
+        let mut taken: Vec<_> = (0..CURSOR_COLOR_COUNT).collect();
+        taken.push(0);
+        assert_eq!(Profile::new(1, Default::default(), &taken).color, 1);
+    }
+}
@@ -49,11 +49,34 @@ impl Into<u8> for Digit {
 pub struct DigitBitFlags(u16);
 
 impl DigitBitFlags {
+    /// A set containing every digit from 1 to 9.
+    pub fn all() -> Self {
+        DigitBitFlags(0b11_1111_1110)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn difference(&self, other: DigitBitFlags) -> Self {
+        DigitBitFlags(self.0 & !other.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Digit> {
+        let flags = *self;
+        (1..=9)
+            .filter(move |i| flags.contains_u8(*i))
+            .map(|i| Digit::try_from(i).unwrap())
+    }
+
     pub fn contains_u8(&self, value: u8) -> bool {
         (1u16 << value as u16) & self.0 != 0
     }
 
-    #[allow(dead_code)]
     pub fn contains(&self, value: Digit) -> bool {
         self.contains_u8(value.into())
     }
@@ -68,25 +91,19 @@ impl DigitBitFlags {
 
     #[cfg(feature = "sql")]
     pub fn sql_serialize(&self) -> [u8; 2] {
-        self.0.to_ne_bytes()
+        self.0.to_le_bytes()
     }
 
     #[cfg(feature = "sql")]
     pub fn sql_deserialize(bytes: [u8; 2]) -> Self {
-        DigitBitFlags(u16::from_ne_bytes(bytes))
+        DigitBitFlags(u16::from_le_bytes(bytes))
     }
 }
 
 // this conversion is mostly just for serialization/deserialization
 impl Into<Vec<Digit>> for DigitBitFlags {
     fn into(self) -> Vec<Digit> {
-        let mut result = Vec::with_capacity(self.0.count_ones() as usize);
-        for i in 1..=9 {
-            if self.contains_u8(i) {
-                result.push(Digit::try_from(i).unwrap());
-            }
-        }
-        result
+        self.iter().collect()
     }
 }
 
@@ -137,6 +154,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn set_operations() {
+        let low = DigitBitFlags::from(vec![Digit::D1, Digit::D2, Digit::D3]);
+        let odd = DigitBitFlags::from(vec![Digit::D1, Digit::D3, Digit::D5]);
+        assert_eq!(low.difference(odd), DigitBitFlags::from(vec![Digit::D2]));
+        assert_eq!(DigitBitFlags::all().len(), 9);
+        assert!(DigitBitFlags::all()
+            .difference(DigitBitFlags::all())
+            .is_empty());
+        assert_eq!(
+            odd.iter().collect::<Vec<_>>(),
+            vec![Digit::D1, Digit::D3, Digit::D5]
+        );
+    }
+
     #[test]
     #[cfg(feature = "sql")]
     fn sql_serialize_deserialize() {
@@ -2,15 +2,30 @@ use serde::{Serialize, Serializer};
 use std::error::Error;
 use std::fmt;
 
+use crate::room::SessionId;
+
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum SudokuError {
+    InvalidRevision(u64),
+    InvalidSquareCount(usize),
     InvalidSquareIndex(usize),
+    NothingToRedo,
+    NothingToUndo,
+    NoSuchPlayer(SessionId),
+    NotHost,
     ReceivedBinaryMessage,
+    RoomClosed,
     RoomFull(usize),
+    RoomLocked,
     SerdeJson(serde_json::Error),
+    ShuttingDown,
+    Spectating,
     TooManyBoardDiffs(usize, usize),
+    TooManySpectators(usize),
     TooManySquares(usize, usize),
+    UnsolvablePuzzle,
+    UnverifiablePuzzle,
 
     // Internal errors should never happen.
     Internal(Box<dyn Error + Sync + Send>),
@@ -19,28 +34,79 @@ pub enum SudokuError {
 impl fmt::Display for SudokuError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            SudokuError::InvalidRevision(revision) => write!(
+                f,
+                "Got diffs based on revision {}, which is either newer than the room or too old \
+                to transform. Reconnect to get the latest board.",
+                revision
+            ),
+            SudokuError::InvalidSquareCount(count) => write!(
+                f,
+                "Got a board with {} squares, but a board must have exactly 81 squares.",
+                count
+            ),
             SudokuError::InvalidSquareIndex(idx) => {
                 write!(f, "Got a diff containing an index of {}, which is out of bounds.", idx)
             }
+            SudokuError::NothingToRedo => write!(f, "There's nothing to redo."),
+            SudokuError::NothingToUndo => write!(
+                f,
+                "There's nothing to undo. Changes made before the board was replaced, or too long \
+                ago, can't be undone."
+            ),
+            SudokuError::NoSuchPlayer(session_id) => write!(
+                f,
+                "There's no player with session id {} in this room.",
+                session_id
+            ),
+            SudokuError::NotHost => write!(f, "Only the room's host can do that."),
             SudokuError::ReceivedBinaryMessage => {
                 write!(f, "Messages must be JSON-encoded text, not binary blobs.")
             }
+            SudokuError::RoomClosed => write!(f, "This room has been closed by its host."),
             SudokuError::RoomFull(max_count) => write!(
                 f,
                 "This room is full. No more than {} connections are allowed to a single room.",
                 max_count
             ),
+            SudokuError::RoomLocked => {
+                write!(f, "This room has been locked, so the board can't be changed.")
+            }
             SudokuError::SerdeJson(err) => write!(f, "Request could not be parsed: {}", err),
+            SudokuError::ShuttingDown => write!(
+                f,
+                "The server is shutting down, so the board can't be changed. Reconnect to keep \
+                playing."
+            ),
+            SudokuError::Spectating => write!(
+                f,
+                "You're spectating this room, so you can't change the board or move a cursor."
+            ),
             SudokuError::TooManyBoardDiffs(count, max_count) => write!(
                 f,
                 "Got {} diffs in a request, but there is a maximum of {} diffs per request.",
                 count, max_count
             ),
+            SudokuError::TooManySpectators(max_count) => write!(
+                f,
+                "This room already has the maximum of {} spectators.",
+                max_count
+            ),
             SudokuError::TooManySquares(count, max_count) => write!(
                 f,
                 "Received a diff containing {} squares, but a diff can't contain more than {} squares.",
                 count, max_count
             ),
+            SudokuError::UnsolvablePuzzle => write!(
+                f,
+                "The puzzle can't be solved. Check that its givens (the locked squares) don't \
+                conflict."
+            ),
+            SudokuError::UnverifiablePuzzle => write!(
+                f,
+                "The puzzle has too few givens to check that it can be solved. Add more givens and \
+                try again."
+            ),
             SudokuError::Internal(_) => write!(f, "Internal Error"),
         }
     }
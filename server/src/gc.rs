@@ -0,0 +1,47 @@
+use log::{debug, error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{self, Instant};
+
+use crate::global_state::GlobalState;
+use crate::storage::Storage;
+
+/// Evicts idle rooms from memory every `period` until `stop_rx` fires. See
+/// `GlobalState::collect_garbage` for the eviction rules.
+pub async fn run_periodically(
+    storage: Arc<dyn Storage>,
+    global_state: Arc<GlobalState>,
+    period: Duration,
+    idle_timeout: Duration,
+    max_resident_rooms: Option<usize>,
+    mut stop_rx: oneshot::Receiver<()>,
+) {
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut stop_rx => return,
+        }
+        let start = Instant::now();
+        match global_state
+            .collect_garbage(&*storage, idle_timeout, max_resident_rooms)
+            .await
+        {
+            Ok(stats) if stats.evicted_rooms > 0 => info!(
+                "Evicted {} idle room(s) in {:?}, {} room(s) still resident",
+                stats.evicted_rooms,
+                start.elapsed(),
+                stats.resident_rooms
+            ),
+            Ok(stats) => debug!(
+                "Garbage collection found nothing to evict, {} room(s) resident",
+                stats.resident_rooms
+            ),
+            Err(err) => error!(
+                "Failed to write back idle rooms, so none were evicted: {}",
+                err
+            ),
+        }
+    }
+}
@@ -1,53 +1,68 @@
-#[cfg(feature = "sql")]
 use futures::prelude::*;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, RwLock};
 
+use crate::config::LimitsConfig;
+use crate::metrics;
 use crate::room::{RoomId, RoomState};
-use crate::sql;
+use crate::storage::{self, Storage, StorageError};
 
-#[cfg(feature = "sql")]
 type PendingRoomState = future::WeakShared<
-    future::BoxFuture<'static, Result<Option<Arc<Mutex<RoomState>>>, Arc<sql::ReadRoomError>>>,
+    future::BoxFuture<'static, Result<Option<Arc<Mutex<RoomState>>>, Arc<StorageError>>>,
 >;
 
+/// The results of a single `GlobalState::collect_garbage` pass.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    pub evicted_rooms: usize,
+    pub resident_rooms: usize,
+    pub pruned_pending_rooms: usize,
+}
+
+/// What happened when `GlobalState::evict_room` was asked to evict a room.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Eviction {
+    Evicted,
+    NotResident,
+    /// The room has sessions, or somebody was in the middle of joining it.
+    HasSessions,
+}
+
 #[derive(Default)]
 pub struct GlobalState {
-    // TODO: rooms are never cleaned up. We need to GC them and move them to a database
+    /// Rooms that are resident in memory. Idle rooms are written back and evicted by
+    /// `collect_garbage`.
     rooms: RwLock<HashMap<RoomId, Arc<Mutex<RoomState>>>>,
     /// Room futures that we're currently reading from. This is used to avoid a (small) thundering
-    /// herd problem where we read the same room from SQL multiple times.
-    // TODO: Periodically clean out dead WeakShared refs during GC.
-    #[cfg(feature = "sql")]
+    /// herd problem where we read the same room from storage multiple times. Dead refs are cleaned
+    /// out by `collect_garbage`.
     pending_rooms: Mutex<HashMap<RoomId, PendingRoomState>>,
+    /// Used to size the queues of rooms read from storage.
+    limits: LimitsConfig,
 }
 
 impl GlobalState {
-    pub async fn insert_room(&self, room_id: RoomId, room_state: Arc<Mutex<RoomState>>) {
-        self.rooms.write().await.insert(room_id, room_state);
+    pub fn new(limits: LimitsConfig) -> Self {
+        GlobalState {
+            limits,
+            ..Default::default()
+        }
     }
 
-    /// Attempts to read the room from memory. If not found, it returns None. To fall back to
-    /// reading from SQL, enable the "sql" feature for this crate. This requires a stub 'pool'
-    /// argument and returns a Result to match the type signature of the sql-enabled version.
-    #[cfg(not(feature = "sql"))]
-    pub async fn get_room(
-        self: &Arc<Self>,
-        _db_pool: &Arc<sql::Pool>,
-        room_id: &RoomId,
-    ) -> Result<Option<Arc<Mutex<RoomState>>>, Arc<sql::ReadRoomError>> {
-        Ok(self.rooms.read().await.get(room_id).cloned())
+    pub async fn insert_room(&self, room_id: RoomId, room_state: Arc<Mutex<RoomState>>) {
+        self.rooms.write().await.insert(room_id, room_state);
     }
 
-    /// Attempts to read the room from memory, falling back to doing a lookup from SQL if it
-    /// doesn't exist in memory.
-    #[cfg(feature = "sql")]
+    /// Attempts to read the room from memory, falling back to reading it from storage if it
+    /// isn't in memory.
     pub async fn get_room(
         self: &Arc<Self>,
-        db_pool: &Arc<sql::Pool>,
+        storage: &Arc<dyn Storage>,
         room_id: &RoomId,
-    ) -> Result<Option<Arc<Mutex<RoomState>>>, Arc<sql::ReadRoomError>> {
+    ) -> Result<Option<Arc<Mutex<RoomState>>>, Arc<StorageError>> {
         let rooms_read_guard = self.rooms.read().await;
         if let Some(room) = rooms_read_guard.get(room_id) {
             return Ok(Some(room.clone()));
@@ -69,12 +84,15 @@ impl GlobalState {
                 let fut = {
                     let room_id = *room_id;
                     let self_arc = self.clone();
-                    let db_pool = db_pool.clone();
+                    let storage = storage.clone();
                     async move {
-                        let room = sql::read_room(&db_pool, room_id)
+                        let timer = metrics::ROOM_READ_DURATION.start_timer();
+                        let room = storage
+                            .read_room(room_id, &self_arc.limits)
                             .await
                             .map(|rs| rs.map(|rs| Arc::new(Mutex::new(rs))))
                             .map_err(Arc::new);
+                        timer.observe_duration();
                         if let Ok(Some(room)) = room.clone() {
                             self_arc.rooms.write().await.insert(room_id, room);
                         }
@@ -96,10 +114,61 @@ impl GlobalState {
         drop(rooms_read_guard);
         drop(pending_rooms_guard);
 
-        // Run the sql query
+        // Read from storage
         read_room_fut.await
     }
 
+    /// Every room that's currently held in memory.
+    pub async fn resident_rooms(&self) -> Vec<(RoomId, Arc<Mutex<RoomState>>)> {
+        self.rooms
+            .read()
+            .await
+            .iter()
+            .map(|(room_id, room_state)| (*room_id, room_state.clone()))
+            .collect()
+    }
+
+    /// Writes a room back and removes it from memory, whether or not it's idle. Rooms with
+    /// connected (or suspended) sessions can't be evicted, since those sessions would be left
+    /// attached to a room that nobody else can find.
+    pub async fn evict_room(
+        &self,
+        storage: &dyn Storage,
+        room_id: &RoomId,
+    ) -> Result<Eviction, StorageError> {
+        let rs_mutex = match self.rooms.read().await.get(room_id) {
+            Some(rs_mutex) => rs_mutex.clone(),
+            None => return Ok(Eviction::NotResident),
+        };
+        if rs_mutex.lock().await.session_count() != 0 {
+            return Ok(Eviction::HasSessions);
+        }
+        storage::writeback_rooms(storage, vec![(*room_id, rs_mutex.clone())]).await?;
+        drop(rs_mutex);
+
+        let mut rooms_guard = self.rooms.write().await;
+        let evictable = match rooms_guard.get(room_id) {
+            // see collect_garbage
+            Some(rs_mutex) if Arc::strong_count(rs_mutex) == 1 => match rs_mutex.try_lock() {
+                Ok(rs) => rs.session_count() == 0 && !rs.dirty,
+                Err(_) => false,
+            },
+            Some(_) => false,
+            None => return Ok(Eviction::NotResident),
+        };
+        if evictable {
+            rooms_guard.remove(room_id);
+            Ok(Eviction::Evicted)
+        } else {
+            Ok(Eviction::HasSessions)
+        }
+    }
+
+    /// The number of rooms currently held in memory.
+    pub async fn resident_room_count(&self) -> usize {
+        self.rooms.read().await.len()
+    }
+
     /// Gets a vec of all the dirty rooms that need to be written back to disk. This is an
     /// expensive operation and will take a read lock on the data structure for some period of
     /// time.
@@ -107,7 +176,6 @@ impl GlobalState {
     /// Eventually, this could be made more efficient by putting dirty rooms into a list when they
     /// become dirty, that way we wouldn't have to grab a lock on every room to read the dirty
     /// status.
-    #[cfg(feature = "sql")]
     pub async fn get_dirty_rooms(&self) -> Vec<(RoomId, Arc<Mutex<RoomState>>)> {
         // copy room info out of the HashMap as quickly as possible to avoid holding the RwLock
         let room_map_guard = self.rooms.read().await;
@@ -119,7 +187,7 @@ impl GlobalState {
         drop(room_map_guard);
 
         // filter room_vec by grabbing the lock on every room and checking the dirty flag
-        stream::iter(room_vec.into_iter())
+        stream::iter(room_vec)
             .map(|(room_id, rs_mutex)| async move {
                 if rs_mutex.lock().await.dirty {
                     Some((room_id, rs_mutex))
@@ -133,6 +201,106 @@ impl GlobalState {
             .collect()
             .await
     }
+    /// Evicts rooms from memory that have no connected sessions and have been idle for at least
+    /// `idle_timeout`. If more than `max_resident_rooms` rooms are resident, idle rooms are evicted
+    /// early, least recently active first.
+    ///
+    /// Dirty rooms are written back before they're evicted. If the writeback fails, nothing is
+    /// evicted and the error is returned.
+    pub async fn collect_garbage(
+        &self,
+        storage: &dyn Storage,
+        idle_timeout: Duration,
+        max_resident_rooms: Option<usize>,
+    ) -> Result<GcStats, StorageError> {
+        let now = Instant::now();
+
+        // copy room info out of the HashMap as quickly as possible to avoid holding the RwLock
+        let room_vec: Vec<_> = self
+            .rooms
+            .read()
+            .await
+            .iter()
+            .map(|(room_id, room_state)| (*room_id, room_state.clone()))
+            .collect();
+        let resident_count = room_vec.len();
+
+        // find every room with no sessions, along with how long it's been idle
+        let mut idle_rooms: Vec<(RoomId, Arc<Mutex<RoomState>>, Duration)> = stream::iter(room_vec)
+            .map(|(room_id, rs_mutex)| async move {
+                let rs = rs_mutex.lock().await;
+                if rs.session_count() == 0 {
+                    let idle_for = now.saturating_duration_since(rs.last_activity);
+                    drop(rs);
+                    Some((room_id, rs_mutex, idle_for))
+                } else {
+                    None
+                }
+            })
+            .buffer_unordered(5)
+            .filter_map(|el| async move { el })
+            .collect()
+            .await;
+        // most idle first
+        idle_rooms.sort_by_key(|(_, _, idle_for)| Reverse(*idle_for));
+
+        let over_limit = max_resident_rooms
+            .map(|max| resident_count.saturating_sub(max))
+            .unwrap_or(0);
+        let candidates: Vec<_> = idle_rooms
+            .into_iter()
+            .enumerate()
+            .take_while(|(idx, (_, _, idle_for))| *idle_for >= idle_timeout || *idx < over_limit)
+            .map(|(_, room)| room)
+            .collect();
+
+        let mut dirty_candidates = Vec::new();
+        for (room_id, rs_mutex, _) in candidates.iter() {
+            if rs_mutex.lock().await.dirty {
+                dirty_candidates.push((*room_id, rs_mutex.clone()));
+            }
+        }
+        storage::writeback_rooms(storage, dirty_candidates).await?;
+
+        let candidate_ids: Vec<RoomId> = candidates
+            .into_iter()
+            .map(|(room_id, _, _)| room_id)
+            .collect();
+
+        let mut rooms_guard = self.rooms.write().await;
+        let mut evicted_rooms = 0;
+        for room_id in candidate_ids {
+            let evictable = match rooms_guard.get(&room_id) {
+                // If anybody else holds a reference, they may be about to create a session, so
+                // leave the room alone. Nobody new can get a reference while we hold the write
+                // lock.
+                Some(rs_mutex) if Arc::strong_count(rs_mutex) == 1 => match rs_mutex.try_lock() {
+                    Ok(rs) => rs.session_count() == 0 && !rs.dirty,
+                    Err(_) => false,
+                },
+                _ => false,
+            };
+            if evictable {
+                rooms_guard.remove(&room_id);
+                evicted_rooms += 1;
+            }
+        }
+        let resident_rooms = rooms_guard.len();
+        drop(rooms_guard);
+
+        let pruned_pending_rooms = {
+            let mut pending_rooms_guard = self.pending_rooms.lock().await;
+            let before = pending_rooms_guard.len();
+            pending_rooms_guard.retain(|_, weak_fut| weak_fut.upgrade().is_some());
+            before - pending_rooms_guard.len()
+        };
+
+        Ok(GcStats {
+            evicted_rooms,
+            resident_rooms,
+            pruned_pending_rooms,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -141,23 +309,32 @@ mod tests {
     use std::iter;
 
     use super::*;
-    use crate::config::DatabaseConfig;
+    use crate::config::{DatabaseConfig, LimitsConfig};
+    use crate::room::{Completion, SessionRole};
 
     fn mock_database_config() -> DatabaseConfig {
         DatabaseConfig {
-            uri: "sqlite::memory:".to_owned(),
+            uri: if cfg!(feature = "sql") {
+                "sqlite::memory:"
+            } else {
+                "memory:"
+            }
+            .to_owned(),
+            ..Default::default()
         }
     }
 
-    async fn mock_database_pool() -> Arc<sql::Pool> {
-        Arc::new(sql::new_pool(&mock_database_config()).await.unwrap())
+    async fn mock_storage() -> Arc<dyn Storage> {
+        storage::connect(&mock_database_config()).await.unwrap()
     }
 
     #[tokio::test]
-    #[cfg(feature = "sql")]
     async fn dirty_rooms() {
         let room_ids: Vec<_> = iter::repeat_with(RoomId::random).take(20).collect();
-        let mut rooms: Vec<_> = room_ids.iter().map(|rid| RoomState::new(*rid)).collect();
+        let mut rooms: Vec<_> = room_ids
+            .iter()
+            .map(|rid| RoomState::new(*rid, &LimitsConfig::default()))
+            .collect();
 
         // newly constructed rooms are dirty
         assert!(rooms[0].dirty);
@@ -169,10 +346,10 @@ mod tests {
 
         // an empty vec of diffs doesn't actually change the board, but is good enough to mark it
         // as dirty
-        rooms[0].apply_diffs(0, 0, vec![]).unwrap();
-        rooms[1].apply_diffs(0, 0, vec![]).unwrap();
-        rooms[2].apply_diffs(0, 0, vec![]).unwrap();
-        rooms[10].apply_diffs(0, 0, vec![]).unwrap();
+        rooms[0].apply_diffs(0, 0, None, vec![]).unwrap();
+        rooms[1].apply_diffs(0, 0, None, vec![]).unwrap();
+        rooms[2].apply_diffs(0, 0, None, vec![]).unwrap();
+        rooms[10].apply_diffs(0, 0, None, vec![]).unwrap();
 
         let gs = GlobalState::default();
         for r in rooms {
@@ -188,62 +365,218 @@ mod tests {
 
         assert_eq!(
             dirty_room_ids,
-            vec![room_ids[0], room_ids[1], room_ids[2], room_ids[10]]
+            [room_ids[0], room_ids[1], room_ids[2], room_ids[10]]
                 .iter()
                 .cloned()
                 .collect()
         );
     }
 
+    #[tokio::test(threaded_scheduler)]
+    async fn writeback_clears_dirty_rooms() {
+        let storage = mock_storage().await;
+        let gs = Arc::new(GlobalState::default());
+        for _ in 0..3 {
+            let room_id = RoomId::random();
+            gs.insert_room(
+                room_id,
+                Arc::new(Mutex::new(RoomState::new(
+                    room_id,
+                    &LimitsConfig::default(),
+                ))),
+            )
+            .await;
+        }
+
+        assert_eq!(storage::writeback(&*storage, &gs).await.unwrap(), 3);
+        assert!(gs.get_dirty_rooms().await.is_empty());
+        // nothing changed, so there's nothing left to write
+        assert_eq!(storage::writeback(&*storage, &gs).await.unwrap(), 0);
+    }
+
     #[tokio::test(threaded_scheduler)]
     async fn get_room_missing() {
-        let db_pool = mock_database_pool().await;
+        let storage = mock_storage().await;
         let gs = Arc::new(GlobalState::default());
         assert!(matches!(
-            gs.get_room(&db_pool, &RoomId::random()).await,
+            gs.get_room(&storage, &RoomId::random()).await,
             Ok(None)
         ));
     }
 
     #[tokio::test(threaded_scheduler)]
     async fn get_room_local() {
-        let db_pool = mock_database_pool().await;
+        let storage = mock_storage().await;
         let gs = Arc::new(GlobalState::default());
         let room_id = RoomId::random();
 
-        let room_state_inserted = Arc::new(Mutex::new(RoomState::new(room_id)));
+        let room_state_inserted = Arc::new(Mutex::new(RoomState::new(
+            room_id,
+            &LimitsConfig::default(),
+        )));
         gs.insert_room(room_id, room_state_inserted.clone()).await;
 
-        let room_state_read = gs.get_room(&db_pool, &room_id).await.unwrap().unwrap();
+        let room_state_read = gs.get_room(&storage, &room_id).await.unwrap().unwrap();
 
         // these are the same by identity
         assert!(Arc::ptr_eq(&room_state_inserted, &room_state_read));
     }
 
     #[tokio::test(threaded_scheduler)]
-    #[cfg(feature = "sql")]
-    async fn get_room_from_sql() {
-        let db_pool = mock_database_pool().await;
+    async fn get_room_from_storage() {
+        let storage = mock_storage().await;
         let gs = Arc::new(GlobalState::default());
         let room_id = RoomId::random();
 
-        let room_state_inserted = Arc::new(Mutex::new(RoomState::new(room_id)));
+        let room_state_inserted = Arc::new(Mutex::new(RoomState::new(
+            room_id,
+            &LimitsConfig::default(),
+        )));
+        room_state_inserted.lock().await.completion = Some(Completion {
+            solve_time_ms: 1234,
+            solved_by: 2,
+        });
         gs.insert_room(room_id, room_state_inserted.clone()).await;
 
         // writeback, then drop the global state
-        sql::writeback(&db_pool, &gs).await.unwrap();
+        storage::writeback(&*storage, &gs).await.unwrap();
         drop(gs);
 
         // make a new (empty) global state
         let gs = Arc::new(GlobalState::default());
-        let room_state_read = gs.get_room(&db_pool, &room_id).await.unwrap().unwrap();
+        let room_state_read = gs.get_room(&storage, &room_id).await.unwrap().unwrap();
 
-        // these are different by identity, because we deserialized it from sql
+        // these are different by identity, because we read it back from storage
         assert!(!Arc::ptr_eq(&room_state_inserted, &room_state_read));
-        // however, the room id matches
+        // however, the room id, completion, and access keys match
         assert_eq!(
             room_state_inserted.lock().await.room_id,
             room_state_read.lock().await.room_id
         );
+        assert_eq!(
+            room_state_inserted.lock().await.completion,
+            room_state_read.lock().await.completion
+        );
+        assert_eq!(
+            room_state_inserted.lock().await.access_keys,
+            room_state_read.lock().await.access_keys
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn event_log_round_trip() {
+        use crate::board::{BoardDiff, BoardDiffOperation};
+        use crate::digit::Digit;
+        use crate::room::{replay, RoomEventKind};
+
+        let storage = mock_storage().await;
+        let gs = Arc::new(GlobalState::default());
+        let room_id = RoomId::random();
+        let set_number = |idx: u8| BoardDiff {
+            squares: vec![idx],
+            operation: BoardDiffOperation::SetNumber {
+                digit: Some(Digit::D5),
+            },
+        };
+
+        let mut rs = RoomState::new(room_id, &LimitsConfig::default());
+        rs.apply_diffs(1, 1, None, vec![set_number(0)]).unwrap();
+        gs.insert_room(room_id, Arc::new(Mutex::new(rs))).await;
+        storage::writeback(&*storage, &gs).await.unwrap();
+        drop(gs);
+
+        // the log carries on from where it left off after the room is loaded again
+        let gs = Arc::new(GlobalState::default());
+        let rs_mutex = gs.get_room(&storage, &room_id).await.unwrap().unwrap();
+        let board = {
+            let mut rs = rs_mutex.lock().await;
+            assert_eq!(rs.revision(), 1);
+            rs.apply_diffs(2, 1, None, vec![set_number(1)]).unwrap();
+            rs.board.clone()
+        };
+        storage::writeback(&*storage, &gs).await.unwrap();
+
+        let events = storage.read_events(room_id).await.unwrap();
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.seq, e.revision))
+                .collect::<Vec<_>>(),
+            vec![(0, 1), (1, 2)]
+        );
+        assert_eq!(events[1].session_id, 2);
+        assert!(matches!(events[1].kind, RoomEventKind::ApplyDiffs { .. }));
+        assert_eq!(replay(&events).unwrap(), board);
+        assert_eq!(replay(&events[..1]).unwrap().squares()[1].number, None);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn collect_garbage_evicts_idle_rooms() {
+        let storage = mock_storage().await;
+        let gs = Arc::new(GlobalState::default());
+        let idle_room_id = RoomId::random();
+        let busy_room_id = RoomId::random();
+        gs.insert_room(
+            idle_room_id,
+            Arc::new(Mutex::new(RoomState::new(
+                idle_room_id,
+                &LimitsConfig::default(),
+            ))),
+        )
+        .await;
+        let busy_room = Arc::new(Mutex::new(RoomState::new(
+            busy_room_id,
+            &LimitsConfig::default(),
+        )));
+        let _session = busy_room
+            .lock()
+            .await
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        gs.insert_room(busy_room_id, busy_room).await;
+
+        let stats = gs
+            .collect_garbage(&*storage, Duration::from_secs(0), None)
+            .await
+            .unwrap();
+        assert_eq!(stats.evicted_rooms, 1);
+        assert_eq!(stats.resident_rooms, 1);
+        assert!(gs.rooms.read().await.contains_key(&busy_room_id));
+
+        // the evicted room was written back first, so it's still readable
+        assert!(gs
+            .get_room(&storage, &idle_room_id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn collect_garbage_respects_idle_timeout_and_limit() {
+        let storage = mock_storage().await;
+        let gs = Arc::new(GlobalState::default());
+        let room_ids: Vec<_> = iter::repeat_with(RoomId::random).take(5).collect();
+        for (idx, room_id) in room_ids.iter().enumerate() {
+            let mut rs = RoomState::new(*room_id, &LimitsConfig::default());
+            // make lower indexes look like they've been idle for longer
+            rs.last_activity -= Duration::from_secs(100 - idx as u64);
+            gs.insert_room(*room_id, Arc::new(Mutex::new(rs))).await;
+        }
+
+        // nothing has been idle for long enough, and there's no limit
+        let stats = gs
+            .collect_garbage(&*storage, Duration::from_secs(3600), None)
+            .await
+            .unwrap();
+        assert_eq!(stats.evicted_rooms, 0);
+
+        // the limit evicts the least recently active rooms first
+        let stats = gs
+            .collect_garbage(&*storage, Duration::from_secs(3600), Some(3))
+            .await
+            .unwrap();
+        assert_eq!(stats.evicted_rooms, 2);
+        let resident: HashSet<_> = gs.rooms.read().await.keys().cloned().collect();
+        assert_eq!(resident, room_ids[2..].iter().cloned().collect());
     }
 }
@@ -0,0 +1,123 @@
+//! Endpoints for load balancers to check on the server.
+//!
+//! - `GET /healthz` succeeds as long as the server is able to respond at all.
+//! - `GET /readyz` only succeeds once storage is connected and migrated, and starts failing again
+//!   as soon as a shutdown signal is received, so that traffic can drain away before the process
+//!   exits.
+//!
+//! Until storage is connected, every other route that needs it gets a 503 from `starting_filter`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+#[derive(Default)]
+pub struct Health {
+    ready: AtomicBool,
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    /// Called once storage is connected and every migration has run.
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    /// Called when a shutdown signal is received. There's no going back from this.
+    pub fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    fn readiness(&self) -> (StatusCode, &'static str) {
+        if self.is_shutting_down() {
+            (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+        } else if !self.ready.load(Ordering::SeqCst) {
+            (StatusCode::SERVICE_UNAVAILABLE, "starting")
+        } else {
+            (StatusCode::OK, "ready")
+        }
+    }
+}
+
+/// Answers every request with a 503 until the server is ready, and rejects them after that, so
+/// that routes which need storage can be put behind it.
+pub fn starting_filter(health: Arc<Health>) -> BoxedFilter<(impl Reply,)> {
+    warp::any()
+        .and(warp::any().map(move || health.clone()))
+        .and_then(|health: Arc<Health>| async move {
+            if health.ready.load(Ordering::SeqCst) {
+                Err(warp::reject::not_found())
+            } else {
+                Ok(warp::reply::with_status(
+                    "starting",
+                    StatusCode::SERVICE_UNAVAILABLE,
+                ))
+            }
+        })
+        .boxed()
+}
+
+pub fn get_filter(health: Arc<Health>) -> BoxedFilter<(impl Reply,)> {
+    let healthz = warp::path!("healthz").map(|| "ok");
+    let readyz = warp::path!("readyz")
+        .and(warp::any().map(move || health.clone()))
+        .map(|health: Arc<Health>| {
+            let (status, body) = health.readiness();
+            warp::reply::with_status(body, status)
+        });
+    warp::get().and(healthz.or(readyz)).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn status(filter: &BoxedFilter<(impl Reply + 'static,)>, path: &str) -> StatusCode {
+        warp::test::request()
+            .path(path)
+            .reply(filter)
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let health = Arc::new(Health::default());
+        let filter = get_filter(health.clone());
+        assert_eq!(status(&filter, "/healthz").await, StatusCode::OK);
+        assert_eq!(
+            status(&filter, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        health.set_ready();
+        assert_eq!(status(&filter, "/readyz").await, StatusCode::OK);
+        health.set_shutting_down();
+        assert_eq!(
+            status(&filter, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // the server is still alive while it drains
+        assert_eq!(status(&filter, "/healthz").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn starting() {
+        let health = Arc::new(Health::default());
+        let filter = starting_filter(health.clone());
+        assert_eq!(
+            status(&filter, "/api/v1/rooms").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        health.set_ready();
+        assert_eq!(
+            status(&filter, "/api/v1/rooms").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
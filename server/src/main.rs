@@ -1,23 +1,50 @@
+mod admin;
 mod board;
+mod color;
 mod config;
 mod cursors;
 mod digit;
 mod error;
+mod gc;
 mod global_state;
+mod health;
+mod metrics;
+mod ot;
+mod puzzle;
 mod realtime;
+mod replay;
 mod room;
-mod sql;
+mod rooms;
+mod shutdown;
+mod solver;
+mod storage;
+mod writeback;
 
 use log::{error, info, warn};
 use signal_hook::iterator::Signals;
 use signal_hook::{SIGINT, SIGQUIT, SIGTERM};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::oneshot;
 use tokio::task;
+use tokio::time;
+use warp::Filter;
 
+use crate::config::DatabaseConfig;
 use crate::global_state::GlobalState;
+use crate::health::Health;
+use crate::storage::{DeferredStorage, MemoryStorage, Storage};
 
-async fn signal_listener(shutdown_tx: oneshot::Sender<()>) {
+/// How long to wait before trying to connect to storage again.
+const STORAGE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Waits for a shutdown signal, then gives connected clients `drain_period` to leave before
+/// letting the HTTP server stop.
+async fn signal_listener(
+    health: Arc<Health>,
+    global_state: Arc<GlobalState>,
+    drain_period: Duration,
+) {
     let result = task::spawn_blocking(move || {
         // signal_hook doesn't support tokio 0.2 or 0.3 yet (but will soon)
         // https://github.com/vorner/signal-hook/pull/51
@@ -31,18 +58,7 @@ async fn signal_listener(shutdown_tx: oneshot::Sender<()>) {
         };
         for sig in signals.forever() {
             match sig {
-                SIGINT | SIGQUIT | SIGTERM => {
-                    info!("Shutting down HTTP server.");
-                    let result = shutdown_tx.send(());
-                    if result.is_err() {
-                        error!(concat!(
-                            "Failed to send shutdown signal to HTTP server. ",
-                            "Exiting uncleanly. This may lose user data."
-                        ));
-                        std::process::exit(1);
-                    }
-                    return;
-                }
+                SIGINT | SIGQUIT | SIGTERM => return,
                 _ => {}
             }
         }
@@ -51,37 +67,140 @@ async fn signal_listener(shutdown_tx: oneshot::Sender<()>) {
     if let Err(err) = result {
         error!("Signal listener failed to complete: {}", err);
     }
-}
 
-#[tokio::main]
-async fn main() {
-    let config = config::get_config().unwrap();
-    config.logging.to_dispatch().apply().unwrap();
-
-    info!("Starting server");
+    // this also stops new clients from connecting
+    health.set_shutting_down();
+    info!(
+        "Shutting down. Waiting up to {:?} for clients to disconnect.",
+        drain_period
+    );
+    shutdown::drain(&global_state, drain_period).await;
+    info!("Shutting down HTTP server.");
+}
 
+/// Connects to storage and runs its migrations, retrying until that works. The server reports
+/// that it isn't ready in the meantime.
+async fn connect_storage(config: &DatabaseConfig) -> Arc<dyn Storage> {
     if !cfg!(feature = "sql") {
         warn!(concat!(
             "The 'sql' feature was not compiled into this binary. Database settings will be ",
             "ignored, and data will not persist across server restarts."
         ));
+        return Arc::new(MemoryStorage::default());
     }
+    loop {
+        match storage::connect(config).await {
+            Ok(storage) => return storage,
+            Err(err) => {
+                error!(
+                    "Failed to connect to storage, retrying in {:?}: {}",
+                    STORAGE_RETRY_INTERVAL, err
+                );
+                time::delay_for(STORAGE_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
 
-    let db_pool = Arc::new(sql::new_pool(&config.database).await.unwrap());
-    let global_state: Arc<GlobalState> = Arc::new(Default::default());
-    let realtime_api = realtime::get_filter(global_state.clone(), db_pool.clone());
+#[tokio::main]
+async fn main() {
+    let config = Arc::new(config::get_config().unwrap());
+    config.logging.to_dispatch().apply().unwrap();
+
+    info!("Starting server");
 
-    let (shutdown_tx, shutdown_rx) = oneshot::channel();
-    let (_addr, server) = warp::serve(realtime_api)
-        .bind_with_graceful_shutdown(config.listen_addr, async { shutdown_rx.await.unwrap() });
+    // Routes get storage that's only connected once the server is listening, so that /readyz can
+    // report that it's still starting while migrations run.
+    let deferred_storage = Arc::new(DeferredStorage::default());
+    let storage: Arc<dyn Storage> = deferred_storage.clone();
+    let global_state = Arc::new(GlobalState::new(config.limits.clone()));
+    let health = Arc::new(Health::default());
+    let realtime_api = realtime::get_filter(
+        config.clone(),
+        health.clone(),
+        global_state.clone(),
+        storage.clone(),
+    );
+    let replay_api = replay::get_filter(global_state.clone(), storage.clone());
+    let rooms_api = rooms::get_filter(config.clone(), global_state.clone(), storage.clone());
+    let metrics_api = metrics::get_filter(global_state.clone());
+    let admin_api = admin::get_filter(config.clone(), global_state.clone(), storage.clone());
+    let health_api = health::get_filter(health.clone());
+
+    let routes = health_api
+        .or(metrics_api)
+        .or(health::starting_filter(health.clone()))
+        .or(realtime_api)
+        .or(replay_api)
+        .or(rooms_api)
+        .or(admin_api);
+    let (_addr, server) = warp::serve(routes).bind_with_graceful_shutdown(
+        config.listen_addr,
+        signal_listener(
+            health.clone(),
+            global_state.clone(),
+            config.shutdown.drain_period(),
+        ),
+    );
+    let mut server = task::spawn(server);
+
+    // a shutdown signal can arrive before storage ever connects
+    let connected = tokio::select! {
+        connected = connect_storage(&config.database) => connected,
+        _ = &mut server => {
+            info!("HTTP server stopped before storage was connected");
+            return;
+        }
+    };
+    deferred_storage.set(connected);
+    health.set_ready();
+    info!("Storage is connected, ready for requests");
 
-    tokio::join!(server, signal_listener(shutdown_tx));
+    let (writeback_stop_tx, writeback_stop_rx) = oneshot::channel();
+    let periodic_writeback = config.database.writeback_interval().map(|period| {
+        task::spawn(writeback::run_periodically(
+            storage.clone(),
+            global_state.clone(),
+            period,
+            writeback_stop_rx,
+        ))
+    });
+
+    let (gc_stop_tx, gc_stop_rx) = oneshot::channel();
+    let periodic_gc = config.gc.interval().map(|period| {
+        task::spawn(gc::run_periodically(
+            storage.clone(),
+            global_state.clone(),
+            period,
+            config.gc.idle_timeout(),
+            config.gc.max_resident_rooms,
+            gc_stop_rx,
+        ))
+    });
+
+    if let Err(err) = server.await {
+        error!("HTTP server failed to complete: {}", err);
+    }
 
     info!("HTTP server stopped");
-    if cfg!(feature = "sql") {
-        info!("Flushing global state to database");
-        sql::writeback(&db_pool, &global_state).await.unwrap();
+    if let Some(periodic_gc) = periodic_gc {
+        let _ = gc_stop_tx.send(());
+        if let Err(err) = periodic_gc.await {
+            error!("Garbage collection task failed to complete: {}", err);
+        }
+    }
+    if let Some(periodic_writeback) = periodic_writeback {
+        // let any in-progress flush finish before doing the final one
+        let _ = writeback_stop_tx.send(());
+        if let Err(err) = periodic_writeback.await {
+            error!("Periodic writeback task failed to complete: {}", err);
+        }
     }
+    // sessions that outlasted the drain are still connected, and shouldn't be able to change
+    // anything the final writeback would miss
+    shutdown::stop_changes(&global_state).await;
+    info!("Flushing global state to storage");
+    storage::writeback(&*storage, &global_state).await.unwrap();
 
     info!("Graceful shutdown succeeded")
 }
@@ -0,0 +1,119 @@
+//! Prometheus metrics, served in the text exposition format from `GET /metrics`.
+//!
+//! Everything is registered with the `prometheus` crate's default registry the first time it's
+//! used, so metrics that haven't been touched yet won't show up.
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+use std::sync::Arc;
+use warp::filters::BoxedFilter;
+use warp::http::header::CONTENT_TYPE;
+use warp::{Filter, Reply};
+
+use crate::global_state::GlobalState;
+use crate::realtime::InternalErrorReject;
+
+pub static ROOMS_RESIDENT: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("sudoku_rooms_resident", "Rooms currently held in memory.").unwrap()
+});
+
+pub static SESSIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sudoku_sessions",
+        "Sessions that are connected, or disconnected but still able to resume, by role.",
+        &["role"]
+    )
+    .unwrap()
+});
+
+pub static DIFFS_APPLIED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "sudoku_diffs_applied_total",
+        "Board diffs applied to rooms, including undos and redos."
+    )
+    .unwrap()
+});
+
+pub static LAGGED_FULL_UPDATES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "sudoku_lagged_full_updates_total",
+        "Full updates sent because a session fell too far behind to be sent partial updates."
+    )
+    .unwrap()
+});
+
+pub static WRITEBACK_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "sudoku_writeback_duration_seconds",
+        "How long it took to write a batch of dirty rooms back to storage."
+    )
+    .unwrap()
+});
+
+pub static WRITEBACK_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "sudoku_writeback_failures_total",
+        "Rooms that failed to be written back to storage, and were left dirty."
+    )
+    .unwrap()
+});
+
+pub static ROOM_READ_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "sudoku_room_read_duration_seconds",
+        "How long it took to read a room that wasn't in memory from storage."
+    )
+    .unwrap()
+});
+
+pub static WEBSOCKET_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sudoku_websocket_errors_total",
+        "Realtime API connections that ended with an error, by kind.",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub fn get_filter(global_state: Arc<GlobalState>) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(warp::any().map(move || global_state.clone()))
+        .and_then(|global_state: Arc<GlobalState>| async move {
+            ROOMS_RESIDENT.set(global_state.resident_room_count().await as i64);
+            let encoder = TextEncoder::new();
+            let mut body = Vec::new();
+            encoder
+                .encode(&prometheus::gather(), &mut body)
+                .map_err(|_| warp::reject::custom(InternalErrorReject))?;
+            Result::<_, warp::reject::Rejection>::Ok(warp::reply::with_header(
+                body,
+                CONTENT_TYPE,
+                encoder.format_type(),
+            ))
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        DIFFS_APPLIED.inc();
+        let global_state = Arc::new(GlobalState::default());
+        let response = warp::test::request()
+            .path("/metrics")
+            .reply(&get_filter(global_state))
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = std::str::from_utf8(response.body()).unwrap();
+        assert!(body.contains("sudoku_rooms_resident 0"));
+        assert!(body.contains("sudoku_diffs_applied_total"));
+    }
+}
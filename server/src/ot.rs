@@ -0,0 +1,268 @@
+//! Operational transformation for [BoardDiff]s.
+//!
+//! Clients send diffs along with the room revision they were based on. If other sessions' diffs
+//! were applied since that revision, the incoming diffs are transformed against them before being
+//! applied, so that the result doesn't depend on which concurrent diff reached the server first.
+//!
+//! Diffs only interact when they touch the same square. The rules for two concurrent operations
+//! on the same square are:
+//!
+//! - Two `SetNumber`s with different digits: the session with priority (the lower session id)
+//!   wins, and the other `SetNumber` is dropped for that square.
+//! - `RemovePencilMark` and `AddPencilMark` for the same digit and pencil type: the add wins.
+//! - `ClearPencilMarks` and `AddPencilMark` of the same pencil type: the clear only removes the
+//!   marks the clearing session could see, so the concurrently added mark survives.
+//! - Colors follow the same rules as pencil marks: `RemoveColor` loses to a concurrent `AddColor`
+//!   of the same color, and `ClearColors` keeps concurrently added colors.
+//!
+//! Everything else commutes and is left alone. That includes `SetLocked`, which only the host can
+//! send: anything else done to a square after it's locked is ignored, so the lock wins.
+
+use crate::board::{BoardDiff, BoardDiffOperation};
+
+/// What should happen to a diff's operation on the squares it shares with a concurrent diff.
+enum Overlap {
+    Keep,
+    Drop,
+    /// Keep the operation, and then also apply this operation to the shared squares.
+    KeepThen(BoardDiffOperation),
+}
+
+fn overlap(op: &BoardDiffOperation, applied: &BoardDiffOperation, has_priority: bool) -> Overlap {
+    use BoardDiffOperation::*;
+
+    match (op, applied) {
+        (
+            SetNumber { digit },
+            SetNumber {
+                digit: applied_digit,
+            },
+        ) => {
+            if digit == applied_digit || has_priority {
+                Overlap::Keep
+            } else {
+                Overlap::Drop
+            }
+        }
+        (
+            RemovePencilMark { r#type, digit },
+            AddPencilMark {
+                r#type: applied_type,
+                digit: applied_digit,
+            },
+        ) if r#type == applied_type && digit == applied_digit => Overlap::Drop,
+        (
+            ClearPencilMarks { r#type },
+            AddPencilMark {
+                r#type: applied_type,
+                digit,
+            },
+        ) if r#type == applied_type => Overlap::KeepThen(AddPencilMark {
+            r#type: *r#type,
+            digit: *digit,
+        }),
+        (
+            RemoveColor { color },
+            AddColor {
+                color: applied_color,
+            },
+        ) if color == applied_color => Overlap::Drop,
+        (ClearColors, AddColor { color }) => Overlap::KeepThen(AddColor { color: *color }),
+        _ => Overlap::Keep,
+    }
+}
+
+/// Transforms a single `diff` so that it can be applied after `applied`, where both were
+/// originally based on the same board. This may split the diff into several diffs, or drop it
+/// entirely.
+pub fn transform(diff: &BoardDiff, applied: &BoardDiff, has_priority: bool) -> Vec<BoardDiff> {
+    let (shared, unshared): (Vec<u8>, Vec<u8>) = diff
+        .squares
+        .iter()
+        .partition(|sq| applied.squares.contains(sq));
+    if shared.is_empty() {
+        return vec![diff.clone()];
+    }
+    match overlap(&diff.operation, &applied.operation, has_priority) {
+        Overlap::Keep => vec![diff.clone()],
+        Overlap::Drop if unshared.is_empty() => vec![],
+        Overlap::Drop => vec![BoardDiff {
+            squares: unshared,
+            operation: diff.operation.clone(),
+        }],
+        Overlap::KeepThen(operation) => vec![
+            diff.clone(),
+            BoardDiff {
+                squares: shared,
+                operation,
+            },
+        ],
+    }
+}
+
+/// Transforms two concurrent sequences of diffs against each other. Returns `(diffs', applied')`,
+/// where `diffs'` can be applied after `applied`, and `applied'` can be applied after `diffs`,
+/// and both orders produce the same board.
+pub fn transform_sequences(
+    diffs: Vec<BoardDiff>,
+    applied: Vec<BoardDiff>,
+    has_priority: bool,
+) -> (Vec<BoardDiff>, Vec<BoardDiff>) {
+    if diffs.is_empty() || applied.is_empty() {
+        return (diffs, applied);
+    }
+    if diffs.len() > 1 {
+        let mut diffs = diffs;
+        let rest = diffs.split_off(1);
+        let (mut first, applied) = transform_sequences(diffs, applied, has_priority);
+        let (rest, applied) = transform_sequences(rest, applied, has_priority);
+        first.extend(rest);
+        return (first, applied);
+    }
+    if applied.len() > 1 {
+        let mut applied = applied;
+        let rest = applied.split_off(1);
+        let (diffs, mut first) = transform_sequences(diffs, applied, has_priority);
+        let (diffs, rest) = transform_sequences(diffs, rest, has_priority);
+        first.extend(rest);
+        return (diffs, first);
+    }
+    (
+        transform(&diffs[0], &applied[0], has_priority),
+        transform(&applied[0], &diffs[0], !has_priority),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::board::{BoardPencilType, BoardState};
+    use crate::color::Color;
+    use crate::digit::Digit;
+
+    fn apply_all(board: &BoardState, diffs: &[BoardDiff]) -> BoardState {
+        let mut board = board.clone();
+        for diff in diffs {
+            board.apply(diff).unwrap();
+        }
+        board
+    }
+
+    /// Checks that applying `a` then `b'` gives the same result as applying `b` then `a'`.
+    fn assert_converges(board: &BoardState, a: &[BoardDiff], b: &[BoardDiff], a_priority: bool) {
+        let (a_prime, b_prime) = transform_sequences(a.to_vec(), b.to_vec(), a_priority);
+        let a_first = apply_all(&apply_all(board, a), &b_prime);
+        let b_first = apply_all(&apply_all(board, b), &a_prime);
+        assert_eq!(
+            a_first, b_first,
+            "diverged for a = {:?}, b = {:?}, a' = {:?}, b' = {:?}, a_priority = {}",
+            a, b, a_prime, b_prime, a_priority
+        );
+    }
+
+    fn digit(value: u8) -> Digit {
+        Digit::try_from(value).unwrap()
+    }
+
+    fn random_diff(rng: &mut StdRng) -> BoardDiff {
+        // use a small set of squares and digits so that diffs overlap often
+        let mut squares: Vec<u8> = (0..4).collect();
+        squares.shuffle(rng);
+        squares.truncate(rng.gen_range(1, 4));
+        let r#type = *[BoardPencilType::Centers, BoardPencilType::Corners]
+            .choose(rng)
+            .unwrap();
+        let d = digit(rng.gen_range(1, 4));
+        let color = Color::try_from(rng.gen_range(0, 3)).unwrap();
+        let operation = match rng.gen_range(0, 8) {
+            0 => BoardDiffOperation::SetNumber { digit: Some(d) },
+            1 => BoardDiffOperation::SetNumber { digit: None },
+            2 => BoardDiffOperation::AddPencilMark { r#type, digit: d },
+            3 => BoardDiffOperation::RemovePencilMark { r#type, digit: d },
+            4 => BoardDiffOperation::ClearPencilMarks { r#type },
+            5 => BoardDiffOperation::AddColor { color },
+            6 => BoardDiffOperation::RemoveColor { color },
+            _ => BoardDiffOperation::ClearColors,
+        };
+        BoardDiff { squares, operation }
+    }
+
+    fn random_diffs(rng: &mut StdRng) -> Vec<BoardDiff> {
+        (0..rng.gen_range(1, 5)).map(|_| random_diff(rng)).collect()
+    }
+
+    #[test]
+    fn conflicting_set_number() {
+        let set = |d| BoardDiff {
+            squares: vec![0, 1],
+            operation: BoardDiffOperation::SetNumber {
+                digit: Some(digit(d)),
+            },
+        };
+        // the diff with priority is kept
+        assert_eq!(transform(&set(1), &set(2), true), vec![set(1)]);
+        // the other is dropped
+        assert_eq!(transform(&set(2), &set(1), false), vec![]);
+        // but only on the shared squares
+        let applied = BoardDiff {
+            squares: vec![1],
+            ..set(1)
+        };
+        assert_eq!(
+            transform(&set(2), &applied, false),
+            vec![BoardDiff {
+                squares: vec![0],
+                ..set(2)
+            }]
+        );
+    }
+
+    #[test]
+    fn clear_racing_add() {
+        let clear = BoardDiff {
+            squares: vec![0, 1],
+            operation: BoardDiffOperation::ClearPencilMarks {
+                r#type: BoardPencilType::Centers,
+            },
+        };
+        let add = BoardDiff {
+            squares: vec![1, 2],
+            operation: BoardDiffOperation::AddPencilMark {
+                r#type: BoardPencilType::Centers,
+                digit: Digit::D5,
+            },
+        };
+        assert_eq!(
+            transform(&clear, &add, true),
+            vec![
+                clear.clone(),
+                BoardDiff {
+                    squares: vec![1],
+                    ..add.clone()
+                }
+            ]
+        );
+        assert_eq!(transform(&add, &clear, false), vec![add.clone()]);
+        let (clear, add) = ([clear], [add]);
+        assert_converges(&BoardState::default(), &clear, &add, true);
+        assert_converges(&BoardState::default(), &clear, &add, false);
+    }
+
+    #[test]
+    fn random_sequences_converge() {
+        let mut rng = StdRng::seed_from_u64(0x5ad0c0);
+        for _ in 0..5000 {
+            let base = apply_all(&BoardState::default(), &random_diffs(&mut rng));
+            let a = random_diffs(&mut rng);
+            let b = random_diffs(&mut rng);
+            // either side can be the one with priority
+            assert_converges(&base, &a, &b, true);
+            assert_converges(&base, &a, &b, false);
+        }
+    }
+}
@@ -0,0 +1,472 @@
+//! Reads and writes puzzles in the text formats that other sudoku tools use. Numbers read from a
+//! puzzle are givens, so they're `locked`.
+//!
+//! - `line`: 81 characters on a single line, using `1`-`9` for numbers and `.` or `0` for empty
+//!   squares. This is what the web client's `?board=` parameter uses.
+//! - `grid`: nine rows of nine squares, using the same characters as `line`. Whitespace and the
+//!   separators `|`, `+`, `-` and `=` are ignored, so lines with nothing else on them are skipped.
+//! - `sdk`: SadMan Sudoku's format, which is a grid without any separators.
+//! - `sdx`: SadMan Sudoku's extended format. Each row is nine space-separated squares, which are
+//!   either a given, a number the player filled in (prefixed with `u`), or the square's
+//!   candidates (its center pencil marks) run together. `0` is an empty square.
+//! - `json`: the same `BoardState` JSON used by the rest of the API, which keeps everything.
+//!
+//! In every format except `json`, lines starting with `#` are metadata and are skipped. `line`,
+//! `grid` and `sdk` only have room for numbers, so every number is written out as if it were a
+//! given. `sdx` drops corner pencil marks, and can't write a single candidate, since it would look
+//! like a given.
+
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use crate::board::{BoardSquare, BoardState};
+use crate::digit::{Digit, DigitBitFlags};
+
+const GRID_SEPARATORS: [char; 4] = ['|', '+', '-', '='];
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PuzzleFormat {
+    Line,
+    Grid,
+    Sdk,
+    Sdx,
+    Json,
+}
+
+/// Says what was wrong with a puzzle, and where. Lines and columns are counted from 1, and
+/// columns count characters rather than bytes.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(line: usize, column: usize, message: impl Into<String>) -> Self {
+        ParseError {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    /// An error for input that ended too early, positioned just past the last character.
+    fn at_end(input: &str, message: impl Into<String>) -> Self {
+        let (line, column) = match input.lines().enumerate().last() {
+            Some((line_idx, line)) => (line_idx + 1, line.chars().count() + 1),
+            None => (1, 1),
+        };
+        ParseError::new(line, column, message)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl Error for ParseError {}
+
+pub fn parse(format: PuzzleFormat, input: &str) -> Result<BoardState, ParseError> {
+    let squares = match format {
+        PuzzleFormat::Line => parse_line(input)?,
+        PuzzleFormat::Grid => parse_grid(input, &GRID_SEPARATORS)?,
+        PuzzleFormat::Sdk => parse_grid(input, &[])?,
+        PuzzleFormat::Sdx => parse_sdx(input)?,
+        PuzzleFormat::Json => return parse_json(input),
+    };
+    Ok(BoardState::from_squares(squares).expect("puzzle parsers always produce 81 squares"))
+}
+
+pub fn emit(format: PuzzleFormat, board: &BoardState) -> String {
+    let rows = board.squares().chunks(9);
+    match format {
+        PuzzleFormat::Line => board.squares().iter().map(number_char).collect(),
+        PuzzleFormat::Grid => {
+            let mut out = String::new();
+            for (row_idx, row) in rows.enumerate() {
+                if row_idx == 3 || row_idx == 6 {
+                    out.push_str("------+-------+------\n");
+                }
+                let boxes: Vec<String> = row
+                    .chunks(3)
+                    .map(|bx| {
+                        let chars: Vec<String> =
+                            bx.iter().map(|sq| number_char(sq).to_string()).collect();
+                        chars.join(" ")
+                    })
+                    .collect();
+                out.push_str(&boxes.join(" | "));
+                out.push('\n');
+            }
+            out
+        }
+        PuzzleFormat::Sdk => rows
+            .map(|row| {
+                let mut line: String = row.iter().map(number_char).collect();
+                line.push('\n');
+                line
+            })
+            .collect(),
+        PuzzleFormat::Sdx => rows
+            .map(|row| {
+                let squares: Vec<String> = row.iter().map(sdx_square).collect();
+                squares.join(" ") + "\n"
+            })
+            .collect(),
+        PuzzleFormat::Json => serde_json::to_string(board).expect("boards should always serialize"),
+    }
+}
+
+fn digit(c: char) -> Option<Digit> {
+    c.to_digit(10).and_then(|d| Digit::try_from(d as u8).ok())
+}
+
+fn given(digit: Digit) -> BoardSquare {
+    BoardSquare {
+        number: Some(digit),
+        locked: true,
+        ..Default::default()
+    }
+}
+
+/// Parses a square in the `line`, `grid` and `sdk` formats.
+fn square(c: char) -> Option<BoardSquare> {
+    match c {
+        '.' | '0' => Some(BoardSquare::default()),
+        _ => digit(c).map(given),
+    }
+}
+
+fn number_char(sq: &BoardSquare) -> char {
+    match sq.number {
+        Some(digit) => (b'0' + Into::<u8>::into(digit)) as char,
+        None => '.',
+    }
+}
+
+fn sdx_square(sq: &BoardSquare) -> String {
+    match sq.number {
+        Some(_) if sq.locked => number_char(sq).to_string(),
+        Some(_) => format!("u{}", number_char(sq)),
+        None if sq.centers.len() > 1 => sq
+            .centers
+            .iter()
+            .map(|digit| (b'0' + Into::<u8>::into(digit)) as char)
+            .collect(),
+        None => "0".to_owned(),
+    }
+}
+
+/// Lines that have something on them besides metadata, along with their line numbers.
+fn content_lines(input: &str) -> impl Iterator<Item = (usize, &str)> {
+    input
+        .lines()
+        .enumerate()
+        .map(|(line_idx, line)| (line_idx + 1, line))
+        .filter(|(_, line)| !line.starts_with('#'))
+}
+
+fn parse_line(input: &str) -> Result<Vec<BoardSquare>, ParseError> {
+    let mut squares = Vec::with_capacity(81);
+    let mut puzzle_line = None;
+    for (line_no, line) in content_lines(input) {
+        for (col_idx, c) in line.chars().enumerate() {
+            if c.is_whitespace() {
+                continue;
+            }
+            let err = |message: String| ParseError::new(line_no, col_idx + 1, message);
+            match puzzle_line {
+                None => puzzle_line = Some(line_no),
+                Some(puzzle_line) if puzzle_line != line_no => {
+                    return Err(err("expected the whole puzzle on one line".to_owned()));
+                }
+                Some(_) => {}
+            }
+            if squares.len() == 81 {
+                return Err(err("expected only 81 squares".to_owned()));
+            }
+            squares.push(square(c).ok_or_else(|| err(format!("unexpected {:?}", c)))?);
+        }
+    }
+    if squares.len() < 81 {
+        return Err(ParseError::at_end(
+            input,
+            format!("expected 81 squares, but found {}", squares.len()),
+        ));
+    }
+    Ok(squares)
+}
+
+fn parse_grid(input: &str, separators: &[char]) -> Result<Vec<BoardSquare>, ParseError> {
+    let mut squares = Vec::with_capacity(81);
+    let mut rows = 0;
+    for (line_no, line) in content_lines(input) {
+        let mut row_len = 0;
+        for (col_idx, c) in line.chars().enumerate() {
+            if c.is_whitespace() || separators.contains(&c) {
+                continue;
+            }
+            let err = |message: String| ParseError::new(line_no, col_idx + 1, message);
+            let sq = square(c).ok_or_else(|| err(format!("unexpected {:?}", c)))?;
+            if rows == 9 {
+                return Err(err("expected only 9 rows".to_owned()));
+            }
+            if row_len == 9 {
+                return Err(err("expected only 9 squares in this row".to_owned()));
+            }
+            squares.push(sq);
+            row_len += 1;
+        }
+        if row_len == 0 {
+            continue;
+        }
+        if row_len < 9 {
+            return Err(ParseError::new(
+                line_no,
+                line.chars().count() + 1,
+                format!("expected 9 squares in this row, but found {}", row_len),
+            ));
+        }
+        rows += 1;
+    }
+    if rows < 9 {
+        return Err(ParseError::at_end(
+            input,
+            format!("expected 9 rows, but found {}", rows),
+        ));
+    }
+    Ok(squares)
+}
+
+/// Splits a line on whitespace, returning each word along with the column it starts at.
+fn words(line: &str) -> Vec<(usize, String)> {
+    let mut words: Vec<(usize, String)> = Vec::new();
+    let mut in_word = false;
+    for (col_idx, c) in line.chars().enumerate() {
+        if c.is_whitespace() {
+            in_word = false;
+        } else if in_word {
+            words.last_mut().unwrap().1.push(c);
+        } else {
+            words.push((col_idx + 1, c.to_string()));
+            in_word = true;
+        }
+    }
+    words
+}
+
+fn parse_sdx(input: &str) -> Result<Vec<BoardSquare>, ParseError> {
+    let mut squares = Vec::with_capacity(81);
+    let mut rows = 0;
+    for (line_no, line) in content_lines(input) {
+        let words = words(line);
+        if words.is_empty() {
+            continue;
+        }
+        let (first_col, _) = words[0];
+        if rows == 9 {
+            return Err(ParseError::new(line_no, first_col, "expected only 9 rows"));
+        }
+        if words.len() != 9 {
+            let column = match words.get(9) {
+                Some((col, _)) => *col,
+                None => line.chars().count() + 1,
+            };
+            return Err(ParseError::new(
+                line_no,
+                column,
+                format!("expected 9 squares in this row, but found {}", words.len()),
+            ));
+        }
+        for (col, word) in words {
+            squares
+                .push(parse_sdx_square(&word).map_err(|(offset, message)| {
+                    ParseError::new(line_no, col + offset, message)
+                })?);
+        }
+        rows += 1;
+    }
+    if rows < 9 {
+        return Err(ParseError::at_end(
+            input,
+            format!("expected 9 rows, but found {}", rows),
+        ));
+    }
+    Ok(squares)
+}
+
+/// Parses a single sdx square. Errors come with the offset of the bad character within `word`.
+fn parse_sdx_square(word: &str) -> Result<BoardSquare, (usize, String)> {
+    let unexpected = |offset: usize, c: char| (offset, format!("unexpected {:?}", c));
+    if let Some(number) = word.strip_prefix('u') {
+        let mut chars = number.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => match digit(c) {
+                Some(digit) => Ok(BoardSquare {
+                    number: Some(digit),
+                    ..Default::default()
+                }),
+                None => Err(unexpected(1, c)),
+            },
+            (Some(_), Some(c)) => Err(unexpected(2, c)),
+            (None, _) => Err((1, "expected a number after 'u'".to_owned())),
+        };
+    }
+    if word == "0" {
+        return Ok(BoardSquare::default());
+    }
+    let mut digits = Vec::new();
+    for (offset, c) in word.chars().enumerate() {
+        digits.push(digit(c).ok_or_else(|| unexpected(offset, c))?);
+    }
+    if let [digit] = digits[..] {
+        return Ok(given(digit));
+    }
+    Ok(BoardSquare {
+        centers: DigitBitFlags::from(digits),
+        ..Default::default()
+    })
+}
+
+fn parse_json(input: &str) -> Result<BoardState, ParseError> {
+    let board: BoardState = serde_json::from_str(input).map_err(|err| {
+        // serde_json puts the position at the end of its message, but we already have a spot for
+        // that
+        let message = err.to_string();
+        let message = message.split(" at line ").next().unwrap_or_default();
+        ParseError::new(err.line(), err.column(), message)
+    })?;
+    board
+        .validate()
+        .map_err(|err| ParseError::at_end(input, err.to_string()))?;
+    Ok(board)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUZZLE: &str =
+        "53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79";
+
+    fn error_position(format: PuzzleFormat, input: &str) -> (usize, usize) {
+        let err = parse(format, input).unwrap_err();
+        (err.line, err.column)
+    }
+
+    #[test]
+    fn round_trip() {
+        let board = parse(PuzzleFormat::Line, PUZZLE).unwrap();
+        assert_eq!(board.squares()[0], given(Digit::D5));
+        assert_eq!(board.squares()[2], BoardSquare::default());
+        assert_eq!(emit(PuzzleFormat::Line, &board), PUZZLE);
+        for &format in [
+            PuzzleFormat::Grid,
+            PuzzleFormat::Sdk,
+            PuzzleFormat::Sdx,
+            PuzzleFormat::Json,
+        ]
+        .iter()
+        {
+            let emitted = emit(format, &board);
+            assert_eq!(parse(format, &emitted).unwrap(), board, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn grid() {
+        let grid = emit(
+            PuzzleFormat::Grid,
+            &parse(PuzzleFormat::Line, PUZZLE).unwrap(),
+        );
+        assert!(grid.starts_with("5 3 . | . 7 . | . . .\n"));
+        assert_eq!(grid.lines().nth(3), Some("------+-------+------"));
+
+        // other separators and spacing work too
+        let compact = "+===+===+===+\n|53.|.7.|...|\n|6..|195|...|\n|.98|...|.6.|\n\
+                       +---+---+---+\n|8..|.6.|..3|\n|4..|8.3|..1|\n|7..|.2.|..6|\n\
+                       +---+---+---+\n|.6.|...|28.|\n|...|419|..5|\n|...|.8.|.79|\n+===+===+===+\n";
+        assert_eq!(
+            parse(PuzzleFormat::Grid, compact).unwrap(),
+            parse(PuzzleFormat::Line, PUZZLE).unwrap()
+        );
+    }
+
+    #[test]
+    fn sdx_keeps_progress() {
+        let mut sdx = emit(
+            PuzzleFormat::Sdx,
+            &parse(PuzzleFormat::Line, PUZZLE).unwrap(),
+        );
+        // fill in the third square, and give the fourth some candidates
+        sdx.replace_range(4..7, "u4 12");
+        let board = parse(PuzzleFormat::Sdx, &sdx).unwrap();
+        assert_eq!(board.squares()[2].number, Some(Digit::D4));
+        assert!(!board.squares()[2].locked);
+        assert_eq!(
+            board.squares()[3].centers,
+            DigitBitFlags::from(vec![Digit::D1, Digit::D2])
+        );
+        assert!(emit(PuzzleFormat::Sdx, &board).starts_with("5 3 u4 12 7 0 0 0 0\n"));
+    }
+
+    #[test]
+    fn error_positions() {
+        // a bad character
+        let mut line = PUZZLE.to_owned();
+        line.replace_range(10..11, "x");
+        assert_eq!(error_position(PuzzleFormat::Line, &line), (1, 11));
+        // too short, or spread over multiple lines
+        assert_eq!(error_position(PuzzleFormat::Line, &PUZZLE[..80]), (1, 81));
+        assert_eq!(
+            error_position(
+                PuzzleFormat::Line,
+                &format!("{}\n{}", &PUZZLE[..9], &PUZZLE[9..])
+            ),
+            (2, 1)
+        );
+
+        let mut sdk = emit(
+            PuzzleFormat::Sdk,
+            &parse(PuzzleFormat::Line, PUZZLE).unwrap(),
+        );
+        assert!(parse(PuzzleFormat::Sdk, &format!("#A Somebody\n{}", sdk)).is_ok());
+        // a short row
+        sdk.replace_range(10..11, "");
+        assert_eq!(error_position(PuzzleFormat::Sdk, &sdk), (2, 9));
+        // a missing row
+        assert_eq!(
+            error_position(PuzzleFormat::Sdk, "53..7....\n6..195...\n"),
+            (2, 10)
+        );
+
+        let sdx = emit(
+            PuzzleFormat::Sdx,
+            &parse(PuzzleFormat::Line, PUZZLE).unwrap(),
+        );
+        assert_eq!(
+            error_position(PuzzleFormat::Sdx, &sdx.replacen("7", "u7x", 1)),
+            (1, 11)
+        );
+
+        assert_eq!(
+            error_position(PuzzleFormat::Json, "{\n  \"squares\": [,]\n}"),
+            (2, 15)
+        );
+        let err = parse(PuzzleFormat::Json, "{\"squares\": []}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("line 1, column 16: {}", err.message)
+        );
+    }
+}
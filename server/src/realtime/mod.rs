@@ -4,87 +4,222 @@ pub mod tasks;
 use futures::prelude::*;
 use futures::stream::{SplitSink, SplitStream};
 use log::{debug, error, warn};
+use serde::Deserialize;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use tokio::time;
 use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
 use warp::reject::Reject;
 use warp::ws::{Message, WebSocket};
 use warp::{Filter, Reply};
 
-use crate::cursors::SessionCursor;
+use crate::config::Config;
+use crate::cursors::ProfileUpdate;
 use crate::global_state::GlobalState;
+use crate::health::Health;
+use crate::metrics;
 use crate::realtime::protocol::{
-    serialize_response, write_to_socket, ResponseMessage, SocketWriteError,
+    serialize_response, write_to_socket, ResponseMessage, ShareLinks, SocketWriteError,
 };
 use crate::realtime::tasks::error::ApiTaskError;
-use crate::realtime::tasks::{CursorNotifyReceiver, DiffBroadcastReceiver, RequestReceiver};
-use crate::room::{ClientSyncId, RoomId, RoomState, Session};
-use crate::sql;
+use crate::realtime::tasks::{
+    partial_update, CursorNotifyReceiver, DiffBroadcastReceiver, RequestReceiver,
+    RoomNoticeReceiver,
+};
+use crate::room::{
+    Access, AccessKey, ClientSyncId, HostKey, ResumeToken, Revision, RoomId, RoomState, Session,
+    SessionId, SessionRole, SuspendedSession, SyncState,
+};
+use crate::storage::Storage;
 
 #[derive(Debug)]
-struct InternalErrorReject;
+pub struct InternalErrorReject;
 
 impl Reject for InternalErrorReject {}
 
+#[derive(Deserialize)]
+struct ResumeQuery {
+    /// A `ResumeToken` from an earlier connection's `Init` message.
+    resume: Option<String>,
+    /// The last revision the client received before it disconnected.
+    revision: Option<Revision>,
+    /// A `HostKey` from an earlier `Init` or `HostChanged` message. Lets the host take the role
+    /// back when its session can't be resumed, e.g. after the server restarts.
+    host: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RealtimeQuery {
+    /// The `AccessKey` for the room. Not needed when creating a new room.
+    key: Option<String>,
+}
+
 pub fn get_filter(
+    config: Arc<Config>,
+    health: Arc<Health>,
     global_state: Arc<GlobalState>,
-    db_pool: Arc<sql::Pool>,
+    storage: Arc<dyn Storage>,
 ) -> BoxedFilter<(impl Reply,)> {
-    warp::path!("api" / "v1" / "realtime" / ..)
+    // Once the server starts shutting down, clients should go find another server instead of
+    // joining rooms that are about to be written back.
+    let shutting_down = warp::any()
+        .and(warp::any().map(move || health.clone()))
+        .and_then(|health: Arc<Health>| async move {
+            if health.is_shutting_down() {
+                Ok(warp::reply::with_status(
+                    "shutting down",
+                    StatusCode::SERVICE_UNAVAILABLE,
+                ))
+            } else {
+                Err(warp::reject::not_found())
+            }
+        });
+    let upgrade = warp::any()
         .and(
-            // TODO: room ids should be unguessable keys
             warp::path::param::<RoomId>()
                 .map(Some)
                 .or(warp::any().map(|| None))
                 .unify(),
         )
+        .and(warp::query::<RealtimeQuery>())
         .and(warp::any().map(move || global_state.clone()))
-        .and(warp::any().map(move || db_pool.clone()))
+        .and(warp::any().map(move || storage.clone()))
+        .and(warp::any().map({
+            let config = config.clone();
+            move || config.clone()
+        }))
         .and_then(
-            |room_id, global_state: Arc<GlobalState>, db_pool: Arc<_>| async move {
-                Result::<Arc<Mutex<RoomState>>, warp::reject::Rejection>::Ok(match room_id {
-                    Some(room_id) => global_state
-                        .get_room(&db_pool, &room_id)
-                        .await
-                        .map_err(|_| warp::reject::custom(InternalErrorReject))?
-                        .ok_or_else(warp::reject::not_found)?,
+            |room_id: Option<RoomId>,
+             query: RealtimeQuery,
+             global_state: Arc<GlobalState>,
+             storage: Arc<_>,
+             config: Arc<Config>| async move {
+                match room_id {
+                    Some(room_id) => find_room(&global_state, &storage, room_id, query.key).await,
                     None => {
                         let room_id = RoomId::random();
-                        let room_state = Arc::new(Mutex::new(RoomState::new(room_id)));
+                        let mut rs = RoomState::new(room_id, &config.limits);
+                        // nobody else knows the room id yet, so this client's session is the
+                        // first one, and it gets to be the host
+                        rs.host = Some(rs.next_session_id());
+                        let room_state = Arc::new(Mutex::new(rs));
                         global_state.insert_room(room_id, room_state.clone()).await;
-                        room_state
+                        Ok((room_state, Access::Editor))
                     }
-                })
+                }
             },
         )
+        .untuple_one()
         .and(warp::path::end())
         .and(warp::ws())
-        .map(|room_state: Arc<Mutex<RoomState>>, ws: warp::ws::Ws| {
-            // board states aren't very big and we already have our own board diff queue, so keep
-            // these queue sizes small
-            ws.max_send_queue(1 * 1024 * 1024)
-                .max_message_size(512 * 1024)
-                .max_frame_size(512 * 1024)
-                .on_upgrade(move |web_socket| handle_realtime_api(web_socket, room_state))
-        })
+        // the client can pick its name and color up front, instead of sending an UpdateProfile
+        .and(warp::query::<ProfileUpdate>())
+        .and(warp::query::<ResumeQuery>())
+        .and(warp::any().map(move || config.clone()))
+        .map(
+            |room_state: Arc<Mutex<RoomState>>,
+             access: Access,
+             ws: warp::ws::Ws,
+             profile: ProfileUpdate,
+             resume: ResumeQuery,
+             config: Arc<Config>| {
+                // board states aren't very big and we already have our own board diff queue, so
+                // keep these queue sizes small
+                ws.max_send_queue(1 * 1024 * 1024)
+                    .max_message_size(config.limits.max_message_size)
+                    .max_frame_size(config.limits.max_frame_size)
+                    .on_upgrade(move |web_socket| {
+                        handle_realtime_api(web_socket, config, room_state, access, profile, resume)
+                    })
+            },
+        );
+    warp::path!("api" / "v1" / "realtime" / ..)
+        .and(shutting_down.or(upgrade))
         .boxed()
 }
 
-async fn handle_realtime_api(ws: WebSocket, room_state: Arc<Mutex<RoomState>>) {
+/// Looks up an existing room, and checks `key` against its access keys. A missing or wrong key
+/// gets the same rejection as a missing room, so that room ids can't be probed for.
+pub async fn find_room(
+    global_state: &Arc<GlobalState>,
+    storage: &Arc<dyn Storage>,
+    room_id: RoomId,
+    key: Option<String>,
+) -> Result<(Arc<Mutex<RoomState>>, Access), warp::reject::Rejection> {
+    let room_state = global_state
+        .get_room(storage, &room_id)
+        .await
+        .map_err(|_| warp::reject::custom(InternalErrorReject))?
+        .ok_or_else(warp::reject::not_found)?;
+    let key: Option<AccessKey> = match key {
+        Some(key) => Some(key.parse().map_err(|_| warp::reject::not_found())?),
+        None => None,
+    };
+    let access = room_state
+        .lock()
+        .await
+        .check_access(key.as_ref())
+        .ok_or_else(warp::reject::not_found)?;
+    Ok((room_state, access))
+}
+
+async fn handle_realtime_api(
+    ws: WebSocket,
+    config: Arc<Config>,
+    room_state: Arc<Mutex<RoomState>>,
+    access: Access,
+    profile: ProfileUpdate,
+    mut resume: ResumeQuery,
+) {
     let (ws_tx, ws_rx) = ws.split();
     let ws_tx = Arc::new(Mutex::new(ws_tx));
     let ws_rx = Arc::new(Mutex::new(ws_rx));
 
-    // create a new session and prepare it to be shared across multiple tasks
-    let Session {
-        session_id,
-        diff_rx,
-        cursor: SessionCursor {
-            tx: cursor_tx,
-            rx: cursor_rx,
+    // Resume the client's old session if we can, otherwise create a new one. The first messages
+    // are built while holding the lock, so that no diffs can sneak in between them and the
+    // session's diff_rx.
+    let role = SessionRole::from(access);
+    let (room_id, joined) = {
+        let mut rs = room_state.lock().await;
+        let resumed = resume
+            .resume
+            .take()
+            .and_then(|token| token.parse::<ResumeToken>().ok())
+            .and_then(|token| rs.resume_session(token, role));
+        let joined = match resumed {
+            Some((session, mut sync)) => {
+                debug!("resuming session {}", session.session_id);
+                let messages =
+                    catch_up_messages(&rs, session.session_id, &mut sync, resume.revision);
+                Ok((session, sync, messages))
+            }
+            None => rs.new_session(role, profile).map(|session| {
+                let host_key = resume.host.and_then(|key| key.parse::<HostKey>().ok());
+                if let Some(host_key) = host_key {
+                    rs.claim_host(session.session_id, host_key);
+                }
+                let init_msg = init_message(&rs, &session, access, &config);
+                (session, SyncState::default(), vec![init_msg])
+            }),
+        };
+        (rs.room_id, joined)
+    };
+    // prepare the session to be shared across multiple tasks
+    let (
+        Session {
+            session_id,
+            role,
+            resume_token,
+            diff_rx,
+            notice_rx,
+            cursor_tx,
+            cursor_rx,
         },
-    } = match room_state.lock().await.new_session() {
-        Ok(session) => session,
+        sync,
+        messages,
+    ) = match joined {
+        Ok(joined) => joined,
         Err(err) => {
             let response_result = serialize_response(ResponseMessage::from(err));
             if let Ok(response) = response_result {
@@ -94,81 +229,221 @@ async fn handle_realtime_api(ws: WebSocket, room_state: Arc<Mutex<RoomState>>) {
             return;
         }
     };
-    let last_received_sync_id: Arc<Mutex<Option<ClientSyncId>>> = Arc::new(Mutex::new(None));
-    let last_sent_sync_id: Arc<Mutex<Option<ClientSyncId>>> = Arc::new(Mutex::new(None));
+    let last_received_sync_id: Arc<Mutex<Option<ClientSyncId>>> =
+        Arc::new(Mutex::new(sync.last_received_sync_id));
+    let last_sent_sync_id: Arc<Mutex<Option<ClientSyncId>>> =
+        Arc::new(Mutex::new(sync.last_sent_sync_id));
+    let cursor_tx = Arc::new(cursor_tx);
 
     debug!("sending init message to client");
     let write_result = async {
-        let init_msg = {
-            // release the lock as soon as the message is constructed, before we send it
-            let rs = room_state.lock().await;
-            ResponseMessage::Init {
-                room_id: rs.room_id.to_string(),
-                // It's expensive, but clone this so we don't have to keep holding onto the lock.
-                // Maybe this could be an Arc<Cow<>>.
-                board_state: rs.board.clone(),
-            }
-        };
-        write_to_socket(&ws_tx, serialize_response(init_msg)?).await
+        for msg in messages {
+            write_to_socket(&ws_tx, serialize_response(msg)?).await?;
+        }
+        Result::<(), SocketWriteError>::Ok(())
     }
     .await;
 
-    if write_result.is_err() {
-        debug!("failed to send init message, so closing socket instead");
-        close_websocket(ws_tx, ws_rx).await;
-        return;
-    }
+    let mut kicked = false;
+    if write_result.is_ok() {
+        let request_receiver = RequestReceiver {
+            room_state: room_state.clone(),
+            ws_tx: ws_tx.clone(),
+            ws_rx: ws_rx.clone(),
+            session_id,
+            role,
+            last_received_sync_id: last_received_sync_id.clone(),
+            cursor_tx: cursor_tx.clone(),
+            config: config.clone(),
+        }
+        .run();
 
-    let request_receiver = RequestReceiver {
-        room_state: room_state.clone(),
-        ws_tx: ws_tx.clone(),
-        ws_rx: ws_rx.clone(),
-        session_id,
-        last_received_sync_id: last_received_sync_id.clone(),
-        cursor_tx,
+        let diff_broadcast_receiver = DiffBroadcastReceiver {
+            room_state: room_state.clone(),
+            ws_tx: ws_tx.clone(),
+            diff_rx,
+            session_id,
+            last_received_sync_id: last_received_sync_id.clone(),
+            last_sent_sync_id: last_sent_sync_id.clone(),
+        }
+        .run();
+
+        let cursor_notify_receiver = CursorNotifyReceiver {
+            ws_tx: ws_tx.clone(),
+            cursor_rx,
+        }
+        .run();
+
+        let room_notice_receiver = RoomNoticeReceiver {
+            ws_tx: ws_tx.clone(),
+            notice_rx,
+            session_id,
+            room_id,
+            access,
+            public_url: config.public_url.clone(),
+            reconnect_spread: config.shutdown.reconnect_spread(),
+        }
+        .run();
+
+        let result = tokio::select! {
+            r = request_receiver => r,
+            r = diff_broadcast_receiver => r,
+            r = cursor_notify_receiver => r,
+            r = room_notice_receiver => {
+                kicked = r.is_ok();
+                r
+            }
+        };
+
+        if let Err(err) = &result {
+            metrics::WEBSOCKET_ERRORS
+                .with_label_values(&[err.kind()])
+                .inc();
+        }
+        match result {
+            Err(err) => match err {
+                // use a nested match because Result doesn't implement Display
+                ApiTaskError::CursorReceive(_) => {
+                    error!("{}", err);
+                }
+                ApiTaskError::SocketWrite(SocketWriteError::Serialization(_)) => {
+                    error!("{}", err);
+                }
+                ApiTaskError::SocketWrite(SocketWriteError::Warp(_)) => {
+                    // this is probably just a network issue, so use a lower severity
+                    warn!("{}", err);
+                }
+            },
+            Ok(_) => {}
+        }
+    } else {
+        debug!("failed to send init message, so closing socket instead");
     }
-    .run();
 
-    let diff_broadcast_receiver = DiffBroadcastReceiver {
-        room_state: room_state.clone(),
-        ws_tx: ws_tx.clone(),
-        diff_rx,
+    let suspended = SuspendedSession {
         session_id,
-        last_received_sync_id: last_received_sync_id.clone(),
-        last_sent_sync_id: last_sent_sync_id.clone(),
+        role,
+        cursor_tx: Arc::try_unwrap(cursor_tx)
+            .ok()
+            .expect("there should be one ref to cursor_tx once our tasks are finished"),
+        sync: SyncState {
+            last_received_sync_id: *last_received_sync_id.lock().await,
+            last_sent_sync_id: *last_sent_sync_id.lock().await,
+        },
+    };
+    if kicked {
+        room_state
+            .lock()
+            .await
+            .end_session(suspended.session_id, suspended.role);
+    } else {
+        leave_room(room_state, &config, resume_token, suspended).await;
     }
-    .run();
+    close_websocket(ws_tx, ws_rx).await;
+}
 
-    let cursor_notify_receiver = CursorNotifyReceiver {
-        ws_tx: ws_tx.clone(),
-        cursor_rx,
+fn init_message(
+    rs: &RoomState,
+    session: &Session,
+    access: Access,
+    config: &Config,
+) -> ResponseMessage {
+    ResponseMessage::Init {
+        room_id: rs.room_id.to_string(),
+        session_id: session.session_id,
+        resume_token: session.resume_token.to_string(),
+        // It's expensive, but clone this so we don't have to keep holding onto the lock. Maybe
+        // this could be an Arc<Cow<>>.
+        board_state: rs.board.clone(),
+        revision: rs.revision(),
+        conflicts: rs.board.conflicts(),
+        completion: rs.completion,
+        access,
+        // only editors get to see the keys
+        share_links: match access {
+            Access::Editor => Some(ShareLinks::new(
+                &config.public_url,
+                rs.room_id,
+                &rs.access_keys,
+            )),
+            Access::Viewer => None,
+        },
+        locked: rs.locked,
+        host: rs.host,
+        host_key: if rs.is_host(session.session_id) {
+            Some(rs.host_key.to_string())
+        } else {
+            None
+        },
     }
-    .run();
-
-    let result = tokio::select! {
-        r = request_receiver => r,
-        r = diff_broadcast_receiver => r,
-        r = cursor_notify_receiver => r,
-    };
+}
 
-    match result {
-        Err(err) => match err {
-            // use a nested match because Result doesn't implement Display
-            ApiTaskError::CursorReceive(_) => {
-                error!("{}", err);
-            }
-            ApiTaskError::SocketWrite(SocketWriteError::Serialization(_)) => {
-                error!("{}", err);
-            }
-            ApiTaskError::SocketWrite(SocketWriteError::Warp(_)) => {
-                // this is probably just a network issue, so use a lower severity
-                warn!("{}", err);
+/// Builds the messages that bring a resumed session's client up to date: every diff group it
+/// missed since `revision` (exactly as they would have been sent if it had stayed connected), or
+/// a `FullUpdate` if those aren't available anymore.
+fn catch_up_messages(
+    rs: &RoomState,
+    session_id: SessionId,
+    sync: &mut SyncState,
+    revision: Option<Revision>,
+) -> Vec<ResponseMessage> {
+    let mut messages = vec![ResponseMessage::Resumed {
+        session_id,
+        undo_status: rs.undo_status(session_id),
+    }];
+    match revision.and_then(|revision| rs.history_since(revision)) {
+        Some(missed) => {
+            for bc in missed {
+                messages.push(partial_update(bc, session_id, &mut sync.last_sent_sync_id));
+                if let Some(completion) = bc.completion {
+                    messages.push(completion.into());
+                }
             }
-        },
-        Ok(_) => {}
+        }
+        None => {
+            sync.last_sent_sync_id = sync.last_received_sync_id;
+            messages.push(ResponseMessage::FullUpdate {
+                sync_id: sync.last_received_sync_id,
+                board_state: rs.board.clone(),
+                revision: rs.revision(),
+                conflicts: rs.board.conflicts(),
+                completion: rs.completion,
+                undo_status: rs.undo_status(session_id),
+            });
+        }
     }
+    messages
+}
 
-    close_websocket(ws_tx, ws_rx).await;
+/// Suspends a disconnected session so that the client can resume it, and ends it for good once
+/// the grace period runs out. If resumption is disabled, the session ends immediately.
+async fn leave_room(
+    room_state: Arc<Mutex<RoomState>>,
+    config: &Config,
+    resume_token: ResumeToken,
+    suspended: SuspendedSession,
+) {
+    let grace_period = match config.limits.resume_grace_period() {
+        Some(grace_period) => grace_period,
+        None => {
+            room_state
+                .lock()
+                .await
+                .end_session(suspended.session_id, suspended.role);
+            return;
+        }
+    };
+    room_state
+        .lock()
+        .await
+        .suspend_session(resume_token, suspended);
+    tokio::spawn(async move {
+        time::delay_for(grace_period).await;
+        room_state
+            .lock()
+            .await
+            .expire_suspended_sessions(grace_period);
+    });
 }
 
 /// Helper for closing the websocket by unwrapping arcs and reuniting the sink and stream halves of
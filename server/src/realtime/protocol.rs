@@ -8,9 +8,12 @@ use tokio::sync::Mutex;
 use warp::ws::{Message, WebSocket};
 
 use crate::board::{BoardDiff, BoardState};
-use crate::cursors::{CursorSelection, CursorsMapView};
+use crate::cursors::{CursorSelection, CursorsMapView, ProfileUpdate, RosterEntry};
 use crate::error::SudokuError;
-use crate::room::ClientSyncId;
+use crate::room::{
+    Access, AccessKey, AccessKeys, ClientSyncId, Completion, Revision, RoomId, SessionId,
+    UndoStatus,
+};
 
 #[derive(Serialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
@@ -18,43 +21,211 @@ pub enum ResponseMessage {
     #[serde(rename_all = "camelCase")]
     Init {
         room_id: String,
+        /// This session's id, which identifies it in `Roster` and cursor maps.
+        session_id: SessionId,
+        /// Pass this back as the `resume` query parameter (along with the last `revision` the
+        /// client saw) when reconnecting, to pick this session back up.
+        resume_token: String,
         board_state: BoardState,
+        revision: Revision,
+        /// Indexes of squares with a number that conflicts with another square in the same row,
+        /// column, or box. See `BoardState::conflicts`.
+        conflicts: Vec<u8>,
+        /// Set if the room's puzzle has already been solved.
+        completion: Option<Completion>,
+        /// What this session is allowed to do, based on the key it joined with.
+        access: Access,
+        /// Links for inviting other people to the room. Only sent to editors.
+        share_links: Option<ShareLinks>,
+        /// Set if the board can't be changed right now.
+        locked: bool,
+        /// The session that's currently the room's host, if any.
+        host: Option<SessionId>,
+        /// Only sent to the host. Pass this back as the `host` query parameter when reconnecting
+        /// (without resuming) to become the host again.
+        host_key: Option<String>,
+    },
+    /// Sent instead of `Init` when a client resumes its session. This is followed by a
+    /// `PartialUpdate` for every diff group the client missed, or by a `FullUpdate` if those are
+    /// no longer available.
+    #[serde(rename_all = "camelCase")]
+    Resumed {
+        session_id: SessionId,
+        undo_status: UndoStatus,
     },
     #[serde(rename_all = "camelCase")]
     PartialUpdate {
         sync_id: Option<ClientSyncId>,
+        /// The diffs as they were applied, which may differ from what the client sent if they had
+        /// to be transformed against concurrent diffs.
         diffs: Vec<BoardDiff>,
+        revision: Revision,
+        conflicts: Vec<u8>,
+        /// Only set for the session's own diffs (including its undos and redos).
+        undo_status: Option<UndoStatus>,
     },
     /// Sent when the client falls too far behind (RecvError::Lagged)
     #[serde(rename_all = "camelCase")]
     FullUpdate {
         sync_id: Option<ClientSyncId>,
         board_state: BoardState,
+        revision: Revision,
+        conflicts: Vec<u8>,
+        completion: Option<Completion>,
+        undo_status: UndoStatus,
+    },
+    /// Sent to every session (right after the `PartialUpdate` containing the last digit) when the
+    /// puzzle is finished.
+    #[serde(rename_all = "camelCase")]
+    Solved {
+        solve_time_ms: u64,
+        solved_by: SessionId,
     },
+    /// Cursor selections for other players, keyed by session id. Players without a selection
+    /// are left out.
     #[serde(rename_all = "camelCase")]
     UpdateCursor { map: CursorsMapView },
+    /// Sent whenever a player joins, leaves, or changes their profile. Includes every player in
+    /// the room (but not spectators).
+    #[serde(rename_all = "camelCase")]
+    Roster { sessions: Vec<RosterEntry> },
+    /// Sent whenever the room is locked or unlocked. While it's locked, nobody can change the
+    /// board.
+    #[serde(rename_all = "camelCase")]
+    Locked { locked: bool },
+    /// Sent whenever the host role moves to another session, or the host leaves. `host_key` is
+    /// only sent to the new host.
+    #[serde(rename_all = "camelCase")]
+    HostChanged {
+        host: Option<SessionId>,
+        host_key: Option<String>,
+    },
+    /// Sent right before the server disconnects a session that was kicked out of the room. The
+    /// session can't be resumed, so the client shouldn't automatically reconnect.
+    Kicked,
+    /// Sent to every session when the host replaces the room's keys. The old key stops working,
+    /// so reconnect with `key` from now on. Like in `Init`, only editors get `share_links`.
+    #[serde(rename_all = "camelCase")]
+    KeysRotated {
+        key: String,
+        share_links: Option<ShareLinks>,
+    },
+    /// Sent to every session when the server starts shutting down. Once it has no unconfirmed
+    /// changes left, the client should disconnect, wait for `reconnect_after_ms`, and then
+    /// reconnect with its resume token. Sessions don't outlive the server they're on, so the
+    /// client usually gets a new session (and an `Init`) instead of resuming.
+    #[serde(rename_all = "camelCase")]
+    ServerShuttingDown { reconnect_after_ms: u64 },
+    /// Sent when a request fails. If the request was tagged with a `sync_id` (a diff group, undo,
+    /// or one of the host's diff groups), it's included, so that the client can roll the group
+    /// back. The server never applied it, so it won't be confirmed by a `PartialUpdate`.
     #[serde(rename_all = "camelCase")]
-    Error { message: SudokuError },
+    Error {
+        message: SudokuError,
+        sync_id: Option<ClientSyncId>,
+    },
+}
+
+#[derive(Serialize)]
+pub struct ShareLinks {
+    pub editor: String,
+    pub viewer: String,
+}
+
+impl ShareLinks {
+    /// Builds links to the web client at `public_url` that join `room_id` with each of its keys.
+    /// `public_url` may already have a query string, which is kept.
+    pub fn new(public_url: &str, room_id: RoomId, keys: &AccessKeys) -> Self {
+        let separator = if public_url.contains('?') { '&' } else { '?' };
+        let link = |key: AccessKey| {
+            let query = serde_urlencoded::to_string(&[
+                ("room", room_id.to_string()),
+                ("key", key.to_string()),
+            ])
+            .expect("room ids and keys can always be encoded");
+            format!("{}{}{}", public_url, separator, query)
+        };
+        ShareLinks {
+            editor: link(keys.editor),
+            viewer: link(keys.viewer),
+        }
+    }
+}
+
+impl From<Completion> for ResponseMessage {
+    fn from(completion: Completion) -> Self {
+        ResponseMessage::Solved {
+            solve_time_ms: completion.solve_time_ms,
+            solved_by: completion.solved_by,
+        }
+    }
 }
 
 impl From<SudokuError> for ResponseMessage {
     fn from(err: SudokuError) -> Self {
-        ResponseMessage::Error { message: err }
+        ResponseMessage::Error {
+            message: err,
+            sync_id: None,
+        }
     }
 }
 
 #[derive(Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum RequestMessage {
+    /// Replaces the whole board, which sends everyone a `FullUpdate`. By default, only the room's
+    /// host can do this (see `PermissionsConfig`).
     #[serde(rename_all = "camelCase")]
     SetBoardState { board_state: BoardState },
     #[serde(rename_all = "camelCase")]
     ApplyDiffs {
         sync_id: ClientSyncId,
+        /// The last revision the client received before generating these diffs. If omitted, the
+        /// diffs are applied without being transformed.
+        #[serde(default)]
+        base_revision: Option<Revision>,
         diffs: Vec<BoardDiff>,
     },
+    /// Reverts this session's most recent change. Changes that other sessions have made to the
+    /// same squares since are kept. The result is broadcast like any other diffs, tagged with
+    /// `sync_id`.
+    #[serde(rename_all = "camelCase")]
+    Undo { sync_id: ClientSyncId },
+    /// Reapplies the change this session most recently undid.
+    #[serde(rename_all = "camelCase")]
+    Redo { sync_id: ClientSyncId },
     #[serde(rename_all = "camelCase")]
     UpdateCursor { selection: CursorSelection },
+    /// Changes this session's display name, color, or input mode.
+    #[serde(rename_all = "camelCase")]
+    UpdateProfile {
+        #[serde(flatten)]
+        profile: ProfileUpdate,
+    },
+    // The rest of these can only be sent by the host.
+    /// Makes another player the host.
+    #[serde(rename_all = "camelCase")]
+    TransferHost { session_id: SessionId },
+    /// Locks or unlocks squares for everybody. Locked squares can't be changed. Like an undo,
+    /// this is broadcast as a diff group tagged with `sync_id`.
+    #[serde(rename_all = "camelCase")]
+    SetSquaresLocked {
+        sync_id: ClientSyncId,
+        squares: Vec<u8>,
+        locked: bool,
+    },
+    /// Clears every unlocked square's corner and center pencil marks. Like an undo, this is
+    /// broadcast as a diff group tagged with `sync_id`.
+    #[serde(rename_all = "camelCase")]
+    ResetPencilMarks { sync_id: ClientSyncId },
+    /// Disconnects another session, which can't be resumed.
+    #[serde(rename_all = "camelCase")]
+    Kick { session_id: SessionId },
+    /// Replaces the room's keys, so that the old share links stop working. Kicked sessions can
+    /// join again with the old ones until this is done.
+    RotateKeys,
+    /// Disconnects everybody, and stops anybody from joining the room again.
+    CloseRoom,
 }
 
 #[derive(Debug)]
@@ -108,3 +279,52 @@ pub async fn write_to_socket(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_links() {
+        let room_id = RoomId::random();
+        let keys = AccessKeys::random();
+        let links = ShareLinks::new("https://example.com/sudoku/", room_id, &keys);
+        assert_eq!(
+            links.editor,
+            format!(
+                "https://example.com/sudoku/?room={}&key={}",
+                room_id, keys.editor
+            )
+        );
+        // a query string that's already there is kept
+        let links = ShareLinks::new("https://example.com/?theme=dark", room_id, &keys);
+        assert_eq!(
+            links.viewer,
+            format!(
+                "https://example.com/?theme=dark&room={}&key={}",
+                room_id, keys.viewer
+            )
+        );
+    }
+
+    #[test]
+    fn error_sync_id() {
+        let response = ResponseMessage::Error {
+            message: SudokuError::RoomLocked,
+            sync_id: Some(3),
+        };
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({
+                "type": "error",
+                "message": SudokuError::RoomLocked.to_string(),
+                "syncId": 3,
+            })
+        );
+        let response: ResponseMessage = SudokuError::NotHost.into();
+        assert_eq!(
+            serde_json::to_value(&response).unwrap()["syncId"],
+            serde_json::Value::Null
+        );
+    }
+}
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use warp::ws::{Message, WebSocket};
 
-use crate::cursors::SessionCursorReceiver;
+use crate::cursors::{RosterEntry, SessionCursorReceiver};
 use crate::realtime::protocol::{serialize_response, write_to_socket, ResponseMessage};
 use crate::realtime::tasks::error::ApiTaskError;
 
@@ -14,8 +14,19 @@ pub struct CursorNotifyReceiver {
 
 impl CursorNotifyReceiver {
     pub async fn run(mut self) -> Result<(), ApiTaskError> {
+        // the roster only changes when somebody joins, leaves, or updates their profile, so only
+        // send it when it's different from the last one we sent
+        let mut last_roster: Option<Vec<RosterEntry>> = None;
         loop {
             let cursor_map_view = self.cursor_rx.recv().await?;
+            let roster = cursor_map_view.roster();
+            if last_roster.as_ref() != Some(&roster) {
+                let response = ResponseMessage::Roster {
+                    sessions: roster.clone(),
+                };
+                write_to_socket(&self.ws_tx, serialize_response(response)?).await?;
+                last_roster = Some(roster);
+            }
             let response = ResponseMessage::UpdateCursor {
                 map: cursor_map_view,
             };
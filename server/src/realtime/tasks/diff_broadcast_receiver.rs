@@ -5,14 +5,15 @@ use tokio::sync::{broadcast, Mutex};
 use warp::ws::{Message, WebSocket};
 
 use crate::error::SudokuError;
+use crate::metrics;
 use crate::realtime::protocol::{serialize_response, write_to_socket, ResponseMessage};
 use crate::realtime::tasks::error::ApiTaskError;
-use crate::room::{BoardDiffBroadcast, ClientSyncId, RoomState, SessionId};
+use crate::room::{BoardBroadcast, BoardDiffBroadcast, ClientSyncId, RoomState, SessionId};
 
 pub struct DiffBroadcastReceiver {
     pub room_state: Arc<Mutex<RoomState>>,
     pub ws_tx: Arc<Mutex<SplitSink<WebSocket, Message>>>,
-    pub diff_rx: broadcast::Receiver<Arc<BoardDiffBroadcast>>,
+    pub diff_rx: broadcast::Receiver<BoardBroadcast>,
     pub session_id: SessionId,
     pub last_received_sync_id: Arc<Mutex<Option<ClientSyncId>>>,
     pub last_sent_sync_id: Arc<Mutex<Option<ClientSyncId>>>,
@@ -25,38 +26,31 @@ impl DiffBroadcastReceiver {
             if let Err(broadcast::RecvError::Closed) = diff_broadcast {
                 return Result::<(), ApiTaskError>::Ok(());
             }
+            let completion = match &diff_broadcast {
+                Ok(BoardBroadcast::Diffs(bc)) => bc.completion,
+                _ => None,
+            };
             let response = self.handle_diff_broadcast(diff_broadcast).await;
             write_to_socket(&self.ws_tx, serialize_response(response)?).await?;
+            if let Some(completion) = completion {
+                write_to_socket(&self.ws_tx, serialize_response(completion.into())?).await?;
+            }
         }
     }
 
     async fn handle_diff_broadcast(
         &mut self,
-        broadcast: Result<Arc<BoardDiffBroadcast>, broadcast::RecvError>,
+        broadcast: Result<BoardBroadcast, broadcast::RecvError>,
     ) -> ResponseMessage {
         match broadcast {
-            Ok(bc) => {
+            Ok(BoardBroadcast::Diffs(bc)) => {
                 let mut sync_id_guard = self.last_sent_sync_id.lock().await;
-                if bc.sender_id == self.session_id {
-                    *sync_id_guard = Some(bc.sync_id);
-                }
-                ResponseMessage::PartialUpdate {
-                    sync_id: *sync_id_guard,
-                    diffs: bc.board_diffs.clone(),
-                }
+                partial_update(&bc, self.session_id, &mut sync_id_guard)
             }
+            Ok(BoardBroadcast::Replaced) => self.full_update().await,
             Err(broadcast::RecvError::Lagged(_)) => {
-                let (mut last_sent_sync_id_guard, last_received_sync_id_guard, room_state_guard) = tokio::join!(
-                    self.last_sent_sync_id.lock(),
-                    self.last_received_sync_id.lock(),
-                    self.room_state.lock()
-                );
-                *last_sent_sync_id_guard = *last_received_sync_id_guard;
-                self.diff_rx = room_state_guard.new_sessionless_receiver();
-                ResponseMessage::FullUpdate {
-                    sync_id: *last_received_sync_id_guard,
-                    board_state: room_state_guard.board.clone(),
-                }
+                metrics::LAGGED_FULL_UPDATES.inc();
+                self.full_update().await
             }
             Err(broadcast::RecvError::Closed) => {
                 error!("broadcast channel is closed; this shouldn't happen");
@@ -64,4 +58,45 @@ impl DiffBroadcastReceiver {
             }
         }
     }
+
+    /// Builds a `FullUpdate` from the room's current board. Anything still queued in `diff_rx` is
+    /// already part of that board, so the receiver is replaced to skip over it.
+    async fn full_update(&mut self) -> ResponseMessage {
+        let (mut last_sent_sync_id_guard, last_received_sync_id_guard, room_state_guard) = tokio::join!(
+            self.last_sent_sync_id.lock(),
+            self.last_received_sync_id.lock(),
+            self.room_state.lock()
+        );
+        *last_sent_sync_id_guard = *last_received_sync_id_guard;
+        self.diff_rx = room_state_guard.new_sessionless_receiver();
+        ResponseMessage::FullUpdate {
+            sync_id: *last_received_sync_id_guard,
+            board_state: room_state_guard.board.clone(),
+            revision: room_state_guard.revision(),
+            conflicts: room_state_guard.board.conflicts(),
+            completion: room_state_guard.completion,
+            undo_status: room_state_guard.undo_status(self.session_id),
+        }
+    }
+}
+
+/// Builds the `PartialUpdate` for a diff broadcast, updating `last_sent_sync_id` if the broadcast
+/// contains the session's own diffs.
+pub fn partial_update(
+    bc: &BoardDiffBroadcast,
+    session_id: SessionId,
+    last_sent_sync_id: &mut Option<ClientSyncId>,
+) -> ResponseMessage {
+    let mut undo_status = None;
+    if bc.sender_id == session_id {
+        *last_sent_sync_id = Some(bc.sync_id);
+        undo_status = Some(bc.sender_undo_status);
+    }
+    ResponseMessage::PartialUpdate {
+        sync_id: *last_sent_sync_id,
+        diffs: bc.board_diffs.clone(),
+        revision: bc.revision,
+        conflicts: bc.conflicts.clone(),
+        undo_status,
+    }
 }
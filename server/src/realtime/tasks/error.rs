@@ -10,6 +10,17 @@ pub enum ApiTaskError {
     SocketWrite(SocketWriteError),
 }
 
+impl ApiTaskError {
+    /// A short name for the kind of error, for use as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::CursorReceive(_) => "cursor_receive",
+            Self::SocketWrite(SocketWriteError::Serialization(_)) => "serialization",
+            Self::SocketWrite(SocketWriteError::Warp(_)) => "socket_write",
+        }
+    }
+}
+
 impl fmt::Display for ApiTaskError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
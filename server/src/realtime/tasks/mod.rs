@@ -2,7 +2,9 @@ mod cursor_notify_receiver;
 mod diff_broadcast_receiver;
 pub mod error;
 mod request_receiver;
+mod room_notice_receiver;
 
 pub use crate::realtime::tasks::cursor_notify_receiver::CursorNotifyReceiver;
-pub use crate::realtime::tasks::diff_broadcast_receiver::DiffBroadcastReceiver;
+pub use crate::realtime::tasks::diff_broadcast_receiver::{partial_update, DiffBroadcastReceiver};
 pub use crate::realtime::tasks::request_receiver::RequestReceiver;
+pub use crate::realtime::tasks::room_notice_receiver::RoomNoticeReceiver;
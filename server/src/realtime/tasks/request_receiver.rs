@@ -5,21 +5,27 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use warp::ws::{Message, WebSocket};
 
+use crate::board::BoardState;
+use crate::config::Config;
 use crate::cursors::SessionCursorSender;
 use crate::error::SudokuError;
 use crate::realtime::protocol::{
     serialize_response, write_to_socket, RequestMessage, ResponseMessage,
 };
 use crate::realtime::tasks::error::ApiTaskError;
-use crate::room::{ClientSyncId, RoomState, SessionId};
+use crate::room::{ClientSyncId, RoomState, SessionId, SessionRole, UndoKind};
+use crate::solver;
 
 pub struct RequestReceiver {
     pub room_state: Arc<Mutex<RoomState>>,
     pub ws_tx: Arc<Mutex<SplitSink<WebSocket, Message>>>,
     pub ws_rx: Arc<Mutex<SplitStream<WebSocket>>>,
     pub session_id: SessionId,
+    pub role: SessionRole,
     pub last_received_sync_id: Arc<Mutex<Option<ClientSyncId>>>,
-    pub cursor_tx: SessionCursorSender,
+    /// Always set for players, and never set for spectators.
+    pub cursor_tx: Arc<Option<SessionCursorSender>>,
+    pub config: Arc<Config>,
 }
 
 impl RequestReceiver {
@@ -58,33 +64,143 @@ impl RequestReceiver {
         }
     }
 
+    async fn undo(&self, sync_id: ClientSyncId, kind: UndoKind) -> Option<ResponseMessage> {
+        let mut rs = self.room_state.lock().await;
+        *self.last_received_sync_id.lock().await = Some(sync_id);
+        if let Err(err) = rs.undo(self.session_id, sync_id, kind) {
+            Some(ResponseMessage::Error {
+                message: err,
+                sync_id: Some(sync_id),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the room's board, checking that it can be solved without holding the room's lock.
+    async fn set_board(&self, board_state: BoardState) -> Result<(), SudokuError> {
+        let permission = self.config.permissions.set_board;
+        // check before solving, so that sessions that can't set the board can't tie up a thread
+        self.room_state
+            .lock()
+            .await
+            .check_can_set_board(self.session_id, permission)?;
+        // solving can take a while, so don't hold the room's lock for it
+        let board_state =
+            solver::spawn_check_solvable(board_state, self.config.limits.max_solver_nodes).await?;
+        let mut rs = self.room_state.lock().await;
+        // the host could have changed in the meantime
+        rs.check_can_set_board(self.session_id, permission)?;
+        rs.set_board(self.session_id, board_state)
+    }
+
     async fn handle_request_message(&self, req: RequestMessage) -> Option<ResponseMessage> {
         match req {
+            // spectators can't do anything except watch
+            _ if self.role == SessionRole::Spectator => Some(SudokuError::Spectating.into()),
             RequestMessage::SetBoardState { board_state } => {
-                self.room_state.lock().await.board = board_state.clone();
-                None
+                if let Err(err) = self.set_board(board_state).await {
+                    Some(err.into())
+                } else {
+                    None
+                }
             }
-            RequestMessage::ApplyDiffs { sync_id, diffs } => {
+            RequestMessage::ApplyDiffs {
+                sync_id,
+                base_revision,
+                diffs,
+            } => {
                 let mut rs = self.room_state.lock().await;
                 let mut last_received_sync_id_guard = self.last_received_sync_id.lock().await;
                 *last_received_sync_id_guard = Some(sync_id);
-                if let Err(err) = rs.apply_diffs(self.session_id, sync_id, diffs) {
-                    Some(ResponseMessage::Error { message: err })
+                if let Err(err) = rs.apply_diffs(self.session_id, sync_id, base_revision, diffs) {
+                    Some(ResponseMessage::Error {
+                        message: err,
+                        sync_id: Some(sync_id),
+                    })
                 } else {
                     None
                 }
             }
+            RequestMessage::Undo { sync_id } => self.undo(sync_id, UndoKind::Undo).await,
+            RequestMessage::Redo { sync_id } => self.undo(sync_id, UndoKind::Redo).await,
             RequestMessage::UpdateCursor { selection } => {
-                if let Err(err) = self.cursor_tx.update(selection) {
+                let cursor_tx = self.cursor_tx.as_ref().as_ref()?;
+                if let Err(err) = cursor_tx.update(selection) {
                     // this should never happen
                     error!("{}", err);
-                    Some(ResponseMessage::Error {
-                        message: SudokuError::Internal(Box::new(err)),
-                    })
+                    Some(SudokuError::Internal(Box::new(err)).into())
+                } else {
+                    None
+                }
+            }
+            RequestMessage::UpdateProfile { profile } => {
+                let cursor_tx = self.cursor_tx.as_ref().as_ref()?;
+                if let Err(err) = cursor_tx.update_profile(profile) {
+                    // this should never happen
+                    error!("{}", err);
+                    Some(SudokuError::Internal(Box::new(err)).into())
                 } else {
                     None
                 }
             }
+            RequestMessage::TransferHost { session_id } => {
+                self.host_action(|rs, host| rs.transfer_host(host, session_id))
+                    .await
+            }
+            RequestMessage::SetSquaresLocked {
+                sync_id,
+                squares,
+                locked,
+            } => {
+                self.host_group(sync_id, |rs, host| {
+                    rs.set_squares_locked(host, sync_id, squares, locked)
+                })
+                .await
+            }
+            RequestMessage::ResetPencilMarks { sync_id } => {
+                self.host_group(sync_id, |rs, host| rs.reset_pencil_marks(host, sync_id))
+                    .await
+            }
+            RequestMessage::Kick { session_id } => {
+                self.host_action(|rs, host| rs.host_kick(host, session_id))
+                    .await
+            }
+            RequestMessage::RotateKeys => self.host_action(|rs, host| rs.rotate_keys(host)).await,
+            RequestMessage::CloseRoom => self.host_action(|rs, host| rs.close(host)).await,
+        }
+    }
+
+    /// Runs one of the `RoomState` methods that only the host can use, which tell everybody about
+    /// their results themselves.
+    async fn host_action(
+        &self,
+        action: impl FnOnce(&mut RoomState, SessionId) -> Result<(), SudokuError>,
+    ) -> Option<ResponseMessage> {
+        let mut rs = self.room_state.lock().await;
+        if let Err(err) = action(&mut rs, self.session_id) {
+            Some(err.into())
+        } else {
+            None
+        }
+    }
+
+    /// Like `host_action`, but for actions that are applied as a diff group tagged with
+    /// `sync_id`, the same as an undo.
+    async fn host_group(
+        &self,
+        sync_id: ClientSyncId,
+        action: impl FnOnce(&mut RoomState, SessionId) -> Result<(), SudokuError>,
+    ) -> Option<ResponseMessage> {
+        let mut rs = self.room_state.lock().await;
+        *self.last_received_sync_id.lock().await = Some(sync_id);
+        if let Err(err) = action(&mut rs, self.session_id) {
+            Some(ResponseMessage::Error {
+                message: err,
+                sync_id: Some(sync_id),
+            })
+        } else {
+            None
         }
     }
 }
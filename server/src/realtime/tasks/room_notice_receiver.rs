@@ -0,0 +1,71 @@
+use futures::prelude::*;
+use futures::stream::SplitSink;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, Mutex};
+use warp::ws::{Message, WebSocket};
+
+use crate::realtime::protocol::{serialize_response, write_to_socket, ResponseMessage, ShareLinks};
+use crate::realtime::tasks::error::ApiTaskError;
+use crate::room::{Access, RoomId, RoomNotice, SessionId};
+
+pub struct RoomNoticeReceiver {
+    pub ws_tx: Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    pub notice_rx: broadcast::Receiver<RoomNotice>,
+    pub session_id: SessionId,
+    pub room_id: RoomId,
+    /// What the session's key allows it to do, which decides which new key it's sent when the
+    /// keys are rotated.
+    pub access: Access,
+    pub public_url: String,
+    /// The upper bound on the reconnect delay suggested to clients when the server shuts down.
+    pub reconnect_spread: Duration,
+}
+
+impl RoomNoticeReceiver {
+    /// Forwards notices to the client. This only returns (successfully) once the session has been
+    /// kicked, after telling the client why.
+    pub async fn run(mut self) -> Result<(), ApiTaskError> {
+        loop {
+            let response = match self.notice_rx.recv().await {
+                Ok(RoomNotice::Locked(locked)) => ResponseMessage::Locked { locked },
+                Ok(RoomNotice::Kick(kick)) if kick.matches(self.session_id) => {
+                    write_to_socket(&self.ws_tx, serialize_response(ResponseMessage::Kicked)?)
+                        .await?;
+                    return Ok(());
+                }
+                Ok(RoomNotice::Kick(_)) => continue,
+                Ok(RoomNotice::HostChanged { host, host_key }) => ResponseMessage::HostChanged {
+                    host,
+                    host_key: if host == Some(self.session_id) {
+                        Some(host_key.to_string())
+                    } else {
+                        None
+                    },
+                },
+                Ok(RoomNotice::KeysRotated(keys)) => match self.access {
+                    Access::Editor => ResponseMessage::KeysRotated {
+                        key: keys.editor.to_string(),
+                        share_links: Some(ShareLinks::new(&self.public_url, self.room_id, &keys)),
+                    },
+                    Access::Viewer => ResponseMessage::KeysRotated {
+                        key: keys.viewer.to_string(),
+                        share_links: None,
+                    },
+                },
+                Ok(RoomNotice::ShuttingDown) => {
+                    let spread_ms = self.reconnect_spread.as_millis() as u64;
+                    ResponseMessage::ServerShuttingDown {
+                        reconnect_after_ms: rand::thread_rng().gen_range(0, spread_ms + 1),
+                    }
+                }
+                // notices are rare enough that this shouldn't happen
+                Err(broadcast::RecvError::Lagged(_)) => continue,
+                // the room is gone, so there won't be any more notices
+                Err(broadcast::RecvError::Closed) => future::pending().await,
+            };
+            write_to_socket(&self.ws_tx, serialize_response(response)?).await?;
+        }
+    }
+}
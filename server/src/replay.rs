@@ -0,0 +1,367 @@
+//! Lets a room's history be reviewed after the fact, by replaying its event log.
+//!
+//! - `GET /api/v1/replay/{room_id}?key=...` returns every event in the room's log.
+//! - Adding `step=n` returns the board after the first `n` events instead, and `at=t` (a unix
+//!   timestamp in milliseconds) returns the board as it was at that time.
+//! - Opening a websocket to the same url streams the events with their original timing, sped up
+//!   by `speed=x`, except that idle stretches are cut down to a few seconds. If `step` or `at` is
+//!   given, the stream starts from that point.
+//!
+//! Both the editor and the view-only key work, since none of this can change the room.
+
+use futures::prelude::*;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use warp::filters::BoxedFilter;
+use warp::ws::{Message, WebSocket};
+use warp::{Filter, Reply};
+
+use crate::board::BoardState;
+use crate::global_state::GlobalState;
+use crate::realtime::{find_room, InternalErrorReject};
+use crate::room::{replay, RoomEvent, RoomEventKind, RoomId, SessionId};
+use crate::storage::{self, Storage};
+
+const DEFAULT_SPEED: f64 = 1.0;
+const MIN_SPEED: f64 = 0.1;
+const MAX_SPEED: f64 = 1000.0;
+/// The longest a replay stream waits between events, however long the room sat idle.
+const MAX_STREAM_GAP: Duration = Duration::from_secs(3);
+
+#[derive(Deserialize)]
+struct ReplayQuery {
+    key: Option<String>,
+    /// Start from the board after this many events.
+    step: Option<usize>,
+    /// Start from the board as it was at this unix timestamp (in milliseconds). Ignored if `step`
+    /// is given.
+    at: Option<i64>,
+    /// How much faster than real time to stream events over a websocket.
+    speed: Option<f64>,
+}
+
+impl ReplayQuery {
+    /// The number of events to apply to get to the requested point in the log, or `None` if the
+    /// client didn't ask for one.
+    fn step(&self, events: &[RoomEvent]) -> Option<usize> {
+        match (self.step, self.at) {
+            (Some(step), _) => Some(step.min(events.len())),
+            (None, Some(at)) => Some(
+                events
+                    .iter()
+                    .take_while(|event| event.applied_at.timestamp_millis() <= at)
+                    .count(),
+            ),
+            (None, None) => None,
+        }
+    }
+
+    fn speed(&self) -> f64 {
+        match self.speed {
+            None => DEFAULT_SPEED,
+            Some(speed) if speed.is_nan() || speed < MIN_SPEED => MIN_SPEED,
+            Some(speed) if speed > MAX_SPEED => MAX_SPEED,
+            Some(speed) => speed,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplayEvent<'a> {
+    /// The number of events that have been applied once this one has.
+    step: usize,
+    /// Unix timestamp in milliseconds.
+    applied_at: i64,
+    session_id: SessionId,
+    event: &'a RoomEventKind,
+}
+
+impl<'a> ReplayEvent<'a> {
+    fn new(idx: usize, event: &'a RoomEvent) -> Self {
+        ReplayEvent {
+            step: idx + 1,
+            applied_at: event.applied_at.timestamp_millis(),
+            session_id: event.session_id,
+            event: &event.kind,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplayLog<'a> {
+    events: Vec<ReplayEvent<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BoardAtStep {
+    step: usize,
+    step_count: usize,
+    /// When the last applied event happened, or `None` at step 0.
+    applied_at: Option<i64>,
+    board_state: BoardState,
+}
+
+impl BoardAtStep {
+    fn new(events: &[RoomEvent], step: usize) -> Result<Self, warp::reject::Rejection> {
+        let board_state = replay(&events[..step]).map_err(|err| {
+            error!("Failed to replay room events: {}", err);
+            warp::reject::custom(InternalErrorReject)
+        })?;
+        Ok(BoardAtStep {
+            step,
+            step_count: events.len(),
+            applied_at: step
+                .checked_sub(1)
+                .map(|idx| events[idx].applied_at.timestamp_millis()),
+            board_state,
+        })
+    }
+}
+
+/// Messages sent over a replay websocket. The client gets a `Start`, then an `Event` for every
+/// event after the starting point, then `Finished`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ReplayMessage<'a> {
+    #[serde(rename_all = "camelCase")]
+    Start {
+        #[serde(flatten)]
+        board: BoardAtStep,
+        speed: f64,
+    },
+    Event(ReplayEvent<'a>),
+    Finished,
+}
+
+pub fn get_filter(
+    global_state: Arc<GlobalState>,
+    storage: Arc<dyn Storage>,
+) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("api" / "v1" / "replay" / RoomId)
+        .and(warp::query::<ReplayQuery>())
+        .and(warp::any().map(move || global_state.clone()))
+        .and(warp::any().map(move || storage.clone()))
+        .and_then(
+            |room_id: RoomId,
+             query: ReplayQuery,
+             global_state: Arc<GlobalState>,
+             storage: Arc<dyn Storage>| async move {
+                let (room_state, _access) =
+                    find_room(&global_state, &storage, room_id, query.key.clone()).await?;
+                let events = storage::read_room_events(&*storage, room_id, room_state)
+                    .await
+                    .map_err(|err| {
+                        error!("Failed to read events for room {}: {}", room_id, err);
+                        warp::reject::custom(InternalErrorReject)
+                    })?;
+                Result::<_, warp::reject::Rejection>::Ok((query, events))
+            },
+        )
+        .untuple_one()
+        .and(warp::ws().map(Some).or(warp::any().map(|| None)).unify())
+        .and_then(
+            |query: ReplayQuery, events: Vec<RoomEvent>, ws: Option<warp::ws::Ws>| async move {
+                let step = query.step(&events);
+                let response = match ws {
+                    Some(ws) => {
+                        let board = BoardAtStep::new(&events, step.unwrap_or(0))?;
+                        let speed = query.speed();
+                        ws.on_upgrade(move |web_socket| {
+                            stream_events(web_socket, events, board, speed)
+                        })
+                        .into_response()
+                    }
+                    None => match step {
+                        Some(step) => {
+                            warp::reply::json(&BoardAtStep::new(&events, step)?).into_response()
+                        }
+                        None => warp::reply::json(&ReplayLog {
+                            events: events
+                                .iter()
+                                .enumerate()
+                                .map(|(idx, event)| ReplayEvent::new(idx, event))
+                                .collect(),
+                        })
+                        .into_response(),
+                    },
+                };
+                Result::<_, warp::reject::Rejection>::Ok(response)
+            },
+        )
+        .boxed()
+}
+
+async fn stream_events(ws: WebSocket, events: Vec<RoomEvent>, start: BoardAtStep, speed: f64) {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let start_step = start.step;
+    let send_events = async {
+        send(
+            &mut ws_tx,
+            &ReplayMessage::Start {
+                board: start,
+                speed,
+            },
+        )
+        .await?;
+        let mut previous = start_step.checked_sub(1).map(|idx| events[idx].applied_at);
+        for (idx, event) in events.iter().enumerate().skip(start_step) {
+            if let Some(previous) = previous {
+                let gap = (event.applied_at - previous)
+                    .to_std()
+                    .unwrap_or_else(|_| Duration::from_secs(0));
+                time::delay_for(gap.div_f64(speed).min(MAX_STREAM_GAP)).await;
+            }
+            previous = Some(event.applied_at);
+            send(
+                &mut ws_tx,
+                &ReplayMessage::Event(ReplayEvent::new(idx, event)),
+            )
+            .await?;
+        }
+        send(&mut ws_tx, &ReplayMessage::Finished).await
+    };
+    // the client doesn't send us anything, but we need to read from the socket to notice that
+    // it's been closed
+    let client_closed = async {
+        while let Some(Ok(msg)) = ws_rx.next().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+    };
+    tokio::select! {
+        result = send_events => {
+            if let Err(err) = result {
+                debug!("failed to stream replay: {}", err);
+            }
+        }
+        _ = client_closed => {}
+    }
+    if let Ok(ws) = ws_tx.reunite(ws_rx) {
+        let _possible_error = ws.close().await;
+    }
+}
+
+async fn send<S>(ws_tx: &mut S, msg: &ReplayMessage<'_>) -> Result<(), warp::Error>
+where
+    S: Sink<Message, Error = warp::Error> + Unpin,
+{
+    // serializing these can't fail, since they don't contain any maps with non-string keys
+    let text = serde_json::to_string(msg).expect("replay messages should always serialize");
+    ws_tx.send(Message::text(text)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn board_at_step() {
+        use crate::board::{BoardDiff, BoardDiffOperation};
+        use crate::config::LimitsConfig;
+        use crate::digit::Digit;
+        use crate::room::RoomState;
+        use crate::storage::MemoryStorage;
+        use tokio::sync::Mutex;
+
+        let storage = Arc::new(MemoryStorage::default());
+        let global_state = Arc::new(GlobalState::default());
+        let room_id = RoomId::random();
+        let mut rs = RoomState::new(room_id, &LimitsConfig::default());
+        for (sync_id, idx) in [1, 2].iter().enumerate() {
+            rs.apply_diffs(
+                1,
+                sync_id as u64,
+                None,
+                vec![BoardDiff {
+                    squares: vec![*idx],
+                    operation: BoardDiffOperation::SetNumber {
+                        digit: Some(Digit::D5),
+                    },
+                }],
+            )
+            .unwrap();
+        }
+        let key = rs.access_keys.viewer;
+        global_state
+            .insert_room(room_id, Arc::new(Mutex::new(rs)))
+            .await;
+        let filter = get_filter(global_state, storage);
+
+        let response = warp::test::request()
+            .path(&format!("/api/v1/replay/{}?key={}&step=1", room_id, key))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["step"], 1);
+        assert_eq!(body["stepCount"], 2);
+        assert_eq!(body["boardState"]["squares"][1]["number"], 5);
+        assert_eq!(
+            body["boardState"]["squares"][2]["number"],
+            serde_json::Value::Null
+        );
+
+        // the key is required
+        let response = warp::test::request()
+            .path(&format!("/api/v1/replay/{}?step=1", room_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[test]
+    fn step() {
+        let events: Vec<_> = (0..3)
+            .map(|seq| RoomEvent {
+                seq,
+                applied_at: Utc.timestamp_millis(1000 * (seq as i64 + 1)),
+                session_id: 1,
+                sync_id: None,
+                revision: seq,
+                kind: RoomEventKind::ApplyDiffs { diffs: vec![] },
+            })
+            .collect();
+        let query = |step, at| ReplayQuery {
+            key: None,
+            step,
+            at,
+            speed: None,
+        };
+        assert_eq!(query(None, None).step(&events), None);
+        assert_eq!(query(Some(2), None).step(&events), Some(2));
+        assert_eq!(query(Some(10), None).step(&events), Some(3));
+        assert_eq!(query(None, Some(999)).step(&events), Some(0));
+        assert_eq!(query(None, Some(2500)).step(&events), Some(2));
+        // step wins over at
+        assert_eq!(query(Some(1), Some(2500)).step(&events), Some(1));
+
+        let board = BoardAtStep::new(&events, 2).unwrap();
+        assert_eq!(board.applied_at, Some(2000));
+        assert_eq!(board.step_count, 3);
+        assert_eq!(BoardAtStep::new(&events, 0).unwrap().applied_at, None);
+    }
+
+    #[test]
+    fn speed() {
+        let query = |speed| ReplayQuery {
+            key: None,
+            step: None,
+            at: None,
+            speed,
+        };
+        assert_eq!(query(None).speed(), DEFAULT_SPEED);
+        assert_eq!(query(Some(4.0)).speed(), 4.0);
+        assert_eq!(query(Some(0.0)).speed(), MIN_SPEED);
+        assert_eq!(query(Some(f64::NAN)).speed(), MIN_SPEED);
+        assert_eq!(query(Some(f64::INFINITY)).speed(), MAX_SPEED);
+    }
+}
@@ -0,0 +1,157 @@
+//! An append-only log of everything that's changed a room's board.
+//!
+//! Every change is recorded as a [RoomEvent] when it's applied, and written to storage along
+//! with the rest of the room. Replaying a prefix of the log rebuilds the board as it was at that
+//! point, which is useful for reviewing how a puzzle was solved, or for undoing griefing.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::board::{BoardDiff, BoardState};
+use crate::error::SudokuError;
+use crate::room::{ClientSyncId, Revision, SessionId};
+
+/// The position of an event in its room's log, starting from zero.
+pub type EventSeq = u64;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RoomEvent {
+    pub seq: EventSeq,
+    pub applied_at: DateTime<Utc>,
+    /// The session that caused this event. This is zero for events the server generated itself.
+    pub session_id: SessionId,
+    pub sync_id: Option<ClientSyncId>,
+    /// The room's revision after this event was applied.
+    pub revision: Revision,
+    pub kind: RoomEventKind,
+}
+
+/// What happened. This is stored as JSON, so it's easy to query by hand.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum RoomEventKind {
+    /// A group of diffs, as they were actually applied (after being transformed).
+    #[serde(rename_all = "camelCase")]
+    ApplyDiffs { diffs: Vec<BoardDiff> },
+    /// The whole board was replaced. This is also logged as the first event for rooms that
+    /// existed before the log did, so that replaying them starts from the right board.
+    #[serde(rename_all = "camelCase")]
+    SetBoard { board_state: BoardState },
+}
+
+/// Rebuilds a board by applying `events` in order to an empty board. To get the board at some
+/// earlier point, pass a prefix of the log, e.g. `events.iter().take(step)`.
+pub fn replay<'a>(
+    events: impl IntoIterator<Item = &'a RoomEvent>,
+) -> Result<BoardState, SudokuError> {
+    let mut board = BoardState::default();
+    for event in events {
+        match &event.kind {
+            RoomEventKind::ApplyDiffs { diffs } => {
+                for diff in diffs {
+                    board.apply(diff)?;
+                }
+            }
+            RoomEventKind::SetBoard { board_state } => board = board_state.clone(),
+        }
+    }
+    Ok(board)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::BoardDiffOperation;
+    use crate::config::LimitsConfig;
+    use crate::digit::Digit;
+    use crate::room::{RoomId, RoomState};
+
+    fn set_number(idx: u8, digit: Digit) -> BoardDiff {
+        BoardDiff {
+            squares: vec![idx],
+            operation: BoardDiffOperation::SetNumber { digit: Some(digit) },
+        }
+    }
+
+    #[test]
+    fn replay_prefix() {
+        let mut given = BoardState::default();
+        given.apply(&set_number(0, Digit::D1)).unwrap();
+        let start = Utc::now();
+        let event = |seq, kind| RoomEvent {
+            seq,
+            applied_at: start + chrono::Duration::seconds(seq as i64),
+            session_id: 1,
+            sync_id: None,
+            revision: seq,
+            kind,
+        };
+        let events = vec![
+            event(
+                0,
+                RoomEventKind::SetBoard {
+                    board_state: given.clone(),
+                },
+            ),
+            event(
+                1,
+                RoomEventKind::ApplyDiffs {
+                    diffs: vec![set_number(1, Digit::D2), set_number(2, Digit::D3)],
+                },
+            ),
+            event(
+                2,
+                RoomEventKind::ApplyDiffs {
+                    diffs: vec![set_number(1, Digit::D4)],
+                },
+            ),
+        ];
+
+        let board = replay(&events).unwrap();
+        assert_eq!(board.squares()[0].number, Some(Digit::D1));
+        assert_eq!(board.squares()[1].number, Some(Digit::D4));
+        assert_eq!(board.squares()[2].number, Some(Digit::D3));
+
+        assert_eq!(replay(events.iter().take(1)).unwrap(), given);
+        assert_eq!(replay(&events[..0]).unwrap(), BoardState::default());
+        // the board as of a point in time
+        let cutoff = start + chrono::Duration::milliseconds(1500);
+        let board = replay(events.iter().take_while(|e| e.applied_at <= cutoff)).unwrap();
+        assert_eq!(board.squares()[1].number, Some(Digit::D2));
+    }
+
+    #[test]
+    fn kind_json() {
+        let kind = RoomEventKind::ApplyDiffs {
+            diffs: vec![set_number(1, Digit::D2)],
+        };
+        let json = serde_json::to_string(&kind).unwrap();
+        assert!(json.starts_with(r#"{"type":"applyDiffs","diffs":"#));
+        assert_eq!(serde_json::from_str::<RoomEventKind>(&json).unwrap(), kind);
+    }
+
+    #[test]
+    fn room_logs_events() {
+        let mut rs = RoomState::new(RoomId::random(), &LimitsConfig::default());
+        rs.set_board(1, BoardState::default()).unwrap();
+        rs.apply_diffs(1, 7, None, vec![set_number(1, Digit::D2)])
+            .unwrap();
+        rs.apply_diffs(2, 3, None, vec![set_number(2, Digit::D3)])
+            .unwrap();
+
+        let events = rs.take_pending_events();
+        assert_eq!(
+            events.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(events[1].sync_id, Some(7));
+        assert_eq!(events[2].session_id, 2);
+        assert_eq!(events[2].revision, rs.revision());
+        assert_eq!(replay(&events).unwrap(), rs.board);
+        assert!(rs.take_pending_events().is_empty());
+
+        // numbering carries on after the pending events are taken
+        rs.apply_diffs(1, 8, None, vec![]).unwrap();
+        assert_eq!(rs.take_pending_events()[0].seq, 3);
+    }
+}
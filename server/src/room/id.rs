@@ -23,13 +23,7 @@ impl fmt::Display for RoomId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // use a prefix ("r") to allow us to detect possible future changes to this format
         f.write_char('r')?;
-        let len: u128 = ROOM_ID_CHARS.len() as u128;
-        let mut rest = self.0;
-        while rest > 0 {
-            f.write_char(ROOM_ID_CHARS[(rest % len) as usize])?;
-            rest /= len;
-        }
-        Ok(())
+        write_encoded(f, self.0)
     }
 }
 
@@ -45,25 +39,33 @@ impl FromStr for RoomId {
                 return Err(err_fn());
             }
         }
-        let mut coefficient: Option<u128> = Some(1);
-        let mut result: u128 = 0;
-        for ch in iter {
-            match ROOM_ID_CHARS.binary_search(&ch) {
-                Ok(idx) => {
-                    if let Some(coeff) = coefficient {
-                        result += (idx as u128).checked_mul(coeff).ok_or_else(err_fn)?;
-                        coefficient = coeff.checked_mul(ROOM_ID_CHARS.len() as u128);
-                    } else {
-                        return Err(err_fn());
-                    }
-                }
-                Err(_) => {
-                    return Err(err_fn());
-                }
-            }
-        }
-        Ok(RoomId(result))
+        parse_encoded(iter).map(RoomId).ok_or_else(err_fn)
+    }
+}
+
+/// Writes `value` using `ROOM_ID_CHARS`, least significant digit first. This is shared with
+/// `AccessKey`.
+pub(super) fn write_encoded(f: &mut fmt::Formatter<'_>, value: u128) -> fmt::Result {
+    let len: u128 = ROOM_ID_CHARS.len() as u128;
+    let mut rest = value;
+    while rest > 0 {
+        f.write_char(ROOM_ID_CHARS[(rest % len) as usize])?;
+        rest /= len;
+    }
+    Ok(())
+}
+
+/// The inverse of `write_encoded`. Returns `None` on an invalid character or on overflow.
+pub(super) fn parse_encoded(chars: impl Iterator<Item = char>) -> Option<u128> {
+    let mut coefficient: Option<u128> = Some(1);
+    let mut result: u128 = 0;
+    for ch in chars {
+        let idx = ROOM_ID_CHARS.binary_search(&ch).ok()?;
+        let coeff = coefficient?;
+        result = result.checked_add((idx as u128).checked_mul(coeff)?)?;
+        coefficient = coeff.checked_mul(ROOM_ID_CHARS.len() as u128);
     }
+    Some(result)
 }
 
 impl From<RoomId> for u128 {
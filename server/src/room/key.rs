@@ -0,0 +1,209 @@
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+use crate::room::id::{parse_encoded, write_encoded};
+
+/// A secret that grants access to a room. Unlike a `RoomId`, these are never shown to anybody who
+/// doesn't already have access to the room.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct AccessKey(u128);
+
+impl AccessKey {
+    pub fn random() -> AccessKey {
+        // rand::random uses thread_rng, which is a CSPRNG
+        AccessKey(rand::random())
+    }
+}
+
+impl fmt::Display for AccessKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // use a different prefix than RoomId, so the two can't be mixed up
+        f.write_char('k')?;
+        write_encoded(f, self.0)
+    }
+}
+
+impl FromStr for AccessKey {
+    type Err = InvalidKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_prefixed(s, 'k').map(AccessKey)
+    }
+}
+
+impl From<u128> for AccessKey {
+    fn from(val: u128) -> AccessKey {
+        AccessKey(val)
+    }
+}
+
+impl From<AccessKey> for u128 {
+    fn from(val: AccessKey) -> u128 {
+        val.0
+    }
+}
+
+/// A secret that lets a client pick its session back up after reconnecting, for a short time
+/// after it disconnects. Only the session it was issued to ever sees it.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ResumeToken(u128);
+
+impl ResumeToken {
+    pub fn random() -> ResumeToken {
+        ResumeToken(rand::random())
+    }
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('s')?;
+        write_encoded(f, self.0)
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = InvalidKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_prefixed(s, 's').map(ResumeToken)
+    }
+}
+
+/// A secret that makes whoever holds it the room's host. It's only ever shown to the current host,
+/// and is replaced whenever the host role is handed to somebody else. Unlike a session, it's
+/// persisted with the room, so the host can reclaim the role after a restart.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct HostKey(u128);
+
+impl HostKey {
+    pub fn random() -> HostKey {
+        HostKey(rand::random())
+    }
+}
+
+impl fmt::Display for HostKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('h')?;
+        write_encoded(f, self.0)
+    }
+}
+
+impl FromStr for HostKey {
+    type Err = InvalidKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_prefixed(s, 'h').map(HostKey)
+    }
+}
+
+impl From<u128> for HostKey {
+    fn from(val: u128) -> HostKey {
+        HostKey(val)
+    }
+}
+
+impl From<HostKey> for u128 {
+    fn from(val: HostKey) -> u128 {
+        val.0
+    }
+}
+
+fn parse_prefixed(s: &str, prefix: char) -> Result<u128, InvalidKeyError> {
+    let mut iter = s.chars();
+    if iter.next() != Some(prefix) {
+        return Err(InvalidKeyError);
+    }
+    parse_encoded(iter).ok_or(InvalidKeyError)
+}
+
+// Don't include the invalid key in the error, since it might be a typo'd version of a real one.
+#[derive(Debug, Eq, PartialEq)]
+pub struct InvalidKeyError;
+
+impl fmt::Display for InvalidKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("not a valid key")
+    }
+}
+
+impl Error for InvalidKeyError {}
+
+/// What a session is allowed to do in a room, based on the key it joined with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Access {
+    /// Can change the board, and can see the room's keys so that they can be shared.
+    Editor,
+    /// Can only watch.
+    Viewer,
+}
+
+/// The secret keys for a room. Each room has one key per level of `Access`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AccessKeys {
+    pub editor: AccessKey,
+    pub viewer: AccessKey,
+    /// Set for rooms from before access keys existed, whose links don't have a key. These can
+    /// be joined as an editor without a key, until somebody joins with one of the keys.
+    pub keyless: bool,
+}
+
+impl AccessKeys {
+    pub fn random() -> AccessKeys {
+        AccessKeys {
+            editor: AccessKey::random(),
+            viewer: AccessKey::random(),
+            keyless: false,
+        }
+    }
+
+    /// Returns the access granted by `key`, or `None` if it isn't one of this room's keys.
+    pub fn check(&self, key: &AccessKey) -> Option<Access> {
+        if *key == self.editor {
+            Some(Access::Editor)
+        } else if *key == self.viewer {
+            Some(Access::Viewer)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversion_back_and_forth() {
+        for _ in 0..1000 {
+            let key = AccessKey::random();
+            assert_eq!(key.to_string().parse::<AccessKey>(), Ok(key));
+        }
+    }
+
+    #[test]
+    fn room_ids_are_not_keys() {
+        let room_id = crate::room::RoomId::random().to_string();
+        assert_eq!(room_id.parse::<AccessKey>(), Err(InvalidKeyError));
+        assert_eq!("".parse::<AccessKey>(), Err(InvalidKeyError));
+        let token = ResumeToken::random();
+        assert_eq!(token.to_string().parse::<ResumeToken>(), Ok(token));
+        assert_eq!(token.to_string().parse::<AccessKey>(), Err(InvalidKeyError));
+        let host_key = HostKey::random();
+        assert_eq!(host_key.to_string().parse::<HostKey>(), Ok(host_key));
+        assert_eq!(
+            host_key.to_string().parse::<AccessKey>(),
+            Err(InvalidKeyError)
+        );
+    }
+
+    #[test]
+    fn check() {
+        let keys = AccessKeys::random();
+        assert_eq!(keys.check(&keys.editor), Some(Access::Editor));
+        assert_eq!(keys.check(&keys.viewer), Some(Access::Viewer));
+        assert_eq!(keys.check(&AccessKey::random()), None);
+    }
+}
@@ -1,29 +1,42 @@
+mod event;
 mod id;
+mod key;
+mod notice;
+mod undo;
 
+use chrono::{DateTime, Utc};
 use log::error;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
 
-use crate::board::{BoardDiff, BoardState};
-use crate::cursors::{Cursors, SessionCursor};
+use crate::board::{BoardDiff, BoardDiffOperation, BoardPencilType, BoardState};
+use crate::config::{LimitsConfig, SetBoardPermission};
+use crate::cursors::{
+    Cursors, ProfileUpdate, SessionCursor, SessionCursorReceiver, SessionCursorSender,
+};
 use crate::error::SudokuError;
+use crate::metrics;
+use crate::ot;
+pub use crate::room::event::{replay, EventSeq, RoomEvent, RoomEventKind};
 pub use crate::room::id::RoomId;
+pub use crate::room::key::{Access, AccessKey, AccessKeys, HostKey, ResumeToken};
+pub use crate::room::notice::{Kick, RoomNotice};
+use crate::room::undo::{UndoEntry, UndoStacks};
+pub use crate::room::undo::{UndoKind, UndoStatus};
 
-// Limit the number of sessions per room because:
-// - We have to send O(n^2) messages per n clients
-// - We need to assign a unique color to each cursor, and there's only so many unique-looking
-//   colors.
-pub const MAX_SESSIONS_PER_ROOM: usize = 8;
-// If we exhaust this queue size and the websocket buffer, the client has lagged, and we should
-// send them a FullUpdate next time.
-const MAX_BOARD_DIFF_GROUP_QUEUE: usize = 32;
-// The client's high-level operations can be applied as a group of diffs. This needs to be larger
-// than the largest possible set of diffs that can be generated when handling a high-level
-// operation.
-const MAX_BOARD_DIFF_GROUP_SIZE: usize = 8;
+// Notices are rare, so sessions should never fall this far behind on them.
+const MAX_ROOM_NOTICE_QUEUE: usize = 16;
+// The number of applied diff groups we remember so that we can transform diffs from clients that
+// haven't seen them yet. Clients that fall further behind than this get an error.
+const MAX_REVISION_HISTORY: usize = 64;
 
 pub type BoardId = u64;
 pub type SessionId = u64;
+/// Session ids start at 1, so this marks events that came from the HTTP API instead of a session.
+pub const API_SESSION_ID: SessionId = 0;
 
 // The client should send an increasing value with each diff. When we send a message to the client,
 // we share the last value we saw. The client can then use this information to figure out which
@@ -32,18 +45,100 @@ pub type SessionId = u64;
 // A sync ID can be None if we haven't received a sync id from the client yet.
 pub type ClientSyncId = u64;
 
+// Incremented every time a group of diffs is applied to a room. Clients tell us which revision
+// their diffs were based on, so that we can transform them against any concurrent diffs they
+// haven't seen yet. See the `ot` module.
+pub type Revision = u64;
+
+/// What a session is allowed to do once it's joined a room.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SessionRole {
+    Player,
+    /// Receives every update, but can't change the board or show a cursor. Spectators don't count
+    /// towards `LimitsConfig::max_players_per_room`, and are limited separately by
+    /// `LimitsConfig::max_spectators_per_room`.
+    Spectator,
+}
+
+impl SessionRole {
+    fn name(self) -> &'static str {
+        match self {
+            SessionRole::Player => "player",
+            SessionRole::Spectator => "spectator",
+        }
+    }
+}
+
+impl From<Access> for SessionRole {
+    fn from(access: Access) -> Self {
+        match access {
+            Access::Editor => SessionRole::Player,
+            Access::Viewer => SessionRole::Spectator,
+        }
+    }
+}
+
 pub struct Session {
     pub session_id: SessionId,
-    pub diff_rx: broadcast::Receiver<Arc<BoardDiffBroadcast>>,
-    pub cursor: SessionCursor,
+    pub role: SessionRole,
+    /// Lets the client resume this session if it reconnects soon after disconnecting.
+    pub resume_token: ResumeToken,
+    pub diff_rx: broadcast::Receiver<BoardBroadcast>,
+    pub notice_rx: broadcast::Receiver<RoomNotice>,
+    /// Only players have a cursor to update.
+    pub cursor_tx: Option<SessionCursorSender>,
+    pub cursor_rx: SessionCursorReceiver,
+}
+
+/// The last sync ids received from and sent to a session's client. See `ClientSyncId`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SyncState {
+    pub last_received_sync_id: Option<ClientSyncId>,
+    pub last_sent_sync_id: Option<ClientSyncId>,
+}
+
+/// A session whose client disconnected, but which can still be resumed. Holding onto the cursor
+/// sender keeps the session's slot (and so its name and color) reserved.
+pub struct SuspendedSession {
+    pub session_id: SessionId,
+    pub role: SessionRole,
+    pub cursor_tx: Option<SessionCursorSender>,
+    pub sync: SyncState,
+}
+
+/// What sessions receive through `Session::diff_rx`, in the order the room changed.
+#[derive(Clone)]
+pub enum BoardBroadcast {
+    Diffs(Arc<BoardDiffBroadcast>),
+    /// The whole board was replaced, so sessions need a `FullUpdate` instead of diffs.
+    Replaced,
 }
 
 pub struct BoardDiffBroadcast {
+    /// The diffs as they were actually applied, after being transformed.
     pub board_diffs: Vec<BoardDiff>,
+    /// The room's revision after these diffs were applied.
+    pub revision: Revision,
+    /// The board's conflicting squares after these diffs were applied.
+    pub conflicts: Vec<u8>,
     // these allow the sender to identify it's own messages and use that to update the current
     // sync_id.
     pub sender_id: SessionId,
     pub sync_id: ClientSyncId,
+    /// Set if these diffs finished the puzzle.
+    pub completion: Option<Completion>,
+    /// What the sender can undo or redo now that these diffs have been applied.
+    pub sender_undo_status: UndoStatus,
+}
+
+/// Records when and by whom a room's puzzle was finished.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Completion {
+    /// Milliseconds between the puzzle being set up and the last digit being placed.
+    pub solve_time_ms: u64,
+    /// The session that placed the last digit.
+    pub solved_by: SessionId,
 }
 
 pub struct RoomState {
@@ -51,71 +146,637 @@ pub struct RoomState {
     #[allow(dead_code)]
     pub board_id: BoardId,
     pub board: BoardState,
+    /// The secrets a session has to present to join this room. Whoever creates the room is given
+    /// the editor key.
+    pub access_keys: AccessKeys,
     /// Indicates that the RoomState has changed in a way that causes it to differ from the room
     /// on disk. This is cleared whenever we write back to disk.
     pub dirty: bool,
+    /// The last time a session joined, left, or changed the board. Used by garbage collection to
+    /// find idle rooms.
+    pub last_activity: Instant,
+    /// When the current puzzle was set up. Used to compute the solve time.
+    pub started_at: DateTime<Utc>,
+    /// Set once the board is completely and correctly filled in. This stays set even if
+    /// somebody changes the board afterwards.
+    pub completion: Option<Completion>,
+    /// While set, nobody can change the board. Use `set_locked` to change this, so that sessions
+    /// find out.
+    pub locked: bool,
+    /// Whoever presents this becomes the host. Rooms are created with a random one, which is
+    /// given to the creator.
+    pub host_key: HostKey,
+    /// The session that's currently the host, if any. Sessions don't outlive the server, so this
+    /// isn't persisted, and the host has to reclaim the role with `host_key` after a restart.
+    pub host: Option<SessionId>,
+    /// Set once the host closes the room. Closed rooms can't be joined.
+    pub closed: bool,
+    /// Set once the server is about to write the room back for the last time before exiting.
+    /// Nothing can change the board after that, so that the final writeback doesn't miss
+    /// anything. This isn't persisted.
+    stopped: bool,
+    revision: Revision,
+    /// The most recently applied diff groups, oldest first.
+    history: VecDeque<Arc<BoardDiffBroadcast>>,
+    /// The `seq` of the next event added to this room's log.
+    next_event_seq: EventSeq,
+    /// Events that haven't been written to storage yet.
+    pending_events: Vec<RoomEvent>,
     // DO NOT send to this without grabbing the mutex first, otherwise the board state could fall
     // behind. This is a private member and only used via RoomState::apply.
-    diff_tx: broadcast::Sender<Arc<BoardDiffBroadcast>>,
+    diff_tx: broadcast::Sender<BoardBroadcast>,
+    notice_tx: broadcast::Sender<RoomNotice>,
     /// Used to create unique session_ids for each Session
     session_counter: SessionId,
+    /// The number of players and spectators that are currently connected. Rooms with connected
+    /// sessions are never garbage collected.
+    connected_players: usize,
+    connected_spectators: usize,
+    /// The ids of every connected player, so that the host role is only handed to one of them.
+    players: HashSet<SessionId>,
+    /// Disconnected sessions that can still be resumed, along with when they disconnected. These
+    /// are still included in the connected session counts.
+    suspended_sessions: HashMap<ResumeToken, (SuspendedSession, Instant)>,
+    /// Each session's undo and redo stacks. These are kept while a session is suspended, so that
+    /// it can still undo things after resuming.
+    undo_stacks: HashMap<SessionId, UndoStacks>,
     cursors: Cursors,
+    /// The limits from the config, which can't change while the server is running.
+    limits: LimitsConfig,
 }
 
 impl RoomState {
-    pub fn new(room_id: RoomId) -> RoomState {
-        let (diff_tx, _diff_rx) = broadcast::channel(MAX_BOARD_DIFF_GROUP_QUEUE);
+    pub fn new(room_id: RoomId, limits: &LimitsConfig) -> RoomState {
+        let (diff_tx, _diff_rx) = broadcast::channel(limits.max_diff_group_queue);
+        let (notice_tx, _notice_rx) = broadcast::channel(MAX_ROOM_NOTICE_QUEUE);
         RoomState {
             room_id,
             board_id: 0,
             board: Default::default(),
+            access_keys: AccessKeys::random(),
             dirty: true,
+            last_activity: Instant::now(),
+            started_at: Utc::now(),
+            completion: None,
+            locked: false,
+            host_key: HostKey::random(),
+            host: None,
+            closed: false,
+            stopped: false,
+            revision: 0,
+            history: VecDeque::with_capacity(MAX_REVISION_HISTORY),
+            next_event_seq: 0,
+            pending_events: Vec::new(),
             diff_tx,
+            notice_tx,
             session_counter: 0,
+            connected_players: 0,
+            connected_spectators: 0,
+            players: HashSet::new(),
+            suspended_sessions: HashMap::new(),
+            undo_stacks: HashMap::new(),
             cursors: Cursors::new(),
+            limits: limits.clone(),
         }
     }
 
-    pub fn new_session(&mut self) -> Result<Session, SudokuError> {
+    pub fn new_session(
+        &mut self,
+        role: SessionRole,
+        profile: ProfileUpdate,
+    ) -> Result<Session, SudokuError> {
+        let limits = &self.limits;
+        if self.closed {
+            return Err(SudokuError::RoomClosed);
+        }
         self.session_counter += 1;
-        Ok(Session {
+        let (cursor_tx, cursor_rx) = match role {
+            SessionRole::Player => {
+                let SessionCursor { tx, rx } = self
+                    .cursors
+                    .new_session(self.session_counter, profile, limits.max_players_per_room)
+                    .or(Err(SudokuError::RoomFull(limits.max_players_per_room)))?;
+                (Some(tx), rx)
+            }
+            SessionRole::Spectator => {
+                if self.connected_spectators >= limits.max_spectators_per_room {
+                    return Err(SudokuError::TooManySpectators(
+                        limits.max_spectators_per_room,
+                    ));
+                }
+                (None, self.cursors.new_spectator())
+            }
+        };
+        let session = Session {
             session_id: self.session_counter,
+            role,
+            resume_token: ResumeToken::random(),
             diff_rx: self.diff_tx.subscribe(),
-            cursor: self
-                .cursors
-                .new_session(self.session_counter)
-                .or(Err(SudokuError::RoomFull(MAX_SESSIONS_PER_ROOM)))?,
-        })
+            notice_rx: self.notice_tx.subscribe(),
+            cursor_tx,
+            cursor_rx,
+        };
+        match role {
+            SessionRole::Player => {
+                self.connected_players += 1;
+                self.players.insert(session.session_id);
+            }
+            SessionRole::Spectator => self.connected_spectators += 1,
+        }
+        metrics::SESSIONS.with_label_values(&[role.name()]).inc();
+        self.last_activity = Instant::now();
+        Ok(session)
+    }
+
+    /// Must be called once for every successful call to `new_session` when that session
+    /// disconnects.
+    pub fn end_session(&mut self, session_id: SessionId, role: SessionRole) {
+        self.undo_stacks.remove(&session_id);
+        self.players.remove(&session_id);
+        if self.host == Some(session_id) {
+            self.set_host(None);
+        }
+        let count = match role {
+            SessionRole::Player => &mut self.connected_players,
+            SessionRole::Spectator => &mut self.connected_spectators,
+        };
+        *count = count.saturating_sub(1);
+        metrics::SESSIONS.with_label_values(&[role.name()]).dec();
+        self.last_activity = Instant::now();
+    }
+
+    /// Keeps a disconnected session around so that it can be picked back up with
+    /// `resume_session`. This takes the place of calling `end_session`, and the session keeps
+    /// counting towards the room's limits until it's resumed or expires.
+    pub fn suspend_session(&mut self, resume_token: ResumeToken, session: SuspendedSession) {
+        self.suspended_sessions
+            .insert(resume_token, (session, Instant::now()));
+        self.last_activity = Instant::now();
+    }
+
+    /// Picks a suspended session back up, returning it along with the sync state it had when it
+    /// disconnected. The new session's `diff_rx` only receives diffs applied from now on, so use
+    /// `history_since` to catch the client up.
+    ///
+    /// Returns `None` if the token is unknown (e.g. because it expired), or if it belongs to a
+    /// session with a different role.
+    pub fn resume_session(
+        &mut self,
+        resume_token: ResumeToken,
+        role: SessionRole,
+    ) -> Option<(Session, SyncState)> {
+        match self.suspended_sessions.get(&resume_token) {
+            Some((suspended, _)) if suspended.role == role => {}
+            _ => return None,
+        }
+        let (suspended, _) = self.suspended_sessions.remove(&resume_token)?;
+        let cursor_rx = match &suspended.cursor_tx {
+            Some(cursor_tx) => cursor_tx.new_receiver(),
+            None => self.cursors.new_spectator(),
+        };
+        let session = Session {
+            session_id: suspended.session_id,
+            role,
+            resume_token,
+            diff_rx: self.diff_tx.subscribe(),
+            notice_rx: self.notice_tx.subscribe(),
+            cursor_tx: suspended.cursor_tx,
+            cursor_rx,
+        };
+        self.last_activity = Instant::now();
+        Some((session, suspended.sync))
+    }
+
+    /// Ends every suspended session that disconnected more than `grace_period` ago, freeing up
+    /// their cursor slots.
+    pub fn expire_suspended_sessions(&mut self, grace_period: Duration) {
+        let mut expired = Vec::new();
+        self.suspended_sessions
+            .retain(|_, (suspended, suspended_at)| {
+                let keep = suspended_at.elapsed() < grace_period;
+                if !keep {
+                    expired.push((suspended.session_id, suspended.role));
+                }
+                keep
+            });
+        for (session_id, role) in expired {
+            self.end_session(session_id, role);
+        }
+    }
+
+    /// Disconnects the matching sessions, without letting them be resumed. Suspended sessions end
+    /// right away, and connected ones end once they receive the notice.
+    pub fn kick(&mut self, kick: Kick) {
+        let kicked: Vec<ResumeToken> = self
+            .suspended_sessions
+            .iter()
+            .filter(|(_, (suspended, _))| kick.matches(suspended.session_id))
+            .map(|(resume_token, _)| *resume_token)
+            .collect();
+        for resume_token in kicked {
+            if let Some((suspended, _)) = self.suspended_sessions.remove(&resume_token) {
+                self.end_session(suspended.session_id, suspended.role);
+            }
+        }
+        // there may not be any connected sessions to receive this
+        let _ = self.notice_tx.send(RoomNotice::Kick(kick));
+    }
+
+    /// Locks or unlocks the board, and lets every session know.
+    pub fn set_locked(&mut self, locked: bool) {
+        if self.locked != locked {
+            self.locked = locked;
+            self.dirty = true;
+            let _ = self.notice_tx.send(RoomNotice::Locked(locked));
+        }
+    }
+
+    /// Lets every session know that the server is shutting down.
+    pub fn announce_shutdown(&self) {
+        let _ = self.notice_tx.send(RoomNotice::ShuttingDown);
+    }
+
+    /// Stops any more changes to the board, for sessions that are still connected when the
+    /// server does its final writeback.
+    pub fn stop_changes(&mut self) {
+        self.stopped = true;
+    }
+
+    fn check_writable(&self) -> Result<(), SudokuError> {
+        if self.stopped {
+            Err(SudokuError::ShuttingDown)
+        } else if self.locked {
+            Err(SudokuError::RoomLocked)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The number of connected players and spectators.
+    pub fn session_counts(&self) -> (usize, usize) {
+        (self.connected_players, self.connected_spectators)
+    }
+
+    /// The number of connected sessions, including spectators.
+    pub fn session_count(&self) -> usize {
+        self.connected_players + self.connected_spectators
+    }
+
+    /// Like `session_count`, but without suspended sessions, which are still counted there
+    /// because they're holding onto a slot.
+    pub fn active_session_count(&self) -> usize {
+        self.session_count() - self.suspended_sessions.len()
     }
 
     // creates a broadcast::Receiver without creating a new session. Useful for resetting the
     // receiver in an already-existing session.
-    pub fn new_sessionless_receiver(&self) -> broadcast::Receiver<Arc<BoardDiffBroadcast>> {
+    pub fn new_sessionless_receiver(&self) -> broadcast::Receiver<BoardBroadcast> {
         self.diff_tx.subscribe()
     }
 
+    pub fn revision(&self) -> Revision {
+        self.revision
+    }
+
+    /// The id that the next call to `new_session` will give out.
+    pub fn next_session_id(&self) -> SessionId {
+        self.session_counter + 1
+    }
+
+    /// Whether the session is the room's host. A suspended host stays the host until its session
+    /// ends.
+    pub fn is_host(&self, session_id: SessionId) -> bool {
+        self.host == Some(session_id)
+    }
+
+    /// Checks that the session is allowed to replace the whole board. When only the host is,
+    /// the permission follows the room's host key, which is persisted, so whoever created the room
+    /// can still set its board after a restart by reclaiming the role with that key.
+    pub fn check_can_set_board(
+        &self,
+        session_id: SessionId,
+        permission: SetBoardPermission,
+    ) -> Result<(), SudokuError> {
+        match permission {
+            SetBoardPermission::Host => self.check_host(session_id),
+            SetBoardPermission::Players => Ok(()),
+        }
+    }
+
+    /// Returns the access granted by the key a client is joining with, if any. Keyless rooms
+    /// stop letting people in without a key as soon as somebody uses one, since that means the
+    /// keys have been shared.
+    pub fn check_access(&mut self, key: Option<&AccessKey>) -> Option<Access> {
+        match key {
+            Some(key) => {
+                let access = self.access_keys.check(key)?;
+                if self.access_keys.keyless {
+                    self.access_keys.keyless = false;
+                    self.dirty = true;
+                }
+                Some(access)
+            }
+            None if self.access_keys.keyless => Some(Access::Editor),
+            None => None,
+        }
+    }
+
+    fn check_host(&self, session_id: SessionId) -> Result<(), SudokuError> {
+        if self.is_host(session_id) {
+            Ok(())
+        } else {
+            Err(SudokuError::NotHost)
+        }
+    }
+
+    fn set_host(&mut self, host: Option<SessionId>) {
+        self.host = host;
+        let _ = self.notice_tx.send(RoomNotice::HostChanged {
+            host,
+            host_key: self.host_key,
+        });
+    }
+
+    /// Makes the session the host if `key` is the room's host key. Only players can be the host.
+    pub fn claim_host(&mut self, session_id: SessionId, key: HostKey) -> bool {
+        if key != self.host_key || !self.players.contains(&session_id) {
+            return false;
+        }
+        self.set_host(Some(session_id));
+        true
+    }
+
+    /// Hands the host role to another player. The host key is replaced, so that the old host
+    /// can't take the role back by reconnecting.
+    pub fn transfer_host(
+        &mut self,
+        session_id: SessionId,
+        to: SessionId,
+    ) -> Result<(), SudokuError> {
+        self.check_host(session_id)?;
+        if !self.players.contains(&to) {
+            return Err(SudokuError::NoSuchPlayer(to));
+        }
+        self.host_key = HostKey::random();
+        self.dirty = true;
+        self.set_host(Some(to));
+        Ok(())
+    }
+
+    /// Locks or unlocks squares for everybody, so that they can't be changed until they're
+    /// unlocked again. Only the host can do this. It's applied like any other diff group, so the
+    /// host can undo it.
+    pub fn set_squares_locked(
+        &mut self,
+        session_id: SessionId,
+        sync_id: ClientSyncId,
+        squares: Vec<u8>,
+        locked: bool,
+    ) -> Result<(), SudokuError> {
+        self.check_host(session_id)?;
+        self.check_writable()?;
+        let board_diffs = vec![BoardDiff {
+            squares,
+            operation: BoardDiffOperation::SetLocked { locked },
+        }];
+        self.apply_group(session_id, sync_id, board_diffs, None)
+    }
+
+    /// Clears the corner and center pencil marks from every unlocked square. Only the host can
+    /// do this. It's applied like any other diff group, so the host can undo it.
+    pub fn reset_pencil_marks(
+        &mut self,
+        session_id: SessionId,
+        sync_id: ClientSyncId,
+    ) -> Result<(), SudokuError> {
+        self.check_host(session_id)?;
+        self.check_writable()?;
+        let squares: Vec<u8> = (0..self.board.squares().len() as u8).collect();
+        let board_diffs = [BoardPencilType::Corners, BoardPencilType::Centers]
+            .iter()
+            .map(|&r#type| BoardDiff {
+                squares: squares.clone(),
+                operation: BoardDiffOperation::ClearPencilMarks { r#type },
+            })
+            .collect();
+        self.apply_group(session_id, sync_id, board_diffs, None)
+    }
+
+    /// Only the host can lock or unlock squares, so diff groups that do are rejected for
+    /// everybody else.
+    fn check_can_lock(
+        &self,
+        session_id: SessionId,
+        board_diffs: &[BoardDiff],
+    ) -> Result<(), SudokuError> {
+        let locks = board_diffs
+            .iter()
+            .any(|diff| matches!(diff.operation, BoardDiffOperation::SetLocked { .. }));
+        if locks {
+            self.check_host(session_id)?;
+        }
+        Ok(())
+    }
+
+    /// Like `kick`, but on behalf of the host instead of an admin. A kicked player can join again
+    /// with the same share link, so to keep them out, the host should also `rotate_keys`.
+    pub fn host_kick(
+        &mut self,
+        session_id: SessionId,
+        kicked: SessionId,
+    ) -> Result<(), SudokuError> {
+        self.check_host(session_id)?;
+        self.kick(Kick::Session(kicked));
+        Ok(())
+    }
+
+    /// Replaces the room's access keys, so that the old share links stop working. Connected
+    /// sessions are sent their new key, but disconnected ones can't resume with their old one.
+    /// Only the host can do this.
+    pub fn rotate_keys(&mut self, session_id: SessionId) -> Result<(), SudokuError> {
+        self.check_host(session_id)?;
+        self.access_keys = AccessKeys::random();
+        self.dirty = true;
+        let _ = self
+            .notice_tx
+            .send(RoomNotice::KeysRotated(self.access_keys));
+        Ok(())
+    }
+
+    /// Kicks everybody out, and stops anybody from joining again. Only the host can do this.
+    pub fn close(&mut self, session_id: SessionId) -> Result<(), SudokuError> {
+        self.check_host(session_id)?;
+        self.closed = true;
+        self.dirty = true;
+        self.kick(Kick::Everyone);
+        Ok(())
+    }
+
+    /// Replaces the board with a new puzzle, restarting the solve timer, and sends every session
+    /// a `FullUpdate`.
+    ///
+    /// Checking that the puzzle can be solved is slow, so it's up to the caller to do that first
+    /// with `solver::spawn_check_solvable`, without holding the room's lock.
+    pub fn set_board(
+        &mut self,
+        session_id: SessionId,
+        board: BoardState,
+    ) -> Result<(), SudokuError> {
+        self.check_writable()?;
+        board.validate()?;
+        self.replace_board(session_id, board);
+        self.started_at = Utc::now();
+        self.completion = None;
+        Ok(())
+    }
+
+    /// Changes the board without going through diffs, and sends every session a `FullUpdate`.
+    /// Diffs based on the old board can't be transformed onto the new one, so the revision
+    /// history starts over.
+    fn replace_board(&mut self, session_id: SessionId, board: BoardState) {
+        self.board = board;
+        self.revision += 1;
+        self.history.clear();
+        self.dirty = true;
+        self.last_activity = Instant::now();
+        // the old board's changes can't be undone on top of a new one
+        self.undo_stacks.clear();
+        self.log_event(
+            session_id,
+            None,
+            RoomEventKind::SetBoard {
+                board_state: self.board.clone(),
+            },
+        );
+        // there's nobody to tell if the room was just created
+        let _ = self.diff_tx.send(BoardBroadcast::Replaced);
+    }
+
+    /// Applies a group of diffs from a session and broadcasts them to every session.
+    ///
+    /// If `base_revision` is given, the diffs are transformed against any diffs from other
+    /// sessions that were applied after that revision. Otherwise they're assumed to be based on
+    /// the current revision. Either every diff in the group is applied, or none of them are.
     pub fn apply_diffs(
         &mut self,
         session_id: SessionId,
         sync_id: ClientSyncId,
+        base_revision: Option<Revision>,
         board_diffs: Vec<BoardDiff>,
     ) -> Result<(), SudokuError> {
-        if board_diffs.len() > MAX_BOARD_DIFF_GROUP_SIZE {
+        self.check_writable()?;
+        let board_diffs = match base_revision {
+            Some(base_revision) => {
+                self.transform_diffs(session_id, base_revision, board_diffs, false)?
+            }
+            None => board_diffs,
+        };
+        self.check_can_lock(session_id, &board_diffs)?;
+        // transforming can split diffs up, so check the size of what would actually be applied
+        if board_diffs.len() > self.limits.max_diff_group_size {
             return Err(SudokuError::TooManyBoardDiffs(
                 board_diffs.len(),
-                MAX_BOARD_DIFF_GROUP_SIZE,
+                self.limits.max_diff_group_size,
             ));
         }
+        self.apply_group(session_id, sync_id, board_diffs, None)
+    }
+
+    /// Reverts the session's most recent change that hasn't been undone yet, or reapplies the
+    /// change it most recently undid. Changes other sessions have made since are left alone.
+    pub fn undo(
+        &mut self,
+        session_id: SessionId,
+        sync_id: ClientSyncId,
+        kind: UndoKind,
+    ) -> Result<(), SudokuError> {
+        self.check_writable()?;
+        let nothing_to_do = || match kind {
+            UndoKind::Undo => SudokuError::NothingToUndo,
+            UndoKind::Redo => SudokuError::NothingToRedo,
+        };
+        let stacks = self.undo_stacks.entry(session_id).or_default();
+        let entry = stacks.pop(kind).ok_or_else(nothing_to_do)?;
+        let board_diffs = self
+            .transform_diffs(session_id, entry.revision, entry.diffs, true)
+            .map_err(|_| {
+                // this entry is too old to transform, and everything under it is even older
+                if let Some(stacks) = self.undo_stacks.get_mut(&session_id) {
+                    stacks.clear(kind);
+                }
+                nothing_to_do()
+            })?;
+        // undoing a lock is still locking, so it's only allowed while the session is the host
+        self.check_can_lock(session_id, &board_diffs)?;
+        self.apply_group(session_id, sync_id, board_diffs, Some(kind))
+    }
+
+    /// What the session can currently undo or redo.
+    pub fn undo_status(&self, session_id: SessionId) -> UndoStatus {
+        self.undo_stacks
+            .get(&session_id)
+            .map(UndoStacks::status)
+            .unwrap_or_default()
+    }
+
+    /// Applies and broadcasts diffs that have already been transformed, and records how to revert
+    /// them. `undo` is set if the diffs are themselves an undo or redo.
+    fn apply_group(
+        &mut self,
+        session_id: SessionId,
+        sync_id: ClientSyncId,
+        board_diffs: Vec<BoardDiff>,
+        undo: Option<UndoKind>,
+    ) -> Result<(), SudokuError> {
+        let mut board = self.board.clone();
         for bd in board_diffs.iter() {
-            self.board.apply(bd)?;
+            board.apply(bd)?;
         }
+        let inverse = undo::inverse(&self.board, &board, &board_diffs);
+        self.board = board;
+        self.revision += 1;
         self.dirty = true;
-        let broadcast = BoardDiffBroadcast {
+        self.last_activity = Instant::now();
+        metrics::DIFFS_APPLIED.inc_by(board_diffs.len() as u64);
+        let stacks = self.undo_stacks.entry(session_id).or_default();
+        stacks.record(
+            undo,
+            UndoEntry {
+                revision: self.revision,
+                diffs: inverse,
+            },
+        );
+        let sender_undo_status = stacks.status();
+        self.log_event(
+            session_id,
+            Some(sync_id),
+            RoomEventKind::ApplyDiffs {
+                diffs: board_diffs.clone(),
+            },
+        );
+        let conflicts = self.board.conflicts();
+        let mut completion = None;
+        if self.completion.is_none() && conflicts.is_empty() && self.board.is_filled() {
+            completion = Some(Completion {
+                solve_time_ms: (Utc::now() - self.started_at).num_milliseconds().max(0) as u64,
+                solved_by: session_id,
+            });
+            self.completion = completion;
+        }
+        let broadcast = Arc::new(BoardDiffBroadcast {
             board_diffs,
+            revision: self.revision,
+            conflicts,
             sender_id: session_id,
             sync_id,
-        };
-        if let Err(_) = self.diff_tx.send(Arc::new(broadcast)) {
+            completion,
+            sender_undo_status,
+        });
+        if self.history.len() == MAX_REVISION_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(broadcast.clone());
+        if let Err(_) = self.diff_tx.send(BoardBroadcast::Diffs(broadcast)) {
             // we shouldn't be sending if there's no receivers, because the session doing the
             // sending should also be receiving.
             error!("tried to send message to broadcast with no receivers")
@@ -123,18 +784,575 @@ impl RoomState {
         Ok(())
     }
 
-    #[cfg(feature = "sql")]
-    pub fn sql_serialize(&self) -> [u8; 81 * 6] {
-        self.board.sql_serialize()
+    /// Returns every diff group applied after `revision`, oldest first, or `None` if `revision` is
+    /// in the future or has already fallen out of the history.
+    pub fn history_since(
+        &self,
+        revision: Revision,
+    ) -> Option<impl Iterator<Item = &Arc<BoardDiffBroadcast>>> {
+        if revision > self.revision {
+            return None;
+        }
+        let missed_count = (self.revision - revision) as usize;
+        if missed_count > self.history.len() {
+            return None;
+        }
+        Some(self.history.iter().skip(self.history.len() - missed_count))
+    }
+
+    fn log_event(
+        &mut self,
+        session_id: SessionId,
+        sync_id: Option<ClientSyncId>,
+        kind: RoomEventKind,
+    ) {
+        let seq = self.next_event_seq;
+        self.next_event_seq += 1;
+        self.pending_events.push(RoomEvent {
+            seq,
+            applied_at: Utc::now(),
+            session_id,
+            sync_id,
+            revision: self.revision,
+            kind,
+        });
+    }
+
+    /// Every event that hasn't been written to storage yet, oldest first.
+    pub fn pending_events(&self) -> &[RoomEvent] {
+        &self.pending_events
+    }
+
+    /// Removes and returns every event that hasn't been written to storage yet, oldest first.
+    pub fn take_pending_events(&mut self) -> Vec<RoomEvent> {
+        std::mem::take(&mut self.pending_events)
+    }
+
+    /// Puts back events returned by `take_pending_events` that failed to be written, so that
+    /// they're retried along with any newer events.
+    pub fn restore_pending_events(&mut self, mut events: Vec<RoomEvent>) {
+        events.append(&mut self.pending_events);
+        self.pending_events = events;
+    }
+
+    /// Transforms diffs based on `base_revision` against every diff group from other sessions
+    /// that's been applied since. A session's own diff groups are skipped, since they were
+    /// generated before the diffs we're transforming.
+    ///
+    /// If `yield_to_others` is set, the other sessions' diffs always win conflicts. Otherwise
+    /// the older session wins.
+    fn transform_diffs(
+        &self,
+        session_id: SessionId,
+        base_revision: Revision,
+        board_diffs: Vec<BoardDiff>,
+        yield_to_others: bool,
+    ) -> Result<Vec<BoardDiff>, SudokuError> {
+        let missed = self
+            .history_since(base_revision)
+            .ok_or(SudokuError::InvalidRevision(base_revision))?;
+        let mut board_diffs = board_diffs;
+        for applied in missed {
+            if applied.sender_id == session_id {
+                continue;
+            }
+            // break ties between conflicting diffs in favor of the older session
+            let has_priority = !yield_to_others && session_id < applied.sender_id;
+            board_diffs =
+                ot::transform_sequences(board_diffs, applied.board_diffs.clone(), has_priority).0;
+        }
+        Ok(board_diffs)
     }
 
     #[cfg(feature = "sql")]
     pub fn sql_deserialize(
         room_id: RoomId,
-        board_bytes: &[u8; 81 * 6],
+        board_bytes: &[u8],
+        limits: &LimitsConfig,
     ) -> Result<Self, &'static str> {
-        let mut room = Self::new(room_id);
+        let mut room = Self::new(room_id, limits);
         room.board = BoardState::sql_deserialize(board_bytes)?;
         Ok(room)
     }
+
+    /// Continues the event log of a room loaded from storage. Rooms written before the log
+    /// existed get a `SetBoard` event with their current board, so that replaying their log
+    /// starts from the right place.
+    pub fn resume_event_log(&mut self, revision: Revision, next_event_seq: EventSeq) {
+        self.revision = revision;
+        self.next_event_seq = next_event_seq;
+        if next_event_seq == 0 {
+            self.log_event(
+                0,
+                None,
+                RoomEventKind::SetBoard {
+                    board_state: self.board.clone(),
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digit::Digit;
+    use crate::solver::Solver;
+
+    #[test]
+    fn completion() {
+        let mut rs = RoomState::new(RoomId::random(), &LimitsConfig::default());
+        let _session = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        let solution = Solver::new([None; 81]).solve().unwrap().unwrap();
+        let set_number = |idx: usize| BoardDiff {
+            squares: vec![idx as u8],
+            operation: BoardDiffOperation::SetNumber {
+                digit: Some(solution[idx]),
+            },
+        };
+
+        for idx in 0..80 {
+            rs.apply_diffs(1, idx as ClientSyncId, None, vec![set_number(idx)])
+                .unwrap();
+        }
+        assert_eq!(rs.completion, None);
+
+        rs.apply_diffs(2, 80, None, vec![set_number(80)]).unwrap();
+        let completion = rs.completion.unwrap();
+        assert_eq!(completion.solved_by, 2);
+
+        // later changes don't reset or re-trigger the completion
+        rs.apply_diffs(
+            1,
+            81,
+            None,
+            vec![BoardDiff {
+                squares: vec![0],
+                operation: BoardDiffOperation::SetNumber { digit: None },
+            }],
+        )
+        .unwrap();
+        assert_eq!(rs.completion, Some(completion));
+    }
+
+    #[test]
+    fn concurrent_diffs_converge() {
+        let set_number = |digit| BoardDiff {
+            squares: vec![0],
+            operation: BoardDiffOperation::SetNumber { digit: Some(digit) },
+        };
+        let add_mark = BoardDiff {
+            squares: vec![1],
+            operation: BoardDiffOperation::AddPencilMark {
+                r#type: BoardPencilType::Centers,
+                digit: Digit::D4,
+            },
+        };
+        let clear_marks = BoardDiff {
+            squares: vec![1],
+            operation: BoardDiffOperation::ClearPencilMarks {
+                r#type: BoardPencilType::Centers,
+            },
+        };
+        let session1_diffs = vec![set_number(Digit::D1), add_mark];
+        let session2_diffs = vec![set_number(Digit::D2), clear_marks];
+
+        // both sessions generate diffs against revision 0, but they arrive in different orders
+        let mut boards = Vec::new();
+        for session1_first in [true, false].iter() {
+            let mut rs = RoomState::new(RoomId::random(), &LimitsConfig::default());
+            let _sessions = (
+                rs.new_session(SessionRole::Player, Default::default())
+                    .unwrap(),
+                rs.new_session(SessionRole::Player, Default::default())
+                    .unwrap(),
+            );
+            let mut requests = vec![(1, session1_diffs.clone()), (2, session2_diffs.clone())];
+            if !session1_first {
+                requests.reverse();
+            }
+            for (session_id, diffs) in requests {
+                rs.apply_diffs(session_id, 1, Some(0), diffs).unwrap();
+            }
+            assert_eq!(rs.revision(), 2);
+            boards.push(rs.board.clone());
+        }
+        assert_eq!(boards[0], boards[1]);
+        // session 1 has priority for the conflicting number, and the concurrent mark survives the
+        // clear
+        assert_eq!(boards[0].squares()[0].number, Some(Digit::D1));
+        assert!(boards[0].squares()[1].centers.contains(Digit::D4));
+    }
+
+    #[test]
+    fn undo_keeps_other_sessions_changes() {
+        let mut rs = RoomState::new(RoomId::random(), &LimitsConfig::default());
+        let set_number = |idx: u8, digit| BoardDiff {
+            squares: vec![idx],
+            operation: BoardDiffOperation::SetNumber { digit },
+        };
+        let number_at = |rs: &RoomState, idx: usize| rs.board.squares()[idx].number;
+
+        rs.apply_diffs(1, 1, None, vec![set_number(0, Some(Digit::D1))])
+            .unwrap();
+        rs.apply_diffs(1, 2, None, vec![set_number(1, Some(Digit::D1))])
+            .unwrap();
+        // session 2 overwrites session 1's first change
+        rs.apply_diffs(2, 1, None, vec![set_number(0, Some(Digit::D2))])
+            .unwrap();
+        assert_eq!(
+            rs.undo_status(1),
+            UndoStatus {
+                can_undo: true,
+                can_redo: false
+            }
+        );
+
+        // undoing session 1's changes doesn't touch what session 2 did, even though session 1
+        // has priority for normal diffs
+        rs.undo(1, 3, UndoKind::Undo).unwrap();
+        assert_eq!(number_at(&rs, 1), None);
+        rs.undo(1, 4, UndoKind::Undo).unwrap();
+        assert_eq!(number_at(&rs, 0), Some(Digit::D2));
+        assert!(matches!(
+            rs.undo(1, 5, UndoKind::Undo),
+            Err(SudokuError::NothingToUndo)
+        ));
+
+        rs.undo(1, 6, UndoKind::Redo).unwrap();
+        rs.undo(1, 7, UndoKind::Redo).unwrap();
+        assert_eq!(number_at(&rs, 1), Some(Digit::D1));
+        assert_eq!(
+            rs.undo_status(1),
+            UndoStatus {
+                can_undo: true,
+                can_redo: false
+            }
+        );
+        // session 2's own undo history is separate, and puts back what it overwrote
+        rs.undo(2, 2, UndoKind::Undo).unwrap();
+        assert_eq!(number_at(&rs, 0), Some(Digit::D1));
+
+        // a new board can't be undone into
+        rs.set_board(1, BoardState::default()).unwrap();
+        assert_eq!(rs.undo_status(1), UndoStatus::default());
+    }
+
+    #[test]
+    fn set_board() {
+        let mut rs = RoomState::new(RoomId::random(), &LimitsConfig::default());
+        let mut session = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        rs.apply_diffs(1, 1, None, vec![]).unwrap();
+        assert!(matches!(
+            session.diff_rx.try_recv(),
+            Ok(BoardBroadcast::Diffs(_))
+        ));
+
+        let empty: BoardState = serde_json::from_str(r#"{"squares": []}"#).unwrap();
+        assert!(matches!(
+            rs.set_board(1, empty),
+            Err(SudokuError::InvalidSquareCount(0))
+        ));
+        assert_eq!(rs.revision(), 1);
+
+        rs.set_board(1, BoardState::default()).unwrap();
+        assert!(matches!(
+            session.diff_rx.try_recv(),
+            Ok(BoardBroadcast::Replaced)
+        ));
+        // diffs from before the new board can't be transformed onto it
+        assert_eq!(rs.revision(), 2);
+        assert!(matches!(
+            rs.apply_diffs(1, 2, Some(1), vec![]),
+            Err(SudokuError::InvalidRevision(1))
+        ));
+    }
+
+    #[test]
+    fn host_controls() {
+        let limits = LimitsConfig {
+            max_players_per_room: 2,
+            ..Default::default()
+        };
+        let mut rs = RoomState::new(RoomId::random(), &limits);
+        let mut host = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        let player = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        let mut spectator = rs
+            .new_session(SessionRole::Spectator, Default::default())
+            .unwrap();
+        assert!(matches!(
+            rs.close(host.session_id),
+            Err(SudokuError::NotHost)
+        ));
+        // the host key only works for players
+        let host_key = rs.host_key;
+        assert!(!rs.claim_host(spectator.session_id, host_key));
+        assert!(rs.claim_host(host.session_id, host_key));
+        assert_eq!(
+            host.notice_rx.try_recv().unwrap(),
+            RoomNotice::HostChanged {
+                host: Some(host.session_id),
+                host_key,
+            }
+        );
+
+        let pencil_mark = BoardDiff {
+            squares: vec![0],
+            operation: BoardDiffOperation::AddPencilMark {
+                r#type: BoardPencilType::Corners,
+                digit: Digit::D3,
+            },
+        };
+        rs.apply_diffs(player.session_id, 1, None, vec![pencil_mark])
+            .unwrap();
+        assert!(matches!(
+            rs.reset_pencil_marks(player.session_id, 2),
+            Err(SudokuError::NotHost)
+        ));
+        rs.reset_pencil_marks(host.session_id, 1).unwrap();
+        assert!(rs.board.squares()[0].corners.is_empty());
+        assert!(matches!(
+            rs.set_squares_locked(host.session_id, 2, vec![0, 81], true),
+            Err(SudokuError::InvalidSquareIndex(81))
+        ));
+        rs.set_squares_locked(host.session_id, 2, vec![0, 80], true)
+            .unwrap();
+        assert!(rs.board.squares()[80].locked);
+        // these are ordinary diff groups, so other sessions can keep going from where they were,
+        // and the host can undo them
+        assert_eq!(rs.revision(), 3);
+        assert_eq!(rs.history_since(1).unwrap().count(), 2);
+        rs.undo(host.session_id, 3, UndoKind::Undo).unwrap();
+        assert!(!rs.board.squares()[80].locked);
+        rs.undo(host.session_id, 4, UndoKind::Redo).unwrap();
+        assert!(rs.board.squares()[80].locked);
+        // nobody else can lock squares through their own diffs
+        let unlock = BoardDiff {
+            squares: vec![80],
+            operation: BoardDiffOperation::SetLocked { locked: false },
+        };
+        assert!(matches!(
+            rs.apply_diffs(player.session_id, 2, None, vec![unlock]),
+            Err(SudokuError::NotHost)
+        ));
+
+        // once transferred, the old host and its key are both useless
+        assert!(matches!(
+            rs.transfer_host(host.session_id, spectator.session_id),
+            Err(SudokuError::NoSuchPlayer(_))
+        ));
+        rs.transfer_host(host.session_id, player.session_id)
+            .unwrap();
+        assert!(rs.is_host(player.session_id));
+        assert_ne!(rs.host_key, host_key);
+        assert!(!rs.claim_host(host.session_id, host_key));
+
+        // kicking the old host frees up its slot once the realtime API ends its session and drops
+        // its cursor sender
+        rs.host_kick(player.session_id, host.session_id).unwrap();
+        rs.end_session(host.session_id, SessionRole::Player);
+        drop(host);
+        let _replacement = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        // the kicked session could join again with the same link, until the keys are replaced
+        let old_keys = rs.access_keys;
+        assert!(matches!(
+            rs.rotate_keys(spectator.session_id),
+            Err(SudokuError::NotHost)
+        ));
+        rs.rotate_keys(player.session_id).unwrap();
+        let new_keys = rs.access_keys;
+        assert_eq!(rs.check_access(Some(&old_keys.editor)), None);
+        assert_eq!(
+            rs.check_access(Some(&new_keys.editor)),
+            Some(Access::Editor)
+        );
+        let notices: Vec<_> = std::iter::from_fn(|| spectator.notice_rx.try_recv().ok()).collect();
+        assert_eq!(notices.last(), Some(&RoomNotice::KeysRotated(new_keys)));
+
+        rs.close(player.session_id).unwrap();
+        assert!(rs.closed);
+        assert!(matches!(
+            rs.new_session(SessionRole::Player, Default::default()),
+            Err(SudokuError::RoomClosed)
+        ));
+    }
+
+    #[test]
+    fn diff_group_size_is_checked_after_transform() {
+        let limits = LimitsConfig {
+            max_diff_group_size: 1,
+            ..Default::default()
+        };
+        let mut rs = RoomState::new(RoomId::random(), &limits);
+        let _session = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        let add = BoardDiff {
+            squares: vec![0],
+            operation: BoardDiffOperation::AddPencilMark {
+                r#type: BoardPencilType::Centers,
+                digit: Digit::D1,
+            },
+        };
+        let clear = BoardDiff {
+            squares: vec![0],
+            operation: BoardDiffOperation::ClearPencilMarks {
+                r#type: BoardPencilType::Centers,
+            },
+        };
+        rs.apply_diffs(1, 1, Some(0), vec![add]).unwrap();
+        // the clear is transformed into a clear followed by re-adding the concurrent mark
+        assert!(matches!(
+            rs.apply_diffs(2, 1, Some(0), vec![clear]),
+            Err(SudokuError::TooManyBoardDiffs(2, 1))
+        ));
+    }
+
+    #[test]
+    fn diff_queue_uses_configured_size() {
+        let limits = LimitsConfig {
+            max_diff_group_queue: 1,
+            ..Default::default()
+        };
+        let mut rs = RoomState::new(RoomId::random(), &limits);
+        let mut session = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        rs.apply_diffs(1, 1, None, vec![]).unwrap();
+        rs.apply_diffs(1, 2, None, vec![]).unwrap();
+        assert!(matches!(
+            session.diff_rx.try_recv(),
+            Err(broadcast::TryRecvError::Lagged(1))
+        ));
+    }
+
+    #[test]
+    fn invalid_revision() {
+        let mut rs = RoomState::new(RoomId::random(), &LimitsConfig::default());
+        let _session = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        assert!(matches!(
+            rs.apply_diffs(1, 1, Some(1), vec![]),
+            Err(SudokuError::InvalidRevision(1))
+        ));
+        for sync_id in 0..(MAX_REVISION_HISTORY as ClientSyncId + 1) {
+            rs.apply_diffs(1, sync_id, None, vec![]).unwrap();
+        }
+        // revision 0 has fallen out of the history
+        assert!(matches!(
+            rs.apply_diffs(1, 100, Some(0), vec![]),
+            Err(SudokuError::InvalidRevision(0))
+        ));
+        assert!(rs.apply_diffs(1, 100, Some(1), vec![]).is_ok());
+    }
+
+    #[test]
+    fn spectators_have_a_separate_cap() {
+        let limits = LimitsConfig {
+            max_players_per_room: 3,
+            max_spectators_per_room: 2,
+            ..Default::default()
+        };
+        let mut rs = RoomState::new(RoomId::random(), &limits);
+        let _players: Vec<_> = (0..limits.max_players_per_room)
+            .map(|_| {
+                rs.new_session(SessionRole::Player, Default::default())
+                    .unwrap()
+            })
+            .collect();
+        assert!(matches!(
+            rs.new_session(SessionRole::Player, Default::default()),
+            Err(SudokuError::RoomFull(_))
+        ));
+        // the room is full of players, but spectators can still join
+        let spectator = rs
+            .new_session(SessionRole::Spectator, Default::default())
+            .unwrap();
+        assert!(spectator.cursor_tx.is_none());
+        let _spectator = rs
+            .new_session(SessionRole::Spectator, Default::default())
+            .unwrap();
+        assert!(matches!(
+            rs.new_session(SessionRole::Spectator, Default::default()),
+            Err(SudokuError::TooManySpectators(2))
+        ));
+        assert_eq!(rs.session_count(), limits.max_players_per_room + 2);
+        rs.end_session(spectator.session_id, SessionRole::Spectator);
+        assert!(rs
+            .new_session(SessionRole::Spectator, Default::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn resume_session() {
+        let mut rs = RoomState::new(RoomId::random(), &LimitsConfig::default());
+        let session = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        let other = rs
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        let sync = SyncState {
+            last_received_sync_id: Some(3),
+            last_sent_sync_id: Some(2),
+        };
+        let revision = rs.revision();
+        rs.suspend_session(
+            session.resume_token,
+            SuspendedSession {
+                session_id: session.session_id,
+                role: session.role,
+                cursor_tx: session.cursor_tx,
+                sync,
+            },
+        );
+        rs.apply_diffs(other.session_id, 1, None, vec![]).unwrap();
+
+        // the token only works for the same role
+        assert!(rs
+            .resume_session(session.resume_token, SessionRole::Spectator)
+            .is_none());
+        let (resumed, resumed_sync) = rs
+            .resume_session(session.resume_token, SessionRole::Player)
+            .unwrap();
+        assert_eq!(resumed.session_id, session.session_id);
+        assert!(resumed.cursor_tx.is_some());
+        assert_eq!(resumed_sync, sync);
+        // the diffs applied while the session was suspended are still available
+        let missed: Vec<_> = rs.history_since(revision).unwrap().collect();
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].sender_id, other.session_id);
+        // and a token can't be used twice
+        assert!(rs
+            .resume_session(session.resume_token, SessionRole::Player)
+            .is_none());
+
+        // expiring a suspended session ends it
+        rs.suspend_session(
+            resumed.resume_token,
+            SuspendedSession {
+                session_id: resumed.session_id,
+                role: resumed.role,
+                cursor_tx: resumed.cursor_tx,
+                sync,
+            },
+        );
+        assert_eq!(rs.session_count(), 2);
+        rs.expire_suspended_sessions(Duration::from_secs(0));
+        assert_eq!(rs.session_count(), 1);
+        assert!(rs
+            .resume_session(session.resume_token, SessionRole::Player)
+            .is_none());
+    }
 }
@@ -0,0 +1,39 @@
+use crate::room::{AccessKeys, HostKey, SessionId};
+
+/// Something that happened to a room outside of any session's connection, which its sessions
+/// need to hear about. These are broadcast to every session in the room.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RoomNotice {
+    /// The room was locked or unlocked.
+    Locked(bool),
+    /// The matching sessions should disconnect, and can't be resumed.
+    Kick(Kick),
+    /// The host role moved to another session, or the host left. Only the new host should be
+    /// told the key.
+    HostChanged {
+        host: Option<SessionId>,
+        host_key: HostKey,
+    },
+    /// The host replaced the room's keys. Each session should only be told the keys its own
+    /// access allows it to see.
+    KeysRotated(AccessKeys),
+    /// The server is shutting down. Sessions should disconnect once their changes have been
+    /// applied, and reconnect to another (or the restarted) server.
+    ShuttingDown,
+}
+
+/// Which sessions a `RoomNotice::Kick` applies to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kick {
+    Session(SessionId),
+    Everyone,
+}
+
+impl Kick {
+    pub fn matches(self, session_id: SessionId) -> bool {
+        match self {
+            Kick::Session(kicked) => kicked == session_id,
+            Kick::Everyone => true,
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Per-session undo and redo.
+//!
+//! Whenever a session's diffs are applied, we compare the touched squares before and after to
+//! build a group of diffs that puts them back the way they were. Undoing applies that group like
+//! any other diff group, after transforming it against everything other sessions have done since,
+//! so that undo only reverts the session's own changes. Unlike regular diffs, an undo always
+//! loses to a conflicting change from another session.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+
+use crate::board::{BoardDiff, BoardDiffOperation, BoardPencilType, BoardState};
+use crate::room::{Revision, MAX_REVISION_HISTORY};
+
+/// Entries older than the room's revision history can't be transformed anymore, so there's no
+/// point in keeping more than this many.
+const MAX_UNDO_DEPTH: usize = MAX_REVISION_HISTORY;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UndoKind {
+    Undo,
+    Redo,
+}
+
+/// Tells a client whether it has anything to undo or redo.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UndoStatus {
+    pub can_undo: bool,
+    pub can_redo: bool,
+}
+
+pub(super) struct UndoEntry {
+    /// The room's revision right after the change this entry reverts. The diffs are based on this
+    /// revision.
+    pub revision: Revision,
+    pub diffs: Vec<BoardDiff>,
+}
+
+#[derive(Default)]
+pub(super) struct UndoStacks {
+    undo: VecDeque<UndoEntry>,
+    redo: VecDeque<UndoEntry>,
+}
+
+impl UndoStacks {
+    /// Records how to revert a diff group that was just applied. `applied_by` is set if the group
+    /// was itself an undo or redo.
+    pub fn record(&mut self, applied_by: Option<UndoKind>, entry: UndoEntry) {
+        let stack = match applied_by {
+            None => {
+                // a new change makes anything that was undone unreachable
+                self.redo.clear();
+                &mut self.undo
+            }
+            Some(UndoKind::Undo) => &mut self.redo,
+            Some(UndoKind::Redo) => &mut self.undo,
+        };
+        if stack.len() == MAX_UNDO_DEPTH {
+            stack.pop_front();
+        }
+        stack.push_back(entry);
+    }
+
+    pub fn pop(&mut self, kind: UndoKind) -> Option<UndoEntry> {
+        self.stack(kind).pop_back()
+    }
+
+    pub fn clear(&mut self, kind: UndoKind) {
+        self.stack(kind).clear();
+    }
+
+    pub fn status(&self) -> UndoStatus {
+        UndoStatus {
+            can_undo: !self.undo.is_empty(),
+            can_redo: !self.redo.is_empty(),
+        }
+    }
+
+    fn stack(&mut self, kind: UndoKind) -> &mut VecDeque<UndoEntry> {
+        match kind {
+            UndoKind::Undo => &mut self.undo,
+            UndoKind::Redo => &mut self.redo,
+        }
+    }
+}
+
+/// Returns diffs that turn `after` back into `before`, only looking at the squares touched by
+/// `applied` (the diffs that turned `before` into `after`).
+pub(super) fn inverse(
+    before: &BoardState,
+    after: &BoardState,
+    applied: &[BoardDiff],
+) -> Vec<BoardDiff> {
+    let mut squares: Vec<u8> = applied
+        .iter()
+        .flat_map(|diff| diff.squares.iter().cloned())
+        .collect();
+    squares.sort_unstable();
+    squares.dedup();
+
+    let mut inverse: Vec<BoardDiff> = Vec::new();
+    let mut push = |idx: u8, operation: BoardDiffOperation| {
+        // group squares that need the same operation into a single diff
+        match inverse.iter_mut().find(|diff| diff.operation == operation) {
+            Some(diff) => diff.squares.push(idx),
+            None => inverse.push(BoardDiff {
+                squares: vec![idx],
+                operation,
+            }),
+        }
+    };
+    // Locked squares ignore everything else, so squares have to be unlocked before anything else
+    // is put back, and locked again after.
+    let mut unlock = Vec::new();
+    let mut relock = Vec::new();
+    for idx in squares {
+        let (old, new) = match (
+            before.squares().get(idx as usize),
+            after.squares().get(idx as usize),
+        ) {
+            (Some(old), Some(new)) => (old, new),
+            _ => continue,
+        };
+        if old.locked != new.locked {
+            if old.locked {
+                relock.push(idx);
+            } else {
+                unlock.push(idx);
+            }
+        }
+        if old.number != new.number {
+            push(idx, BoardDiffOperation::SetNumber { digit: old.number });
+        }
+        for &(r#type, old_marks, new_marks) in &[
+            (BoardPencilType::Corners, old.corners, new.corners),
+            (BoardPencilType::Centers, old.centers, new.centers),
+        ] {
+            for digit in old_marks.difference(new_marks).iter() {
+                push(idx, BoardDiffOperation::AddPencilMark { r#type, digit });
+            }
+            for digit in new_marks.difference(old_marks).iter() {
+                push(idx, BoardDiffOperation::RemovePencilMark { r#type, digit });
+            }
+        }
+        for color in old.colors.difference(new.colors).iter() {
+            push(idx, BoardDiffOperation::AddColor { color });
+        }
+        for color in new.colors.difference(old.colors).iter() {
+            push(idx, BoardDiffOperation::RemoveColor { color });
+        }
+    }
+    let set_locked = |squares, locked| BoardDiff {
+        squares,
+        operation: BoardDiffOperation::SetLocked { locked },
+    };
+    if !unlock.is_empty() {
+        inverse.insert(0, set_locked(unlock, false));
+    }
+    if !relock.is_empty() {
+        inverse.push(set_locked(relock, true));
+    }
+    inverse
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::color::Color;
+    use crate::digit::Digit;
+
+    fn apply_all(board: &BoardState, diffs: &[BoardDiff]) -> BoardState {
+        let mut board = board.clone();
+        for diff in diffs {
+            board.apply(diff).unwrap();
+        }
+        board
+    }
+
+    #[test]
+    fn inverse_restores_board() {
+        let mark = |digit| BoardDiffOperation::AddPencilMark {
+            r#type: BoardPencilType::Corners,
+            digit,
+        };
+        let before = apply_all(
+            &BoardState::default(),
+            &[
+                BoardDiff {
+                    squares: vec![0, 1, 2],
+                    operation: mark(Digit::D1),
+                },
+                BoardDiff {
+                    squares: vec![1],
+                    operation: mark(Digit::D2),
+                },
+                BoardDiff {
+                    squares: vec![3],
+                    operation: BoardDiffOperation::SetNumber {
+                        digit: Some(Digit::D4),
+                    },
+                },
+            ],
+        );
+        let applied = vec![
+            BoardDiff {
+                squares: vec![0, 1, 3],
+                operation: BoardDiffOperation::SetNumber {
+                    digit: Some(Digit::D9),
+                },
+            },
+            BoardDiff {
+                squares: vec![0, 1, 2],
+                operation: BoardDiffOperation::ClearPencilMarks {
+                    r#type: BoardPencilType::Corners,
+                },
+            },
+            BoardDiff {
+                squares: vec![4],
+                operation: mark(Digit::D5),
+            },
+            BoardDiff {
+                squares: vec![1, 5],
+                operation: BoardDiffOperation::AddColor {
+                    color: Color::try_from(2).unwrap(),
+                },
+            },
+        ];
+        let after = apply_all(&before, &applied);
+        let undo_diffs = inverse(&before, &after, &applied);
+        assert_eq!(apply_all(&after, &undo_diffs), before);
+        // squares that need the same operation share a diff
+        assert!(undo_diffs.contains(&BoardDiff {
+            squares: vec![0, 1, 2],
+            operation: mark(Digit::D1),
+        }));
+        // and nothing is generated for changes that didn't do anything
+        assert!(inverse(&before, &before, &applied).is_empty());
+    }
+
+    #[test]
+    fn inverse_restores_locks() {
+        let set_number = |squares, digit| BoardDiff {
+            squares,
+            operation: BoardDiffOperation::SetNumber { digit: Some(digit) },
+        };
+        let set_locked = |squares, locked| BoardDiff {
+            squares,
+            operation: BoardDiffOperation::SetLocked { locked },
+        };
+        let before = apply_all(
+            &BoardState::default(),
+            &[
+                set_number(vec![0], Digit::D5),
+                set_number(vec![1], Digit::D3),
+                set_locked(vec![1], true),
+            ],
+        );
+        let applied = vec![
+            set_number(vec![0], Digit::D7),
+            set_locked(vec![0], true),
+            set_locked(vec![1], false),
+            set_number(vec![1], Digit::D8),
+        ];
+        let after = apply_all(&before, &applied);
+        assert!(after.squares()[0].locked && !after.squares()[1].locked);
+        // the numbers can only be put back while their squares are unlocked
+        assert_eq!(
+            apply_all(&after, &inverse(&before, &after, &applied)),
+            before
+        );
+    }
+
+    #[test]
+    fn stacks() {
+        let entry = |revision| UndoEntry {
+            revision,
+            diffs: vec![],
+        };
+        let mut stacks = UndoStacks::default();
+        assert_eq!(stacks.status(), UndoStatus::default());
+        stacks.record(None, entry(1));
+        stacks.record(None, entry(2));
+        assert_eq!(stacks.pop(UndoKind::Undo).unwrap().revision, 2);
+        stacks.record(Some(UndoKind::Undo), entry(3));
+        assert_eq!(
+            stacks.status(),
+            UndoStatus {
+                can_undo: true,
+                can_redo: true
+            }
+        );
+        // a new change clears the redo stack
+        stacks.record(None, entry(4));
+        assert!(stacks.pop(UndoKind::Redo).is_none());
+        for revision in 0..(MAX_UNDO_DEPTH as Revision * 2) {
+            stacks.record(None, entry(revision));
+        }
+        assert_eq!(stacks.undo.len(), MAX_UNDO_DEPTH);
+    }
+}
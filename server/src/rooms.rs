@@ -0,0 +1,340 @@
+//! Lets rooms be created and read over plain HTTP, for scripts that don't want to speak the
+//! realtime protocol.
+//!
+//! - `POST /api/v1/rooms` creates a room from a JSON body of `{"boardState": ...}`, or of
+//!   `{"puzzle": "...", "format": "sdk"}` to import a puzzle in one of the formats in `puzzle`
+//!   (`line` by default). Puzzles whose givens can't be solved are rejected, and ones too sparse
+//!   to check within `limits.max_solver_nodes` get a 422. It returns the room's id along with
+//!   share links containing its keys, whether the puzzle has a unique solution, and a host key.
+//!   Whoever passes that host key when joining over the websocket becomes the room's host. The
+//!   room is written to storage before this returns.
+//! - `GET /api/v1/rooms/{room_id}?key=...` returns the room's current board. Either key works.
+//!   Adding `format=sdk` (or any other format) exports it as text instead.
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reject::Rejection;
+use warp::{Filter, Reply};
+
+use crate::board::BoardState;
+use crate::config::Config;
+use crate::error::SudokuError;
+use crate::global_state::GlobalState;
+use crate::puzzle::{self, PuzzleFormat};
+use crate::realtime::protocol::ShareLinks;
+use crate::realtime::{find_room, InternalErrorReject};
+use crate::room::{RoomId, RoomState, API_SESSION_ID};
+use crate::solver::{self, BudgetExhausted, Solver};
+use crate::storage::{self, Storage};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateRoomRequest {
+    board_state: Option<BoardState>,
+    /// Used instead of `board_state` to import a puzzle.
+    puzzle: Option<String>,
+    format: Option<PuzzleFormat>,
+}
+
+impl CreateRoomRequest {
+    fn into_board(self) -> Result<BoardState, String> {
+        match (self.board_state, self.puzzle) {
+            // check_puzzle validates the board
+            (Some(board_state), None) => Ok(board_state),
+            (None, Some(puzzle)) => {
+                let format = self.format.unwrap_or(PuzzleFormat::Line);
+                puzzle::parse(format, &puzzle).map_err(|err| err.to_string())
+            }
+            _ => Err("Send either a boardState or a puzzle.".to_owned()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateRoomResponse {
+    room_id: String,
+    share_links: ShareLinks,
+    unique_solution: bool,
+    /// Nobody is connected to the room yet, so it doesn't have a host. Whoever joins with this as
+    /// the `host` query parameter becomes the host.
+    host_key: String,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct BoardQuery {
+    key: Option<String>,
+    format: Option<PuzzleFormat>,
+}
+
+pub fn get_filter(
+    config: Arc<Config>,
+    global_state: Arc<GlobalState>,
+    storage: Arc<dyn Storage>,
+) -> BoxedFilter<(impl Reply,)> {
+    let max_body_size = config.limits.max_message_size as u64;
+    let config = warp::any().map(move || config.clone());
+    let global_state = warp::any().map(move || global_state.clone());
+    let storage = warp::any().map(move || storage.clone());
+
+    let create_room = warp::path::end()
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_body_size))
+        .and(warp::body::json())
+        .and(config)
+        .and(global_state.clone())
+        .and(storage.clone())
+        .and_then(create_room);
+    let get_board = warp::path!(RoomId)
+        .and(warp::get())
+        .and(warp::query::<BoardQuery>())
+        .and(global_state)
+        .and(storage)
+        .and_then(
+            |room_id, query: BoardQuery, global_state, storage| async move {
+                let (room_state, _access) =
+                    find_room(&global_state, &storage, room_id, query.key).await?;
+                let rs = room_state.lock().await;
+                let response = match query.format {
+                    Some(format) => puzzle::emit(format, &rs.board).into_response(),
+                    None => warp::reply::json(&rs.board).into_response(),
+                };
+                Result::<_, Rejection>::Ok(response)
+            },
+        );
+
+    warp::path!("api" / "v1" / "rooms" / ..)
+        .and(create_room.or(get_board))
+        .boxed()
+}
+
+async fn create_room(
+    request: CreateRoomRequest,
+    config: Arc<Config>,
+    global_state: Arc<GlobalState>,
+    storage: Arc<dyn Storage>,
+) -> Result<warp::reply::Response, Rejection> {
+    let board = match request.into_board() {
+        Ok(board) => board,
+        Err(message) => return Ok(error_reply(message, StatusCode::BAD_REQUEST)),
+    };
+    // solving can take a while, so keep it off the async executor
+    let max_nodes = config.limits.max_solver_nodes;
+    let checked = task::spawn_blocking(move || {
+        check_puzzle(&board, max_nodes).map(|unique_solution| (board, unique_solution))
+    })
+    .await
+    .map_err(|err| {
+        error!("Failed to check a new puzzle: {}", err);
+        warp::reject::custom(InternalErrorReject)
+    })?;
+    let room_id = RoomId::random();
+    let mut rs = RoomState::new(room_id, &config.limits);
+    let unique_solution = match checked.and_then(|(board, unique_solution)| {
+        rs.set_board(API_SESSION_ID, board)
+            .map(|()| unique_solution)
+    }) {
+        Ok(unique_solution) => unique_solution,
+        Err(err) => {
+            let status = match err {
+                // the puzzle might be fine, but it's too sparse for us to tell
+                SudokuError::UnverifiablePuzzle => StatusCode::UNPROCESSABLE_ENTITY,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            return Ok(error_reply(err.to_string(), status));
+        }
+    };
+    let share_links = ShareLinks::new(&config.public_url, room_id, &rs.access_keys);
+    let host_key = rs.host_key.to_string();
+    let room_state = Arc::new(Mutex::new(rs));
+    global_state.insert_room(room_id, room_state.clone()).await;
+    // write it right away, so that the links we hand out keep working even if we crash
+    storage::writeback_rooms(&*storage, vec![(room_id, room_state)])
+        .await
+        .map_err(|err| {
+            error!("Failed to write back new room {}: {}", room_id, err);
+            warp::reject::custom(InternalErrorReject)
+        })?;
+    info!("Created room {} through the HTTP API", room_id);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&CreateRoomResponse {
+            room_id: room_id.to_string(),
+            share_links,
+            unique_solution,
+            host_key,
+        }),
+        StatusCode::CREATED,
+    )
+    .into_response())
+}
+
+/// Checks that a puzzle is valid and solvable before a room is created for it, and returns whether
+/// its solution is unique. This can take a while, so it should be run with `spawn_blocking`.
+fn check_puzzle(board: &BoardState, max_nodes: usize) -> Result<bool, SudokuError> {
+    board.validate()?;
+    solver::check_solvable(board, max_nodes)?;
+    Solver::from_givens(board)
+        .with_max_nodes(max_nodes)
+        .has_unique_solution()
+        .map_err(|BudgetExhausted| SudokuError::UnverifiablePuzzle)
+}
+
+fn error_reply(message: String, status: StatusCode) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&ErrorResponse { message }), status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LimitsConfig;
+    use crate::storage::MemoryStorage;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn create_and_read_room() {
+        let config = Arc::new(Config::default());
+        let global_state = Arc::new(GlobalState::default());
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let filter = get_filter(config, global_state, storage.clone());
+
+        let mut board = serde_json::to_value(BoardState::default()).unwrap();
+        board["squares"][4]["number"] = json!(5);
+        board["squares"][4]["locked"] = json!(true);
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/rooms")
+            .json(&json!({ "boardState": board }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let room_id: RoomId = created["roomId"].as_str().unwrap().parse().unwrap();
+        let stored = storage
+            .read_room(room_id, &LimitsConfig::default())
+            .await
+            .unwrap()
+            .unwrap();
+        // the host key is persisted, so it still works after a restart
+        assert_eq!(created["hostKey"], stored.host_key.to_string());
+
+        // the key is the last thing in the share link
+        let link = created["shareLinks"]["viewer"].as_str().unwrap();
+        let key = &link[link.rfind('=').unwrap() + 1..];
+        let response = warp::test::request()
+            .path(&format!("/api/v1/rooms/{}?key={}", room_id, key))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let read: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(read, board);
+
+        // without the key, the room might as well not exist
+        let response = warp::test::request()
+            .path(&format!("/api/v1/rooms/{}", room_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn import_and_export_puzzle() {
+        let filter = get_filter(
+            Arc::new(Config::default()),
+            Arc::new(GlobalState::default()),
+            Arc::new(MemoryStorage::default()),
+        );
+        let sdk = "#A Somebody\n53..7....\n6..195...\n.98....6.\n8...6...3\n4..8.3..1\n\
+                   7...2...6\n.6....28.\n...419..5\n....8..79\n";
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/rooms")
+            .json(&json!({ "puzzle": sdk, "format": "sdk" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(created["uniqueSolution"], true);
+        let link = created["shareLinks"]["editor"].as_str().unwrap();
+        let key = &link[link.rfind('=').unwrap() + 1..];
+        let response = warp::test::request()
+            .path(&format!(
+                "/api/v1/rooms/{}?key={}&format=line",
+                created["roomId"].as_str().unwrap(),
+                key
+            ))
+            .reply(&filter)
+            .await;
+        assert_eq!(
+            response.body(),
+            "53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79"
+        );
+
+        // parse errors say where the problem is
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/rooms")
+            .json(&json!({ "puzzle": "53x" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(error["message"], "line 1, column 3: unexpected 'x'");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_boards() {
+        let filter = get_filter(
+            Arc::new(Config::default()),
+            Arc::new(GlobalState::default()),
+            Arc::new(MemoryStorage::default()),
+        );
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/rooms")
+            .json(&json!({ "boardState": { "squares": [] } }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // two 5s in the first row can't be solved
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/rooms")
+            .json(&json!({ "puzzle": format!("55{}", ".".repeat(79)) }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn rejects_puzzles_too_sparse_to_check() {
+        let mut config = Config::default();
+        config.limits.max_solver_nodes = 1;
+        let filter = get_filter(
+            Arc::new(config),
+            Arc::new(GlobalState::default()),
+            Arc::new(MemoryStorage::default()),
+        );
+        // this needs a guess, which is over the limit
+        let sparse =
+            "..........9.....5......6.....94........3....1............2...9.4......6..........";
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/rooms")
+            .json(&json!({ "puzzle": sparse }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
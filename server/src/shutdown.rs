@@ -0,0 +1,115 @@
+use log::{info, warn};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+use crate::global_state::GlobalState;
+
+/// How often to check whether every session has disconnected yet.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tells every session that the server is shutting down, and then waits up to `drain_period` for
+/// them to disconnect. Sessions still connected after that are cut off when the process exits.
+///
+/// New websocket upgrades should already be refused by the time this is called, otherwise the
+/// drain may never finish early.
+pub async fn drain(global_state: &GlobalState, drain_period: Duration) {
+    let rooms = global_state.resident_rooms().await;
+    for (_room_id, room_state) in rooms.iter() {
+        room_state.lock().await.announce_shutdown();
+    }
+
+    let start = Instant::now();
+    let deadline = start + drain_period;
+    loop {
+        let mut remaining = 0;
+        for (_room_id, room_state) in rooms.iter() {
+            remaining += room_state.lock().await.active_session_count();
+        }
+        if remaining == 0 {
+            info!("Every session disconnected after {:?}", start.elapsed());
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "{} session(s) were still connected at the end of the drain period",
+                remaining
+            );
+            return;
+        }
+        time::delay_for(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Stops every resident room from changing, so that sessions that outlasted the drain can't
+/// change anything during or after the final writeback.
+pub async fn stop_changes(global_state: &GlobalState) {
+    for (_room_id, room_state) in global_state.resident_rooms().await {
+        room_state.lock().await.stop_changes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LimitsConfig;
+    use crate::error::SudokuError;
+    use crate::room::{RoomId, RoomNotice, RoomState, SessionRole};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn drain_waits_for_sessions() {
+        let global_state = Arc::new(GlobalState::default());
+        let room_id = RoomId::random();
+        let room_state = Arc::new(Mutex::new(RoomState::new(
+            room_id,
+            &LimitsConfig::default(),
+        )));
+        global_state.insert_room(room_id, room_state.clone()).await;
+        let mut session = room_state
+            .lock()
+            .await
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+
+        let drain = tokio::spawn({
+            let global_state = global_state.clone();
+            async move { drain(&global_state, Duration::from_secs(60)).await }
+        });
+        assert_eq!(
+            session.notice_rx.recv().await.unwrap(),
+            RoomNotice::ShuttingDown
+        );
+        // the realtime API ends (or suspends) the session once the client disconnects
+        room_state
+            .lock()
+            .await
+            .end_session(session.session_id, SessionRole::Player);
+        time::timeout(Duration::from_secs(5), drain)
+            .await
+            .expect("the drain should finish as soon as the session is gone")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_changes_rejects_diffs() {
+        let global_state = GlobalState::default();
+        let room_id = RoomId::random();
+        let room_state = Arc::new(Mutex::new(RoomState::new(
+            room_id,
+            &LimitsConfig::default(),
+        )));
+        global_state.insert_room(room_id, room_state.clone()).await;
+        let _session = room_state
+            .lock()
+            .await
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+
+        stop_changes(&global_state).await;
+        assert!(matches!(
+            room_state.lock().await.apply_diffs(1, 1, None, vec![]),
+            Err(SudokuError::ShuttingDown)
+        ));
+    }
+}
@@ -0,0 +1,449 @@
+//! A backtracking solver for validating boards.
+//!
+//! Candidates for each square are tracked as [DigitBitFlags] per row, column, and box. Before
+//! every guess, the solver fills in naked singles (squares with only one candidate) and hidden
+//! singles (digits with only one place left in a row, column, or box), and then branches on the
+//! empty square with the fewest candidates. That's enough to solve almost every puzzle without
+//! guessing much, but a sparse board can still take an unreasonable number of guesses to rule out,
+//! so every search gives up after a budget of guesses (`limits.max_solver_nodes` in the config) and
+//! returns [BudgetExhausted] instead.
+
+use tokio::task;
+
+use crate::board::{square_houses, BoardState};
+use crate::digit::{Digit, DigitBitFlags};
+use crate::error::SudokuError;
+
+/// A fully filled-in board, indexed the same way as [BoardState::squares].
+pub type Solution = [Digit; 81];
+
+/// The most guesses a single search can make before giving up, unless
+/// [Solver::with_max_nodes] says otherwise. Each one takes a couple of microseconds in a release
+/// build, so this keeps even a hopeless search to tens of milliseconds.
+pub const DEFAULT_MAX_NODES: usize = 20_000;
+
+/// Returned when a search gives up after too many guesses, so it couldn't tell whether the board
+/// has a solution.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BudgetExhausted;
+
+/// Returned by `search_inner` to say whether to keep looking for solutions.
+enum Flow {
+    Continue,
+    Stop,
+}
+
+#[derive(Clone)]
+pub struct Solver {
+    squares: [Option<Digit>; 81],
+    rows: [DigitBitFlags; 9],
+    cols: [DigitBitFlags; 9],
+    boxes: [DigitBitFlags; 9],
+    /// Set if two of the initial digits conflict with each other, in which case there are no
+    /// solutions.
+    contradiction: bool,
+    max_nodes: usize,
+}
+
+impl Solver {
+    pub fn new(squares: [Option<Digit>; 81]) -> Self {
+        let mut solver = Solver {
+            squares: [None; 81],
+            rows: Default::default(),
+            cols: Default::default(),
+            boxes: Default::default(),
+            contradiction: false,
+            max_nodes: DEFAULT_MAX_NODES,
+        };
+        for (idx, digit) in squares.iter().enumerate() {
+            if let Some(digit) = *digit {
+                if solver.candidates(idx).contains(digit) {
+                    solver.place(idx, digit);
+                } else {
+                    solver.contradiction = true;
+                }
+            }
+        }
+        solver
+    }
+
+    /// Sets how many guesses each search can make before giving up.
+    pub fn with_max_nodes(mut self, max_nodes: usize) -> Self {
+        self.max_nodes = max_nodes;
+        self
+    }
+
+    /// Builds a solver from only the givens (the locked squares) of the board, ignoring any
+    /// digits that players have entered.
+    pub fn from_givens(board: &BoardState) -> Self {
+        let mut squares = [None; 81];
+        for (idx, sq) in board.squares().iter().enumerate().take(81) {
+            if sq.locked {
+                squares[idx] = sq.number;
+            }
+        }
+        Self::new(squares)
+    }
+
+    /// Builds a solver from every digit currently on the board, including ones entered by
+    /// players.
+    #[cfg(test)]
+    pub fn from_numbers(board: &BoardState) -> Self {
+        let mut squares = [None; 81];
+        for (idx, sq) in board.squares().iter().enumerate().take(81) {
+            squares[idx] = sq.number;
+        }
+        Self::new(squares)
+    }
+
+    /// Returns a solution if one exists. If there are multiple solutions, which one is returned
+    /// is unspecified.
+    pub fn solve(&self) -> Result<Option<Solution>, BudgetExhausted> {
+        let mut solution = None;
+        self.search(&mut |solver| {
+            solution = Some(solver.to_solution());
+            false
+        })?;
+        Ok(solution)
+    }
+
+    /// Counts the number of solutions, stopping early once `limit` is reached.
+    pub fn count_solutions(&self, limit: usize) -> Result<usize, BudgetExhausted> {
+        let mut count = 0;
+        if limit > 0 {
+            self.search(&mut |_| {
+                count += 1;
+                count < limit
+            })?;
+        }
+        Ok(count)
+    }
+
+    pub fn has_unique_solution(&self) -> Result<bool, BudgetExhausted> {
+        Ok(self.count_solutions(2)? == 1)
+    }
+
+    fn candidates(&self, idx: usize) -> DigitBitFlags {
+        let (row, col, bx) = square_houses(idx);
+        DigitBitFlags::all()
+            .difference(self.rows[row])
+            .difference(self.cols[col])
+            .difference(self.boxes[bx])
+    }
+
+    fn place(&mut self, idx: usize, digit: Digit) {
+        let (row, col, bx) = square_houses(idx);
+        self.squares[idx] = Some(digit);
+        self.rows[row].insert(digit);
+        self.cols[col].insert(digit);
+        self.boxes[bx].insert(digit);
+    }
+
+    fn to_solution(&self) -> Solution {
+        let mut solution = [Digit::D1; 81];
+        for (dst, src) in solution.iter_mut().zip(self.squares.iter()) {
+            *dst = src.expect("to_solution should only be called on a filled board");
+        }
+        solution
+    }
+
+    /// Fills in every naked and hidden single, repeating until there are none left. Returns false
+    /// if that leaves a square with no candidates, or a digit with no place in some house.
+    fn propagate(&mut self) -> bool {
+        loop {
+            let mut progress = false;
+            for idx in 0..81 {
+                if self.squares[idx].is_some() {
+                    continue;
+                }
+                let candidates = self.candidates(idx);
+                if candidates.is_empty() {
+                    return false;
+                }
+                if candidates.len() == 1 {
+                    self.place(idx, candidates.iter().next().unwrap());
+                    progress = true;
+                }
+            }
+            for house in 0..27 {
+                let squares = house_squares(house);
+                let placed = match house {
+                    0..=8 => self.rows[house],
+                    9..=17 => self.cols[house - 9],
+                    _ => self.boxes[house - 18],
+                };
+                for digit in DigitBitFlags::all().difference(placed).iter() {
+                    let mut places = squares.iter().copied().filter(|&idx| {
+                        self.squares[idx].is_none() && self.candidates(idx).contains(digit)
+                    });
+                    match (places.next(), places.next()) {
+                        (None, _) => return false,
+                        (Some(idx), None) => {
+                            self.place(idx, digit);
+                            progress = true;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if !progress {
+                return true;
+            }
+        }
+    }
+
+    /// Calls `on_solution` for every solution until it returns false.
+    fn search<F>(&self, on_solution: &mut F) -> Result<(), BudgetExhausted>
+    where
+        F: FnMut(&Solver) -> bool,
+    {
+        if self.contradiction {
+            return Ok(());
+        }
+        let mut nodes_left = self.max_nodes;
+        self.clone().search_inner(on_solution, &mut nodes_left)?;
+        Ok(())
+    }
+
+    fn search_inner<F>(
+        mut self,
+        on_solution: &mut F,
+        nodes_left: &mut usize,
+    ) -> Result<Flow, BudgetExhausted>
+    where
+        F: FnMut(&Solver) -> bool,
+    {
+        if *nodes_left == 0 {
+            return Err(BudgetExhausted);
+        }
+        *nodes_left -= 1;
+        if !self.propagate() {
+            return Ok(Flow::Continue);
+        }
+
+        // propagating leaves at least two candidates in every empty square, so guess at the one
+        // with the fewest
+        let best = (0..81)
+            .filter(|&idx| self.squares[idx].is_none())
+            .map(|idx| (idx, self.candidates(idx)))
+            .min_by_key(|(_, candidates)| candidates.len());
+        match best {
+            None if on_solution(&self) => Ok(Flow::Continue),
+            None => Ok(Flow::Stop),
+            Some((idx, candidates)) => {
+                for digit in candidates.iter() {
+                    let mut guess = self.clone();
+                    guess.place(idx, digit);
+                    if let Flow::Stop = guess.search_inner(on_solution, nodes_left)? {
+                        return Ok(Flow::Stop);
+                    }
+                }
+                Ok(Flow::Continue)
+            }
+        }
+    }
+}
+
+/// Returns the squares in a house: rows are numbered 0-8, columns 9-17, and boxes 18-26.
+fn house_squares(house: usize) -> [usize; 9] {
+    let mut squares = [0; 9];
+    for (i, square) in squares.iter_mut().enumerate() {
+        *square = match house {
+            0..=8 => house * 9 + i,
+            9..=17 => i * 9 + (house - 9),
+            _ => {
+                let bx = house - 18;
+                (bx / 3 * 3 + i / 3) * 9 + bx % 3 * 3 + i % 3
+            }
+        };
+    }
+    squares
+}
+
+/// Checks that the board's givens have at least one solution, so that nobody sits down to solve a
+/// puzzle that can't be finished.
+///
+/// This can take a while for sparse boards, so async code should use [spawn_check_solvable].
+pub fn check_solvable(board: &BoardState, max_nodes: usize) -> Result<(), SudokuError> {
+    match Solver::from_givens(board).with_max_nodes(max_nodes).solve() {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(SudokuError::UnsolvablePuzzle),
+        Err(BudgetExhausted) => Err(SudokuError::UnverifiablePuzzle),
+    }
+}
+
+/// Runs [check_solvable] on a blocking thread, so that it doesn't hold up the async executor, and
+/// hands the board back if it's solvable.
+pub async fn spawn_check_solvable(
+    board: BoardState,
+    max_nodes: usize,
+) -> Result<BoardState, SudokuError> {
+    task::spawn_blocking(move || check_solvable(&board, max_nodes).map(|()| board))
+        .await
+        .map_err(|err| SudokuError::Internal(Box::new(err)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::board::{BoardDiff, BoardDiffOperation};
+
+    fn parse_grid(s: &str) -> [Option<Digit>; 81] {
+        let mut grid = [None; 81];
+        for (idx, ch) in s.chars().enumerate() {
+            grid[idx] = ch.to_digit(10).and_then(|d| Digit::try_from(d as u8).ok());
+        }
+        grid
+    }
+
+    fn assert_valid_solution(givens: &[Option<Digit>; 81], solution: &Solution) {
+        for (idx, given) in givens.iter().enumerate() {
+            if let Some(given) = given {
+                assert_eq!(solution[idx], *given);
+            }
+        }
+        let check = Solver::new({
+            let mut grid = [None; 81];
+            for (dst, src) in grid.iter_mut().zip(solution.iter()) {
+                *dst = Some(*src);
+            }
+            grid
+        });
+        assert!(!check.contradiction);
+    }
+
+    // a puzzle with a single solution
+    const UNIQUE: &str =
+        "53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79";
+    // a puzzle that's notoriously hard for humans
+    const HARD: &str =
+        "8..........36......7..9.2...5...7.......457.....1...3...1....68..85...1..9....4..";
+
+    // very few givens, which don't conflict but make for a huge search space
+    const SPARSE: &str =
+        "..........9.....5......6.....94........3....1............2...9.4......6..........";
+
+    #[test]
+    fn solve_unique() {
+        let grid = parse_grid(UNIQUE);
+        let solver = Solver::new(grid);
+        let solution = solver.solve().unwrap().unwrap();
+        assert_valid_solution(&grid, &solution);
+        assert_eq!(solver.has_unique_solution(), Ok(true));
+    }
+
+    #[test]
+    fn solve_hard() {
+        let grid = parse_grid(HARD);
+        let solver = Solver::new(grid);
+        assert_valid_solution(&grid, &solver.solve().unwrap().unwrap());
+        assert_eq!(solver.has_unique_solution(), Ok(true));
+    }
+
+    #[test]
+    fn solve_sparse() {
+        // plain backtracking took minutes on this one, since it has almost nothing to go on
+        let grid = parse_grid(SPARSE);
+        let solver = Solver::new(grid);
+        assert_valid_solution(&grid, &solver.solve().unwrap().unwrap());
+    }
+
+    #[test]
+    fn budget_exhausted() {
+        // there are far too many solutions to count them all
+        let solver = Solver::new([None; 81]);
+        assert_eq!(solver.count_solutions(usize::MAX), Err(BudgetExhausted));
+    }
+
+    #[test]
+    fn count_solutions_respects_limit() {
+        let solver = Solver::new([None; 81]);
+        assert_eq!(solver.count_solutions(0), Ok(0));
+        assert_eq!(solver.count_solutions(5), Ok(5));
+        assert_eq!(solver.has_unique_solution(), Ok(false));
+    }
+
+    #[test]
+    fn conflicting_givens() {
+        let mut grid = parse_grid(UNIQUE);
+        // there's already a 5 in the first row
+        grid[2] = Some(Digit::D5);
+        let solver = Solver::new(grid);
+        assert_eq!(solver.solve(), Ok(None));
+        assert_eq!(solver.count_solutions(10), Ok(0));
+
+        let mut bs = BoardState::default();
+        for idx in [0, 1].iter() {
+            bs.apply(&BoardDiff {
+                squares: vec![*idx],
+                operation: BoardDiffOperation::SetNumber {
+                    digit: Some(Digit::D5),
+                },
+            })
+            .unwrap();
+        }
+        // the conflicting digits aren't givens until they're locked
+        assert!(check_solvable(&bs, DEFAULT_MAX_NODES).is_ok());
+        bs.apply(&BoardDiff {
+            squares: vec![0, 1],
+            operation: BoardDiffOperation::SetLocked { locked: true },
+        })
+        .unwrap();
+        assert!(matches!(
+            check_solvable(&bs, DEFAULT_MAX_NODES),
+            Err(SudokuError::UnsolvablePuzzle)
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_solvable_off_thread() {
+        let mut bs = BoardState::default();
+        bs.apply(&BoardDiff {
+            squares: vec![0],
+            operation: BoardDiffOperation::SetNumber {
+                digit: Some(Digit::D5),
+            },
+        })
+        .unwrap();
+        assert_eq!(
+            spawn_check_solvable(bs.clone(), DEFAULT_MAX_NODES)
+                .await
+                .unwrap(),
+            bs
+        );
+
+        bs.apply(&BoardDiff {
+            squares: vec![1],
+            operation: BoardDiffOperation::SetNumber {
+                digit: Some(Digit::D5),
+            },
+        })
+        .unwrap();
+        bs.apply(&BoardDiff {
+            squares: vec![0, 1],
+            operation: BoardDiffOperation::SetLocked { locked: true },
+        })
+        .unwrap();
+        assert!(matches!(
+            spawn_check_solvable(bs, DEFAULT_MAX_NODES).await,
+            Err(SudokuError::UnsolvablePuzzle)
+        ));
+    }
+
+    #[test]
+    fn from_board_state() {
+        let mut bs = BoardState::default();
+        for (idx, digit) in parse_grid(UNIQUE).iter().enumerate() {
+            bs.apply(&BoardDiff {
+                squares: vec![idx as u8],
+                operation: BoardDiffOperation::SetNumber { digit: *digit },
+            })
+            .unwrap();
+        }
+        assert_eq!(Solver::from_numbers(&bs).has_unique_solution(), Ok(true));
+        // nothing is locked, so there are no givens
+        assert_eq!(Solver::from_givens(&bs).has_unique_solution(), Ok(false));
+    }
+}
@@ -1,10 +0,0 @@
-#[cfg(feature = "sql")]
-mod real;
-#[cfg(not(feature = "sql"))]
-mod stub;
-
-#[cfg(feature = "sql")]
-pub use crate::sql::real::*;
-
-#[cfg(not(feature = "sql"))]
-pub use crate::sql::stub::*;
@@ -0,0 +1,79 @@
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::config::LimitsConfig;
+use crate::room::{EventSeq, RoomEvent, RoomId, RoomState};
+use crate::storage::{RoomRecord, Storage, StorageError};
+
+/// Keeps written rooms in a `HashMap`. Rooms evicted from `GlobalState` can still be read back,
+/// but everything is lost when the server exits.
+#[derive(Default)]
+pub struct MemoryStorage {
+    // nothing awaits while holding this, so a regular mutex is fine
+    rooms: Mutex<HashMap<RoomId, RoomRecord>>,
+}
+
+impl Storage for MemoryStorage {
+    fn read_room<'a>(
+        &'a self,
+        room_id: RoomId,
+        limits: &'a LimitsConfig,
+    ) -> BoxFuture<'a, Result<Option<RoomState>, StorageError>> {
+        let rooms = self.rooms.lock().expect("memory storage lock was poisoned");
+        let room = rooms.get(&room_id).map(|record| {
+            let mut room = RoomState::new(room_id, limits);
+            room.board = record.board.clone();
+            room.started_at = record.started_at;
+            room.completion = record.completion;
+            room.access_keys = record.access_keys;
+            room.locked = record.locked;
+            room.host_key = record.host_key;
+            room.closed = record.closed;
+            room.resume_event_log(record.revision, record.events.len() as EventSeq);
+            room
+        });
+        future::ready(Ok(room)).boxed()
+    }
+
+    fn write_rooms<'a>(
+        &'a self,
+        records: &'a [RoomRecord],
+    ) -> BoxFuture<'a, Result<Vec<Result<(), StorageError>>, StorageError>> {
+        let mut rooms = self.rooms.lock().expect("memory storage lock was poisoned");
+        for record in records {
+            let mut events = match rooms.remove(&record.room_id) {
+                Some(stored) => stored.events,
+                None => Vec::new(),
+            };
+            // Events from a failed writeback can be written again. They're identical, so only
+            // append the ones that aren't stored yet, like the SQL backends.
+            let stored = events.len() as EventSeq;
+            events.extend(
+                record
+                    .events
+                    .iter()
+                    .filter(|event| event.seq >= stored)
+                    .cloned(),
+            );
+            rooms.insert(
+                record.room_id,
+                RoomRecord {
+                    events,
+                    ..record.clone()
+                },
+            );
+        }
+        future::ready(Ok(records.iter().map(|_| Ok(())).collect())).boxed()
+    }
+
+    fn read_events(&self, room_id: RoomId) -> BoxFuture<'_, Result<Vec<RoomEvent>, StorageError>> {
+        let rooms = self.rooms.lock().expect("memory storage lock was poisoned");
+        let events = rooms
+            .get(&room_id)
+            .map(|record| record.events.clone())
+            .unwrap_or_default();
+        future::ready(Ok(events)).boxed()
+    }
+}
@@ -0,0 +1,526 @@
+//! Persistence for rooms and their event logs.
+//!
+//! Rooms live in memory while they're in use, and are periodically written back to a [Storage]
+//! backend. The backend is picked by the scheme of `database.uri`:
+//!
+//! - `sqlite:` stores everything in a SQLite database (needs the "sql" feature).
+//! - `postgres:` or `postgresql:` uses a Postgres database (needs the "postgres" feature).
+//! - `memory:` keeps written rooms in memory, so nothing survives a restart. This is useful for
+//!   tests and development, and is the only backend available without the "sql" feature.
+
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "sql")]
+mod sqlite;
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use log::{error, info};
+use once_cell::sync::OnceCell;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::board::BoardState;
+use crate::config::{DatabaseConfig, LimitsConfig};
+use crate::global_state::GlobalState;
+use crate::metrics;
+use crate::room::{AccessKeys, Completion, HostKey, Revision, RoomEvent, RoomId, RoomState};
+
+pub use crate::storage::memory::MemoryStorage;
+#[cfg(feature = "postgres")]
+pub use crate::storage::postgres::PostgresStorage;
+#[cfg(feature = "sql")]
+pub use crate::storage::sqlite::SqliteStorage;
+
+/// A place to write rooms back to, and read them from once they've been evicted from memory.
+///
+/// These return boxed futures so that the backend can be chosen at runtime.
+pub trait Storage: Send + Sync {
+    /// Reads a room that was previously written, or returns `None` if there isn't one. `limits`
+    /// sizes the room's queues.
+    fn read_room<'a>(
+        &'a self,
+        room_id: RoomId,
+        limits: &'a LimitsConfig,
+    ) -> BoxFuture<'a, Result<Option<RoomState>, StorageError>>;
+
+    /// Writes rooms and appends their new events to their logs. Backends should do this in a
+    /// single transaction where they can.
+    ///
+    /// The outer error means nothing was written. Otherwise, there's one result per record, since
+    /// one bad room shouldn't stop the others from being written.
+    fn write_rooms<'a>(
+        &'a self,
+        records: &'a [RoomRecord],
+    ) -> BoxFuture<'a, Result<Vec<Result<(), StorageError>>, StorageError>>;
+
+    /// Reads a room's entire event log, oldest first. Pass it to `room::replay` to rebuild the
+    /// room's board at any point in its history.
+    fn read_events(&self, room_id: RoomId) -> BoxFuture<'_, Result<Vec<RoomEvent>, StorageError>>;
+}
+
+/// Everything about a room that gets persisted, copied out of its `RoomState`.
+#[derive(Clone, Debug)]
+pub struct RoomRecord {
+    pub room_id: RoomId,
+    pub board: BoardState,
+    pub started_at: DateTime<Utc>,
+    pub completion: Option<Completion>,
+    pub access_keys: AccessKeys,
+    pub revision: Revision,
+    pub locked: bool,
+    pub host_key: HostKey,
+    pub closed: bool,
+    /// Events logged since the room was last written, oldest first.
+    pub events: Vec<RoomEvent>,
+}
+
+impl RoomRecord {
+    /// Copies the room's state, taking its pending events with it.
+    fn take(rs: &mut RoomState) -> Self {
+        RoomRecord {
+            room_id: rs.room_id,
+            board: rs.board.clone(),
+            started_at: rs.started_at,
+            completion: rs.completion,
+            access_keys: rs.access_keys,
+            revision: rs.revision(),
+            locked: rs.locked,
+            host_key: rs.host_key,
+            closed: rs.closed,
+            events: rs.take_pending_events(),
+        }
+    }
+}
+
+/// Storage that's connected after the server starts listening, so that health checks can be
+/// answered while migrations run. Until `set` is called, everything fails with `NotReady`.
+#[derive(Default)]
+pub struct DeferredStorage(OnceCell<Arc<dyn Storage>>);
+
+impl DeferredStorage {
+    pub fn set(&self, storage: Arc<dyn Storage>) {
+        assert!(
+            self.0.set(storage).is_ok(),
+            "deferred storage can only be set once"
+        );
+    }
+
+    fn get(&self) -> Result<&Arc<dyn Storage>, StorageError> {
+        self.0.get().ok_or(StorageError::NotReady)
+    }
+}
+
+impl Storage for DeferredStorage {
+    fn read_room<'a>(
+        &'a self,
+        room_id: RoomId,
+        limits: &'a LimitsConfig,
+    ) -> BoxFuture<'a, Result<Option<RoomState>, StorageError>> {
+        match self.get() {
+            Ok(storage) => storage.read_room(room_id, limits),
+            Err(err) => future::ready(Err(err)).boxed(),
+        }
+    }
+
+    fn write_rooms<'a>(
+        &'a self,
+        records: &'a [RoomRecord],
+    ) -> BoxFuture<'a, Result<Vec<Result<(), StorageError>>, StorageError>> {
+        match self.get() {
+            Ok(storage) => storage.write_rooms(records),
+            Err(err) => future::ready(Err(err)).boxed(),
+        }
+    }
+
+    fn read_events(&self, room_id: RoomId) -> BoxFuture<'_, Result<Vec<RoomEvent>, StorageError>> {
+        match self.get() {
+            Ok(storage) => storage.read_events(room_id),
+            Err(err) => future::ready(Err(err)).boxed(),
+        }
+    }
+}
+
+/// Opens the backend named by `config.uri`, running any migrations it needs.
+pub async fn connect(config: &DatabaseConfig) -> Result<Arc<dyn Storage>, StorageError> {
+    let uri = &config.uri;
+    match uri.split(':').next().unwrap_or_default() {
+        "memory" => {
+            info!("Using in-memory storage, rooms won't persist across server restarts");
+            Ok(Arc::new(MemoryStorage::default()))
+        }
+        #[cfg(feature = "sql")]
+        "sqlite" => Ok(Arc::new(SqliteStorage::connect(uri).await?)),
+        #[cfg(feature = "postgres")]
+        "postgres" | "postgresql" => Ok(Arc::new(PostgresStorage::connect(uri).await?)),
+        _ => Err(StorageError::UnsupportedUri(uri.clone())),
+    }
+}
+
+/// Writes every dirty room back to storage, returning the number of rooms written.
+///
+/// If a room fails to write (or the whole transaction fails to commit), its dirty flag is set
+/// again so that the next writeback retries it.
+pub async fn writeback(
+    storage: &dyn Storage,
+    global_state: &GlobalState,
+) -> Result<usize, StorageError> {
+    writeback_rooms(storage, global_state.get_dirty_rooms().await).await
+}
+
+/// Like `writeback`, but only writes the given rooms. The rooms are written regardless of their
+/// dirty flag.
+pub async fn writeback_rooms(
+    storage: &dyn Storage,
+    rooms: Vec<(RoomId, Arc<Mutex<RoomState>>)>,
+) -> Result<usize, StorageError> {
+    let rooms: Vec<_> = stream::iter(rooms)
+        .map(|(_room_id, rs_mutex)| async move {
+            let mut rs = rs_mutex.lock().await;
+            // Clear the dirty flag since we'll write this to storage soon. Yes, we're
+            // acknowledging a write before it happens, but this whole service is best-effort
+            // so it doesn't really matter.
+            rs.dirty = false;
+            let record = RoomRecord::take(&mut rs);
+            drop(rs);
+            (rs_mutex, record)
+        })
+        // Try to do a few reads concurrently to avoid hanging on a single locked room mutex
+        .buffer_unordered(5)
+        .collect()
+        .await;
+    let (rs_mutexes, records): (Vec<_>, Vec<_>) = rooms.into_iter().unzip();
+
+    let timer = metrics::WRITEBACK_DURATION.start_timer();
+    let results = storage.write_rooms(&records).await;
+    timer.observe_duration();
+    let results = match results {
+        Ok(results) => results,
+        Err(err) => {
+            metrics::WRITEBACK_FAILURES.inc_by(records.len() as u64);
+            for (rs_mutex, record) in rs_mutexes.into_iter().zip(records) {
+                let mut rs = rs_mutex.lock().await;
+                rs.dirty = true;
+                rs.restore_pending_events(record.events);
+            }
+            return Err(err);
+        }
+    };
+    let mut written = 0;
+    for ((rs_mutex, record), result) in rs_mutexes.into_iter().zip(records).zip(results) {
+        if let Err(err) = result {
+            error!(
+                "Failed to write room {} back to storage: {}",
+                record.room_id, err
+            );
+            metrics::WRITEBACK_FAILURES.inc();
+            let mut rs = rs_mutex.lock().await;
+            rs.dirty = true;
+            rs.restore_pending_events(record.events);
+        } else {
+            written += 1;
+        }
+    }
+    Ok(written)
+}
+
+/// Like `Storage::read_events`, but also includes the room's events that are still only in
+/// memory, without writing anything.
+///
+/// Events that a concurrent writeback is still in the middle of writing are in neither place, so
+/// the in-memory events are only added if they directly follow the stored ones. Otherwise the log
+/// is cut short rather than left with a gap.
+pub async fn read_room_events(
+    storage: &dyn Storage,
+    room_id: RoomId,
+    room_state: Arc<Mutex<RoomState>>,
+) -> Result<Vec<RoomEvent>, StorageError> {
+    // copy these first, so that anything a writeback takes afterwards is already in the copy
+    let pending = room_state.lock().await.pending_events().to_vec();
+    let mut events = storage.read_events(room_id).await?;
+    let mut next_seq = events.last().map_or(0, |event| event.seq + 1);
+    for event in pending {
+        if event.seq > next_seq {
+            break;
+        }
+        if event.seq == next_seq {
+            events.push(event);
+            next_seq += 1;
+        }
+    }
+    Ok(events)
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    #[cfg(feature = "sql")]
+    Deserialization(&'static str),
+    #[cfg(feature = "sql")]
+    Sqlx(sqlx::Error),
+    /// `DeferredStorage` hasn't been connected yet.
+    NotReady,
+    /// The database uri's scheme doesn't match any backend compiled into this binary.
+    UnsupportedUri(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "sql")]
+            Self::Deserialization(err) => write!(f, "{}", err),
+            #[cfg(feature = "sql")]
+            Self::Sqlx(err) => write!(f, "{}", err),
+            Self::NotReady => write!(f, "Storage hasn't been connected yet"),
+            Self::UnsupportedUri(uri) => write!(
+                f,
+                "No storage backend for database uri {:?}. Supported schemes are: {}",
+                uri,
+                supported_schemes().join(", ")
+            ),
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+#[cfg(feature = "sql")]
+impl From<sqlx::Error> for StorageError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Sqlx(err)
+    }
+}
+
+fn supported_schemes() -> Vec<&'static str> {
+    let mut schemes = vec!["memory:"];
+    if cfg!(feature = "sql") {
+        schemes.push("sqlite:");
+    }
+    if cfg!(feature = "postgres") {
+        schemes.extend_from_slice(&["postgres:", "postgresql:"]);
+    }
+    schemes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::{BoardDiff, BoardDiffOperation};
+    use crate::config::SetBoardPermission;
+    use crate::digit::Digit;
+    use crate::error::SudokuError;
+    use crate::room::{replay, SessionRole};
+
+    /// Storage that fails to write any room that has a number in its first square.
+    #[cfg(feature = "sql")]
+    struct PickyStorage(MemoryStorage);
+
+    #[cfg(feature = "sql")]
+    impl Storage for PickyStorage {
+        fn read_room<'a>(
+            &'a self,
+            room_id: RoomId,
+            limits: &'a LimitsConfig,
+        ) -> BoxFuture<'a, Result<Option<RoomState>, StorageError>> {
+            self.0.read_room(room_id, limits)
+        }
+
+        fn write_rooms<'a>(
+            &'a self,
+            records: &'a [RoomRecord],
+        ) -> BoxFuture<'a, Result<Vec<Result<(), StorageError>>, StorageError>> {
+            async move {
+                let (bad, good): (Vec<_>, Vec<_>) = records
+                    .iter()
+                    .cloned()
+                    .partition(|r| r.board.squares()[0].number.is_some());
+                self.0.write_rooms(&good).await?;
+                Ok(records
+                    .iter()
+                    .map(|r| {
+                        if bad.iter().any(|b| b.room_id == r.room_id) {
+                            Err(StorageError::Deserialization("picky"))
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .collect())
+            }
+            .boxed()
+        }
+
+        fn read_events(
+            &self,
+            room_id: RoomId,
+        ) -> BoxFuture<'_, Result<Vec<RoomEvent>, StorageError>> {
+            self.0.read_events(room_id)
+        }
+    }
+
+    fn set_number(idx: u8) -> BoardDiff {
+        BoardDiff {
+            squares: vec![idx],
+            operation: BoardDiffOperation::SetNumber {
+                digit: Some(Digit::D3),
+            },
+        }
+    }
+
+    /// Checks that a backend round-trips rooms and event logs. Every backend's tests call this.
+    pub async fn check_storage(storage: &dyn Storage) {
+        let room_id = RoomId::random();
+        assert!(storage
+            .read_room(room_id, &LimitsConfig::default())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(storage.read_events(room_id).await.unwrap().is_empty());
+
+        let mut rs = RoomState::new(room_id, &LimitsConfig::default());
+        rs.apply_diffs(1, 1, None, vec![set_number(4)]).unwrap();
+        rs.completion = Some(Completion {
+            solve_time_ms: 1234,
+            solved_by: 1,
+        });
+        let rs_mutex = Arc::new(Mutex::new(rs));
+        assert_eq!(
+            writeback_rooms(storage, vec![(room_id, rs_mutex.clone())])
+                .await
+                .unwrap(),
+            1
+        );
+
+        // writing again only appends the new events
+        rs_mutex
+            .lock()
+            .await
+            .apply_diffs(2, 1, None, vec![set_number(5)])
+            .unwrap();
+        rs_mutex.lock().await.set_locked(true);
+        rs_mutex.lock().await.closed = true;
+        // reading the log includes the events that haven't been written yet, without writing
+        let events = read_room_events(storage, room_id, rs_mutex.clone())
+            .await
+            .unwrap();
+        assert_eq!(events.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(events[1].session_id, 2);
+        assert_eq!(storage.read_events(room_id).await.unwrap().len(), 1);
+        assert!(rs_mutex.lock().await.dirty);
+
+        writeback_rooms(storage, vec![(room_id, rs_mutex.clone())])
+            .await
+            .unwrap();
+        let rs = rs_mutex.lock().await;
+        assert_eq!(storage.read_events(room_id).await.unwrap().len(), 2);
+        assert_eq!(replay(&events).unwrap(), rs.board);
+
+        let read = storage
+            .read_room(room_id, &LimitsConfig::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.board, rs.board);
+        assert_eq!(read.access_keys, rs.access_keys);
+        assert_eq!(read.completion, rs.completion);
+        assert!(read.locked);
+        assert_eq!(read.host_key, rs.host_key);
+        assert!(read.closed);
+        assert_eq!(
+            read.started_at.timestamp_millis(),
+            rs.started_at.timestamp_millis()
+        );
+        assert_eq!(read.revision(), rs.revision());
+    }
+
+    #[tokio::test]
+    async fn memory_storage() {
+        check_storage(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn host_key_survives_restart() {
+        let storage = MemoryStorage::default();
+        let room_id = RoomId::random();
+        let rs = RoomState::new(room_id, &LimitsConfig::default());
+        let host_key = rs.host_key;
+        writeback_rooms(&storage, vec![(room_id, Arc::new(Mutex::new(rs)))])
+            .await
+            .unwrap();
+
+        // sessions don't survive a restart, so nobody is the host until the key is presented
+        let mut read = storage
+            .read_room(room_id, &LimitsConfig::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let session = read
+            .new_session(SessionRole::Player, Default::default())
+            .unwrap();
+        assert!(matches!(
+            read.check_can_set_board(session.session_id, SetBoardPermission::Host),
+            Err(SudokuError::NotHost)
+        ));
+        assert!(!read.claim_host(session.session_id, HostKey::random()));
+        assert!(read.claim_host(session.session_id, host_key));
+        assert!(read
+            .check_can_set_board(session.session_id, SetBoardPermission::Host)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn deferred_storage() {
+        let storage = DeferredStorage::default();
+        assert!(matches!(
+            storage.read_events(RoomId::random()).await,
+            Err(StorageError::NotReady)
+        ));
+        storage.set(Arc::new(MemoryStorage::default()));
+        check_storage(&storage).await;
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "sql")]
+    async fn failed_rooms_stay_dirty() {
+        let storage = PickyStorage(MemoryStorage::default());
+        let good_id = RoomId::random();
+        let bad_id = RoomId::random();
+        let good = Arc::new(Mutex::new(RoomState::new(
+            good_id,
+            &LimitsConfig::default(),
+        )));
+        let bad = Arc::new(Mutex::new(RoomState::new(bad_id, &LimitsConfig::default())));
+        bad.lock()
+            .await
+            .apply_diffs(1, 1, None, vec![set_number(0)])
+            .unwrap();
+
+        let written = writeback_rooms(
+            &storage,
+            vec![(good_id, good.clone()), (bad_id, bad.clone())],
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, 1);
+        assert!(!good.lock().await.dirty);
+        let mut bad = bad.lock().await;
+        assert!(bad.dirty);
+        // the events are kept so they're written next time
+        assert_eq!(bad.take_pending_events().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn connect_by_scheme() {
+        let config = |uri: &str| DatabaseConfig {
+            uri: uri.to_owned(),
+            ..Default::default()
+        };
+        assert!(connect(&config("memory:")).await.is_ok());
+        assert!(matches!(
+            connect(&config("mysql://localhost/sudoku")).await,
+            Err(StorageError::UnsupportedUri(_))
+        ));
+    }
+}
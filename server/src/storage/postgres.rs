@@ -0,0 +1,238 @@
+use chrono::{TimeZone, Utc};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use sqlx::Row;
+use std::convert::TryInto;
+
+use crate::config::LimitsConfig;
+use crate::room::{
+    AccessKeys, ClientSyncId, Completion, EventSeq, Revision, RoomEvent, RoomEventKind, RoomId,
+    RoomState, SessionId,
+};
+use crate::storage::{RoomRecord, Storage, StorageError};
+
+type Database = sqlx::Postgres;
+
+/// Stores rooms in Postgres, using the same layout as `SqliteStorage`.
+///
+/// `sqlx-data.json` only covers the SQLite queries, so these queries aren't checked at compile
+/// time. `tests::postgres_storage` covers them instead.
+pub struct PostgresStorage {
+    pool: sqlx::Pool<Database>,
+}
+
+impl PostgresStorage {
+    pub async fn connect(uri: &str) -> Result<Self, StorageError> {
+        let pool = sqlx::Pool::connect(uri).await?;
+        sqlx::migrate!("./migrations/postgres")
+            .run(&pool)
+            .await
+            .map_err(sqlx::Error::from)?;
+        Ok(PostgresStorage { pool })
+    }
+
+    async fn write_rooms_inner(
+        &self,
+        records: &[RoomRecord],
+    ) -> Result<Vec<Result<(), StorageError>>, StorageError> {
+        let mut tx = self.pool.begin().await?;
+        let mut results = Vec::with_capacity(records.len());
+        for record in records {
+            // Unlike SQLite, any failed statement aborts the whole transaction in Postgres, so
+            // each room gets a savepoint that it can be rolled back to.
+            let mut savepoint = sqlx::Connection::begin(&mut *tx).await?;
+            match write_room(&mut savepoint, record).await {
+                Ok(()) => {
+                    savepoint.commit().await?;
+                    results.push(Ok(()));
+                }
+                Err(err) => {
+                    savepoint.rollback().await?;
+                    results.push(Err(err.into()));
+                }
+            }
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+
+    async fn read_room_inner(
+        &self,
+        room_id: RoomId,
+        limits: &LimitsConfig,
+    ) -> Result<Option<RoomState>, StorageError> {
+        let room_id_blob = &u128::from(room_id).to_le_bytes()[..];
+        let row = match sqlx::query(
+            "select board, started_at, solve_time_ms, solved_by, editor_key, viewer_key, revision, \
+            locked, host_key, closed, keyless from rooms where id = $1",
+        )
+        .bind(room_id_blob)
+        .fetch_optional(&self.pool)
+        .await?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let board: Vec<u8> = row.try_get("board")?;
+        let mut room = RoomState::sql_deserialize(room_id, &board, limits)
+            .map_err(StorageError::Deserialization)?;
+        room.started_at = Utc.timestamp_millis(row.try_get("started_at")?);
+        let solve_time_ms: Option<i64> = row.try_get("solve_time_ms")?;
+        let solved_by: Option<i64> = row.try_get("solved_by")?;
+        if let (Some(solve_time_ms), Some(solved_by)) = (solve_time_ms, solved_by) {
+            room.completion = Some(Completion {
+                solve_time_ms: solve_time_ms as u64,
+                solved_by: solved_by as SessionId,
+            });
+        }
+        room.access_keys = AccessKeys {
+            editor: read_key(row.try_get("editor_key")?)?.into(),
+            viewer: read_key(row.try_get("viewer_key")?)?.into(),
+            keyless: row.try_get("keyless")?,
+        };
+        room.locked = row.try_get("locked")?;
+        // like the access keys, rooms written before hosts existed keep their random host key
+        let host_key: Option<Vec<u8>> = row.try_get("host_key")?;
+        if let Some(host_key) = host_key {
+            room.host_key = read_key(host_key)?.into();
+        }
+        room.closed = row.try_get("closed")?;
+        let revision: i64 = row.try_get("revision")?;
+        let next_event_seq: i64 = sqlx::query(
+            "select coalesce(max(seq) + 1, 0) as next_seq from room_events where room_id = $1",
+        )
+        .bind(room_id_blob)
+        .fetch_one(&self.pool)
+        .await?
+        .try_get("next_seq")?;
+        room.resume_event_log(revision as Revision, next_event_seq as EventSeq);
+        Ok(Some(room))
+    }
+
+    async fn read_events_inner(&self, room_id: RoomId) -> Result<Vec<RoomEvent>, StorageError> {
+        let room_id_blob = &u128::from(room_id).to_le_bytes()[..];
+        let rows = sqlx::query(
+            "select seq, applied_at, session_id, sync_id, revision, event \
+            from room_events where room_id = $1 order by seq",
+        )
+        .bind(room_id_blob)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let event: String = row.try_get("event")?;
+                let kind: RoomEventKind = serde_json::from_str(&event)
+                    .map_err(|_| StorageError::Deserialization("event was not valid"))?;
+                let sync_id: Option<i64> = row.try_get("sync_id")?;
+                Ok(RoomEvent {
+                    seq: row.try_get::<i64, _>("seq")? as EventSeq,
+                    applied_at: Utc.timestamp_millis(row.try_get("applied_at")?),
+                    session_id: row.try_get::<i64, _>("session_id")? as SessionId,
+                    sync_id: sync_id.map(|sync_id| sync_id as ClientSyncId),
+                    revision: row.try_get::<i64, _>("revision")? as Revision,
+                    kind,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Storage for PostgresStorage {
+    fn read_room<'a>(
+        &'a self,
+        room_id: RoomId,
+        limits: &'a LimitsConfig,
+    ) -> BoxFuture<'a, Result<Option<RoomState>, StorageError>> {
+        self.read_room_inner(room_id, limits).boxed()
+    }
+
+    fn write_rooms<'a>(
+        &'a self,
+        records: &'a [RoomRecord],
+    ) -> BoxFuture<'a, Result<Vec<Result<(), StorageError>>, StorageError>> {
+        self.write_rooms_inner(records).boxed()
+    }
+
+    fn read_events(&self, room_id: RoomId) -> BoxFuture<'_, Result<Vec<RoomEvent>, StorageError>> {
+        self.read_events_inner(room_id).boxed()
+    }
+}
+
+async fn write_room(
+    tx: &mut sqlx::Transaction<'_, Database>,
+    record: &RoomRecord,
+) -> Result<(), sqlx::Error> {
+    let room_id_blob = &u128::from(record.room_id).to_le_bytes()[..];
+    sqlx::query(
+        "insert into rooms \
+        (id, board, started_at, solve_time_ms, solved_by, editor_key, viewer_key, revision, \
+        locked, host_key, closed, keyless) \
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) \
+        on conflict (id) do update set \
+        board = excluded.board, started_at = excluded.started_at, \
+        solve_time_ms = excluded.solve_time_ms, solved_by = excluded.solved_by, \
+        editor_key = excluded.editor_key, viewer_key = excluded.viewer_key, \
+        revision = excluded.revision, locked = excluded.locked, \
+        host_key = excluded.host_key, closed = excluded.closed, keyless = excluded.keyless",
+    )
+    .bind(room_id_blob)
+    .bind(record.board.sql_serialize())
+    .bind(record.started_at.timestamp_millis())
+    .bind(record.completion.map(|c| c.solve_time_ms as i64))
+    .bind(record.completion.map(|c| c.solved_by as i64))
+    .bind(&u128::from(record.access_keys.editor).to_le_bytes()[..])
+    .bind(&u128::from(record.access_keys.viewer).to_le_bytes()[..])
+    .bind(record.revision as i64)
+    .bind(record.locked)
+    .bind(&u128::from(record.host_key).to_le_bytes()[..])
+    .bind(record.closed)
+    .bind(record.access_keys.keyless)
+    .execute(&mut *tx)
+    .await?;
+    for event in record.events.iter() {
+        let kind =
+            serde_json::to_string(&event.kind).map_err(|err| sqlx::Error::Decode(Box::new(err)))?;
+        // Events that were written before, but whose transaction failed to commit, can be
+        // retried. They're identical, so there's nothing to update.
+        sqlx::query(
+            "insert into room_events \
+            (room_id, seq, applied_at, session_id, sync_id, revision, event) \
+            values ($1, $2, $3, $4, $5, $6, $7) \
+            on conflict (room_id, seq) do nothing",
+        )
+        .bind(room_id_blob)
+        .bind(event.seq as i64)
+        .bind(event.applied_at.timestamp_millis())
+        .bind(event.session_id as i64)
+        .bind(event.sync_id.map(|sync_id| sync_id as i64))
+        .bind(event.revision as i64)
+        .bind(kind)
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+fn read_key(blob: Vec<u8>) -> Result<u128, StorageError> {
+    let bytes: [u8; 16] = blob
+        .try_into()
+        .map_err(|_| StorageError::Deserialization("key blob was the wrong size"))?;
+    Ok(u128::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::check_storage;
+
+    /// Runs against the database in `SUDOKU_TEST_POSTGRES_URI`. It needs a database, so it's
+    /// ignored unless asked for, e.g. `SUDOKU_TEST_POSTGRES_URI=postgres://localhost/sudoku_test
+    /// cargo test --features postgres -- --ignored`.
+    #[tokio::test(threaded_scheduler)]
+    #[ignore]
+    async fn postgres_storage() {
+        let uri = std::env::var("SUDOKU_TEST_POSTGRES_URI")
+            .expect("set SUDOKU_TEST_POSTGRES_URI to the database to test against");
+        check_storage(&PostgresStorage::connect(&uri).await.unwrap()).await;
+    }
+}
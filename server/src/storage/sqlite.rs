@@ -0,0 +1,389 @@
+use chrono::{TimeZone, Utc};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use log::info;
+use std::convert::TryInto;
+
+use crate::config::LimitsConfig;
+use crate::room::{
+    AccessKeys, ClientSyncId, Completion, EventSeq, Revision, RoomEvent, RoomEventKind, RoomId,
+    RoomState, SessionId,
+};
+use crate::storage::{RoomRecord, Storage, StorageError};
+
+type Database = sqlx::Sqlite;
+
+/// Stores rooms in SQLite. Queries are checked at compile time against `sqlx-data.json`.
+pub struct SqliteStorage {
+    pool: sqlx::Pool<Database>,
+}
+
+impl SqliteStorage {
+    pub async fn connect(uri: &str) -> Result<Self, StorageError> {
+        let pool = sqlx::Pool::connect(uri).await?;
+        sqlx::migrate!("./migrations")
+            .run(&pool)
+            .await
+            .map_err(sqlx::Error::from)?;
+        Ok(SqliteStorage { pool })
+    }
+
+    async fn write_rooms_inner(
+        &self,
+        records: &[RoomRecord],
+    ) -> Result<Vec<Result<(), StorageError>>, StorageError> {
+        // Use a transaction to avoid having to flush every write to disk individually. This could
+        // be a large transaction, so it might make sense to chunk the work up in the future to
+        // reduce memory usage.
+        let mut tx = self.pool.begin().await?;
+        let mut results = Vec::with_capacity(records.len());
+        for record in records {
+            // don't return an error, that would kill the rest of the transaction
+            results.push(
+                write_room(&mut tx, record)
+                    .await
+                    .map_err(StorageError::from),
+            );
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+
+    async fn read_room_inner(
+        &self,
+        room_id: RoomId,
+        limits: &LimitsConfig,
+    ) -> Result<Option<RoomState>, StorageError> {
+        // Ids and keys used to be stored in the machine's native byte order. That's the same as
+        // little-endian on most machines, but rooms written on a big-endian one have to be moved
+        // over before they can be found.
+        if cfg!(target_endian = "big") {
+            self.migrate_big_endian_room(room_id).await?;
+        }
+        let room_id_blob = u128::from(room_id).to_le_bytes();
+        let room_id_blob = &room_id_blob[..];
+        let row = match sqlx::query!(
+            "select board, started_at, solve_time_ms, solved_by, editor_key, viewer_key, revision, \
+            locked, host_key, closed, keyless from rooms where id = ?",
+            room_id_blob
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let mut room = RoomState::sql_deserialize(room_id, &row.board, limits)
+            .map_err(StorageError::Deserialization)?;
+        if let Some(started_at) = row.started_at {
+            room.started_at = Utc.timestamp_millis(started_at);
+        }
+        if let (Some(solve_time_ms), Some(solved_by)) = (row.solve_time_ms, row.solved_by) {
+            room.completion = Some(Completion {
+                solve_time_ms: solve_time_ms as u64,
+                solved_by: solved_by as SessionId,
+            });
+        }
+        // Rooms written before access keys existed keep the random keys that RoomState::new
+        // generated. They get written back along with the rest of the room, and nobody needs
+        // them until the room stops being keyless.
+        if let (Some(editor_key), Some(viewer_key)) = (row.editor_key, row.viewer_key) {
+            room.access_keys = AccessKeys {
+                editor: read_key(editor_key)?.into(),
+                viewer: read_key(viewer_key)?.into(),
+                keyless: false,
+            };
+        }
+        room.access_keys.keyless = row.keyless;
+        room.locked = row.locked;
+        // like the access keys, rooms written before hosts existed keep their random host key
+        if let Some(host_key) = row.host_key {
+            room.host_key = read_key(host_key)?.into();
+        }
+        room.closed = row.closed;
+        let next_event_seq = sqlx::query!(
+            r#"select coalesce(max(seq) + 1, 0) as "next_seq: i64" from room_events where room_id = ?"#,
+            room_id_blob
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .next_seq as EventSeq;
+        room.resume_event_log(row.revision.unwrap_or(0) as Revision, next_event_seq);
+        Ok(Some(room))
+    }
+
+    /// Rewrites a room whose id and keys were stored big-endian, along with its events, to use
+    /// little-endian ones. Does nothing if there's no such room.
+    async fn migrate_big_endian_room(&self, room_id: RoomId) -> Result<(), StorageError> {
+        let new_id_blob = u128::from(room_id).to_le_bytes();
+        let new_id_blob = &new_id_blob[..];
+        let old_id_blob = u128::from(room_id).to_be_bytes();
+        let old_id_blob = &old_id_blob[..];
+        if old_id_blob == new_id_blob {
+            return Ok(());
+        }
+        let mut tx = self.pool.begin().await?;
+        let row = match sqlx::query!(
+            "select editor_key, viewer_key from rooms where id = ?",
+            old_id_blob
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        {
+            Some(row) => row,
+            None => return Ok(()),
+        };
+        // reversing a big-endian blob makes it little-endian
+        let reverse = |mut blob: Vec<u8>| {
+            blob.reverse();
+            blob
+        };
+        let editor_key = row.editor_key.map(reverse);
+        let viewer_key = row.viewer_key.map(reverse);
+        sqlx::query!(
+            "update rooms set id = ?, editor_key = ?, viewer_key = ? where id = ?",
+            new_id_blob,
+            editor_key,
+            viewer_key,
+            old_id_blob,
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "update room_events set room_id = ? where room_id = ?",
+            new_id_blob,
+            old_id_blob,
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        info!("Migrated room {} to little-endian ids and keys", room_id);
+        Ok(())
+    }
+
+    async fn read_events_inner(&self, room_id: RoomId) -> Result<Vec<RoomEvent>, StorageError> {
+        let room_id_blob = u128::from(room_id).to_le_bytes();
+        let room_id_blob = &room_id_blob[..];
+        let rows = sqlx::query!(
+            "select seq, applied_at, session_id, sync_id, revision, event \
+            from room_events where room_id = ? order by seq",
+            room_id_blob
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let kind: RoomEventKind = serde_json::from_str(&row.event)
+                    .map_err(|_| StorageError::Deserialization("event was not valid"))?;
+                Ok(RoomEvent {
+                    seq: row.seq as EventSeq,
+                    applied_at: Utc.timestamp_millis(row.applied_at),
+                    session_id: row.session_id as SessionId,
+                    sync_id: row.sync_id.map(|sync_id| sync_id as ClientSyncId),
+                    revision: row.revision as Revision,
+                    kind,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn read_room<'a>(
+        &'a self,
+        room_id: RoomId,
+        limits: &'a LimitsConfig,
+    ) -> BoxFuture<'a, Result<Option<RoomState>, StorageError>> {
+        self.read_room_inner(room_id, limits).boxed()
+    }
+
+    fn write_rooms<'a>(
+        &'a self,
+        records: &'a [RoomRecord],
+    ) -> BoxFuture<'a, Result<Vec<Result<(), StorageError>>, StorageError>> {
+        self.write_rooms_inner(records).boxed()
+    }
+
+    fn read_events(&self, room_id: RoomId) -> BoxFuture<'_, Result<Vec<RoomEvent>, StorageError>> {
+        self.read_events_inner(room_id).boxed()
+    }
+}
+
+async fn write_room(
+    tx: &mut sqlx::Transaction<'_, Database>,
+    record: &RoomRecord,
+) -> Result<(), sqlx::Error> {
+    let room_id_blob = u128::from(record.room_id).to_le_bytes();
+    let room_id_blob = &room_id_blob[..];
+    let board_blob = record.board.sql_serialize();
+    let board_blob = &board_blob[..];
+    let started_at = record.started_at.timestamp_millis();
+    let solve_time_ms = record.completion.map(|c| c.solve_time_ms as i64);
+    let solved_by = record.completion.map(|c| c.solved_by as i64);
+    let editor_key_blob = u128::from(record.access_keys.editor).to_le_bytes();
+    let editor_key_blob = &editor_key_blob[..];
+    let viewer_key_blob = u128::from(record.access_keys.viewer).to_le_bytes();
+    let viewer_key_blob = &viewer_key_blob[..];
+    let host_key_blob = u128::from(record.host_key).to_le_bytes();
+    let host_key_blob = &host_key_blob[..];
+    let revision = record.revision as i64;
+    sqlx::query!(
+        "insert or replace into rooms \
+        (id, board, started_at, solve_time_ms, solved_by, editor_key, viewer_key, revision, \
+        locked, host_key, closed, keyless) \
+        values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        room_id_blob,
+        board_blob,
+        started_at,
+        solve_time_ms,
+        solved_by,
+        editor_key_blob,
+        viewer_key_blob,
+        revision,
+        record.locked,
+        host_key_blob,
+        record.closed,
+        record.access_keys.keyless,
+    )
+    .execute(&mut *tx)
+    .await?;
+    for event in record.events.iter() {
+        write_event(&mut *tx, room_id_blob, event).await?;
+    }
+    Ok(())
+}
+
+async fn write_event(
+    tx: &mut sqlx::Transaction<'_, Database>,
+    room_id_blob: &[u8],
+    event: &RoomEvent,
+) -> Result<(), sqlx::Error> {
+    let seq = event.seq as i64;
+    let applied_at = event.applied_at.timestamp_millis();
+    let session_id = event.session_id as i64;
+    let sync_id = event.sync_id.map(|sync_id| sync_id as i64);
+    let revision = event.revision as i64;
+    let kind =
+        serde_json::to_string(&event.kind).map_err(|err| sqlx::Error::Decode(Box::new(err)))?;
+    // Events that were written before, but whose transaction failed to commit, can be retried.
+    // They're identical, so there's nothing to update, and the log stays append-only.
+    sqlx::query!(
+        "insert into room_events \
+        (room_id, seq, applied_at, session_id, sync_id, revision, event) \
+        values (?, ?, ?, ?, ?, ?, ?) \
+        on conflict (room_id, seq) do nothing",
+        room_id_blob,
+        seq,
+        applied_at,
+        session_id,
+        sync_id,
+        revision,
+        kind,
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+fn read_key(blob: Vec<u8>) -> Result<u128, StorageError> {
+    let bytes: [u8; 16] = blob
+        .try_into()
+        .map_err(|_| StorageError::Deserialization("key blob was the wrong size"))?;
+    Ok(u128::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::Access;
+    use crate::storage::tests::check_storage;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn sqlite_storage() {
+        check_storage(&SqliteStorage::connect("sqlite::memory:").await.unwrap()).await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn migrate_big_endian_room() {
+        let storage = SqliteStorage::connect("sqlite::memory:").await.unwrap();
+        let room_id = RoomId::random();
+        let limits = LimitsConfig::default();
+        let mut rs = RoomState::new(room_id, &limits);
+        rs.apply_diffs(1, 1, None, vec![]).unwrap();
+        rs.set_locked(true);
+        let access_keys = rs.access_keys;
+        storage
+            .write_rooms(&[RoomRecord::take(&mut rs)])
+            .await
+            .unwrap();
+
+        // make it look like it was written on a big-endian machine
+        let be = |value: u128| value.to_be_bytes().to_vec();
+        let le = |value: u128| value.to_le_bytes().to_vec();
+        sqlx::query("update rooms set id = ?, editor_key = ?, viewer_key = ? where id = ?")
+            .bind(be(room_id.into()))
+            .bind(be(access_keys.editor.into()))
+            .bind(be(access_keys.viewer.into()))
+            .bind(le(room_id.into()))
+            .execute(&storage.pool)
+            .await
+            .unwrap();
+        sqlx::query("update room_events set room_id = ? where room_id = ?")
+            .bind(be(room_id.into()))
+            .bind(le(room_id.into()))
+            .execute(&storage.pool)
+            .await
+            .unwrap();
+        assert!(storage.read_room(room_id, &limits).await.unwrap().is_none());
+
+        storage.migrate_big_endian_room(room_id).await.unwrap();
+        let read = storage.read_room(room_id, &limits).await.unwrap().unwrap();
+        assert_eq!(read.access_keys, access_keys);
+        assert!(read.locked);
+        assert_eq!(storage.read_events(room_id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn legacy_rooms_are_keyless() {
+        let storage = SqliteStorage::connect("sqlite::memory:").await.unwrap();
+        let room_id = RoomId::random();
+        let limits = LimitsConfig::default();
+        let mut rs = RoomState::new(room_id, &limits);
+        storage
+            .write_rooms(&[RoomRecord::take(&mut rs)])
+            .await
+            .unwrap();
+        // what the migration does to rooms written before access keys existed
+        sqlx::query(
+            "update rooms set editor_key = null, viewer_key = null, keyless = true where id = ?",
+        )
+        .bind(u128::from(room_id).to_le_bytes().to_vec())
+        .execute(&storage.pool)
+        .await
+        .unwrap();
+
+        let mut read = storage.read_room(room_id, &limits).await.unwrap().unwrap();
+        assert_eq!(read.check_access(None), Some(Access::Editor));
+        // the new keys stick once they're written back, so the links handed out keep working
+        let access_keys = read.access_keys;
+        storage
+            .write_rooms(&[RoomRecord::take(&mut read)])
+            .await
+            .unwrap();
+        let mut read = storage.read_room(room_id, &limits).await.unwrap().unwrap();
+        assert_eq!(read.access_keys, access_keys);
+
+        assert_eq!(
+            read.check_access(Some(&access_keys.viewer)),
+            Some(Access::Viewer)
+        );
+        assert_eq!(read.check_access(None), None);
+        storage
+            .write_rooms(&[RoomRecord::take(&mut read)])
+            .await
+            .unwrap();
+        let mut read = storage.read_room(room_id, &limits).await.unwrap().unwrap();
+        assert!(!read.access_keys.keyless);
+        assert_eq!(read.check_access(None), None);
+    }
+}
@@ -0,0 +1,42 @@
+use log::{error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{self, Instant};
+
+use crate::global_state::GlobalState;
+use crate::storage::{self, Storage};
+
+/// Flushes dirty rooms back to storage every `period` until `stop_rx` fires.
+///
+/// This limits how much data is lost if the server exits uncleanly. A failed flush is logged, and
+/// the affected rooms stay dirty so that they're retried on the next tick. A flush that's already
+/// in progress is never interrupted by `stop_rx`, so the final writeback in `main` can't race
+/// with a half-finished transaction.
+pub async fn run_periodically(
+    storage: Arc<dyn Storage>,
+    global_state: Arc<GlobalState>,
+    period: Duration,
+    mut stop_rx: oneshot::Receiver<()>,
+) {
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut stop_rx => return,
+        }
+        let start = Instant::now();
+        match storage::writeback(&*storage, &global_state).await {
+            Ok(room_count) => info!(
+                "Flushed {} dirty room(s) to storage in {:?}",
+                room_count,
+                start.elapsed()
+            ),
+            Err(err) => error!(
+                "Failed to flush dirty rooms to storage after {:?}: {}",
+                start.elapsed(),
+                err
+            ),
+        }
+    }
+}
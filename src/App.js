@@ -36,12 +36,23 @@ const initialBoard = searchParams.has("board")
   ? decodeBoard(searchParams.get("board"))
   : BoardState.empty();
 const initialRoomId = searchParams.get("room");
+const initialKey = searchParams.get("key");
+// set in links to rooms created through the HTTP API, for whoever should host it
+const initialHostKey = searchParams.get("host");
 
 export default function App() {
   const [gameState, setGameState] = useState(() => {
     if (initialRoomId) {
       const gs = new RemoteGameState();
-      gs.connect(initialRoomId);
+      gs.connect(initialRoomId, initialKey, null, initialHostKey).then(() => {
+        // rooms from before keys existed can be joined without one, but only
+        // until somebody uses a key, so keep ours in the URL
+        if (!initialKey && gs.key) {
+          const url = new URL(window.location.href);
+          url.searchParams.set("key", gs.key);
+          window.history.replaceState(null, "", url.toString());
+        }
+      });
       return gs;
     } else {
       return new LocalGameState(initialBoard);
@@ -0,0 +1,62 @@
+import * as Immutable from "immutable";
+
+import diffsFixture from "./__fixtures__/boardDiffs.json";
+import { applyDiffsToLocalBoardState, BoardDiff } from "./BoardDiffs";
+import BoardState from "./BoardState";
+
+// the server's tests check that it serializes the same diffs to this fixture
+test("diffs serialize like the server's", () => {
+  const diffs: BoardDiff[] = [
+    { squares: [0, 1], operation: { fn: "setNumber", digit: 5 } },
+    { squares: [2], operation: { fn: "setNumber", digit: null } },
+    {
+      squares: [3],
+      operation: { fn: "addPencilMark", type: "corners", digit: 1 },
+    },
+    {
+      squares: [3],
+      operation: { fn: "removePencilMark", type: "centers", digit: 9 },
+    },
+    { squares: [4, 5], operation: { fn: "clearPencilMarks", type: "corners" } },
+    { squares: [0, 1], operation: { fn: "addColor", color: 4 } },
+    { squares: [1], operation: { fn: "removeColor", color: 8 } },
+    { squares: [2], operation: { fn: "clearColors" } },
+    { squares: [6], operation: { fn: "setLocked", locked: true } },
+  ];
+  expect(JSON.parse(JSON.stringify(diffs))).toEqual(diffsFixture);
+});
+
+test("color operations apply to squares", () => {
+  let board = applyDiffsToLocalBoardState(BoardState.empty(), [
+    { squares: [0, 1], operation: { fn: "addColor", color: 4 } },
+    { squares: [0], operation: { fn: "addColor", color: 1 } },
+    { squares: [1], operation: { fn: "removeColor", color: 4 } },
+  ]);
+  expect(board.squares.get(0)?.get("colors")).toEqual(Immutable.Set([1, 4]));
+  expect(board.squares.get(1)?.get("colors")).toEqual(Immutable.Set());
+
+  board = applyDiffsToLocalBoardState(board, [
+    { squares: [0], operation: { fn: "clearColors" } },
+  ]);
+  expect(board.squares.get(0)?.get("colors").isEmpty()).toBe(true);
+});
+
+test("locked squares ignore color operations", () => {
+  const board = applyDiffsToLocalBoardState(
+    BoardState.withNumbers([5, ...Array(80).fill(null)]),
+    [{ squares: [0], operation: { fn: "addColor", color: 2 } }]
+  );
+  expect(board.squares.get(0)?.get("colors").isEmpty()).toBe(true);
+});
+
+test("locked squares can still be unlocked", () => {
+  const board = applyDiffsToLocalBoardState(
+    BoardState.withNumbers([5, ...Array(80).fill(null)]),
+    [
+      { squares: [0], operation: { fn: "setLocked", locked: false } },
+      { squares: [0], operation: { fn: "addColor", color: 2 } },
+    ]
+  );
+  expect(board.squares.get(0)?.get("locked")).toBe(false);
+  expect(board.squares.get(0)?.get("colors")).toEqual(Immutable.Set([2]));
+});
@@ -34,11 +34,32 @@ export type ClearPencilMarksOperation = {
   fn: "clearPencilMarks";
   type: ValueOf<typeof BoardPencilType>;
 };
+// colors are indexes from 0 to 8, the client decides what they look like
+export type AddColorOperation = {
+  fn: "addColor";
+  color: number;
+};
+export type RemoveColorOperation = {
+  fn: "removeColor";
+  color: number;
+};
+export type ClearColorsOperation = {
+  fn: "clearColors";
+};
+// only the host can send this, but everybody receives it
+export type SetLockedOperation = {
+  fn: "setLocked";
+  locked: boolean;
+};
 export type BoardDiffOperation =
   | SetNumberOperation
   | AddPencilMarkOperation
   | RemovePencilMarkOperation
-  | ClearPencilMarksOperation;
+  | ClearPencilMarksOperation
+  | AddColorOperation
+  | RemoveColorOperation
+  | ClearColorsOperation
+  | SetLockedOperation;
 
 export type BoardDiff = {
   squares: number[];
@@ -49,7 +70,8 @@ function applyDiffOperationToSquare(
   square: LocalBoardSquare,
   operation: BoardDiffOperation
 ): LocalBoardSquare {
-  if (square.get("locked")) {
+  // locked squares can only be unlocked
+  if (square.get("locked") && operation.fn !== "setLocked") {
     return square;
   }
   switch (operation.fn) {
@@ -61,6 +83,16 @@ function applyDiffOperationToSquare(
       return square.update(operation.type, (pm) => pm.delete(operation.digit));
     case "clearPencilMarks":
       return square.set(operation.type, Immutable.Set());
+    case "addColor":
+      return square.update("colors", (colors) => colors.add(operation.color));
+    case "removeColor":
+      return square.update("colors", (colors) =>
+        colors.delete(operation.color)
+      );
+    case "clearColors":
+      return square.set("colors", Immutable.Set());
+    case "setLocked":
+      return square.set("locked", operation.locked);
     default:
       throw new Error(
         `Tried call applyDiffs with invalid operation: ${operation}`
@@ -6,6 +6,7 @@ export type BoardSquareProps = {
   number: number | null;
   corners: Immutable.Set<number>;
   centers: Immutable.Set<number>;
+  colors: Immutable.Set<number>;
   locked: boolean;
 };
 
@@ -13,6 +14,7 @@ const boardSquareDefaultValues: BoardSquareProps = {
   number: null,
   corners: Immutable.Set(),
   centers: Immutable.Set(),
+  colors: Immutable.Set(),
   locked: false,
 };
 
@@ -10,6 +10,7 @@ type ServerBoardSquare = {
   number: number | null;
   corners: number[];
   centers: number[];
+  colors: number[];
   locked: boolean;
 };
 
@@ -17,6 +18,13 @@ type ServerBoardState = {
   squares: ServerBoardSquare[];
 };
 
+type InputMode = "normal" | "corners" | "centers";
+type Profile = {
+  name: string;
+  color: number;
+  mode: InputMode;
+};
+
 type SetBoardStateRequestMessage = {
   type: "setBoardState";
   boardState: ServerBoardState;
@@ -24,34 +32,179 @@ type SetBoardStateRequestMessage = {
 type ApplyDiffsRequestMessage = {
   type: "applyDiffs";
   syncId: number;
+  baseRevision: number;
   diffs: BoardDiff[];
 };
-type RequestMessage = SetBoardStateRequestMessage | ApplyDiffsRequestMessage;
+type UndoRequestMessage = {
+  type: "undo" | "redo";
+  syncId: number;
+};
+type UpdateProfileRequestMessage = {
+  type: "updateProfile";
+  name?: string;
+  color?: number;
+  mode?: InputMode;
+};
+// the rest of these can only be sent by the host
+type TransferHostRequestMessage = {
+  type: "transferHost";
+  sessionId: number;
+};
+// like undo, these are applied as a diff group tagged with syncId
+type SetSquaresLockedRequestMessage = {
+  type: "setSquaresLocked";
+  syncId: number;
+  squares: number[];
+  locked: boolean;
+};
+type ResetPencilMarksRequestMessage = {
+  type: "resetPencilMarks";
+  syncId: number;
+};
+type KickRequestMessage = {
+  type: "kick";
+  sessionId: number;
+};
+// kicked sessions can rejoin with the old keys until they're replaced
+type RotateKeysRequestMessage = {
+  type: "rotateKeys";
+};
+type CloseRoomRequestMessage = {
+  type: "closeRoom";
+};
+type RequestMessage =
+  | SetBoardStateRequestMessage
+  | ApplyDiffsRequestMessage
+  | UndoRequestMessage
+  | UpdateProfileRequestMessage
+  | TransferHostRequestMessage
+  | SetSquaresLockedRequestMessage
+  | ResetPencilMarksRequestMessage
+  | KickRequestMessage
+  | RotateKeysRequestMessage
+  | CloseRoomRequestMessage;
+
+type UndoStatus = {
+  canUndo: boolean;
+  canRedo: boolean;
+};
+
+type Completion = {
+  solveTimeMs: number;
+  solvedBy: number;
+};
+
+export type Access = "editor" | "viewer";
+
+export type ShareLinks = {
+  editor: string;
+  viewer: string;
+};
 
 type InitResponseMessage = {
   type: "init";
   roomId: string;
+  sessionId: number;
+  // pass this back as the "resume" query param when reconnecting
+  resumeToken: string;
   boardState: ServerBoardState;
+  revision: number;
+  conflicts: number[];
+  completion: Completion | null;
+  access: Access;
+  // only sent to editors
+  shareLinks: ShareLinks | null;
+  // while locked, the server rejects any changes to the board
+  locked: boolean;
+  host: number | null;
+  // only sent to the host. pass this back as the "host" query param when
+  // reconnecting without resuming to become the host again
+  hostKey: string | null;
+};
+type ResumedResponseMessage = {
+  type: "resumed";
+  sessionId: number;
+  undoStatus: UndoStatus;
 };
 type PartialUpdateResponseMessage = {
   type: "partialUpdate";
   syncId: number;
   diffs: BoardDiff[];
+  revision: number;
+  conflicts: number[];
+  // only set for our own diffs
+  undoStatus: UndoStatus | null;
 };
 type FullUpdateResponseMessage = {
   type: "fullUpdate";
   syncId: number;
   boardState: ServerBoardState;
+  revision: number;
+  conflicts: number[];
+  completion: Completion | null;
+  undoStatus: UndoStatus;
 };
+type SolvedResponseMessage = {
+  type: "solved";
+} & Completion;
 type UpdateCursorResponseMessage = {
   type: "updateCursor";
-  map: {[colorIdx: string]: number[]};
+  map: { [sessionId: string]: { selection: number[] } & Profile };
+};
+type RosterResponseMessage = {
+  type: "roster";
+  sessions: ({ sessionId: number } & Profile)[];
+};
+type LockedResponseMessage = {
+  type: "locked";
+  locked: boolean;
+};
+// hostKey is only sent to the new host
+type HostChangedResponseMessage = {
+  type: "hostChanged";
+  host: number | null;
+  hostKey: string | null;
+};
+// the server closes the connection after this, and the session can't be
+// resumed
+type KickedResponseMessage = {
+  type: "kicked";
+};
+// the old key stops working, so use this one to reconnect. like in "init",
+// shareLinks is only sent to editors
+type KeysRotatedResponseMessage = {
+  type: "keysRotated";
+  key: string;
+  shareLinks: ShareLinks | null;
+};
+// the client should disconnect once its changes are confirmed, and reconnect
+// after the given delay. the session usually can't be resumed on another
+// server, in which case we get a fresh "init" instead of "resumed"
+type ServerShuttingDownResponseMessage = {
+  type: "serverShuttingDown";
+  reconnectAfterMs: number;
+};
+// syncId is set if the request was a diff group (or an undo, or one of the
+// host's diff groups), which the server didn't apply
+type ErrorResponseMessage = {
+  type: "error";
+  message: string;
+  syncId: number | null;
 };
 type ResponseMessage =
   | InitResponseMessage
+  | ResumedResponseMessage
   | PartialUpdateResponseMessage
   | FullUpdateResponseMessage
-  | UpdateCursorResponseMessage;
+  | SolvedResponseMessage
+  | UpdateCursorResponseMessage
+  | RosterResponseMessage
+  | LockedResponseMessage
+  | HostChangedResponseMessage
+  | KickedResponseMessage
+  | KeysRotatedResponseMessage
+  | ServerShuttingDownResponseMessage
+  | ErrorResponseMessage;
 
 function toLocalBoardState(serverBs: ServerBoardState): LocalBoardState {
   return new LocalBoardState(
@@ -61,6 +214,7 @@ function toLocalBoardState(serverBs: ServerBoardState): LocalBoardState {
           number: sq.number,
           corners: Immutable.Set(sq.corners),
           centers: Immutable.Set(sq.centers),
+          colors: Immutable.Set(sq.colors),
           locked: sq.locked,
         })
       )
@@ -79,9 +233,59 @@ const FORCE_LOCALHOST = new URLSearchParams(window.location.search).has(
   "localhost"
 );
 
-function getUri(roomId?: string | null): string {
+type ConnectParams = {
+  key?: string | null;
+  // both of these are needed to resume a session
+  resume?: string | null;
+  revision?: number | null;
+  // makes us the host if we can't resume (e.g. after a restart)
+  host?: string | null;
+};
+
+function getUri(roomId?: string | null, params: ConnectParams = {}): string {
   const base = FORCE_LOCALHOST ? LOCALHOST_REALTIME_API_URI : REALTIME_API_URI;
-  return roomId == null ? base : base + roomId;
+  if (roomId == null) {
+    return base;
+  }
+  const query = new URLSearchParams();
+  if (params.key != null) {
+    query.set("key", params.key);
+  }
+  if (params.resume != null && params.revision != null) {
+    query.set("resume", params.resume);
+    query.set("revision", String(params.revision));
+  }
+  if (params.host != null) {
+    query.set("host", params.host);
+  }
+  const queryString = query.toString();
+  return queryString === "" ? base + roomId : base + roomId + "?" + queryString;
+}
+
+// host keys are kept across page loads, so that whoever created a room can get
+// the host role back, even after the server restarts
+const HOST_KEY_STORAGE_PREFIX = "sudoku-host-key:";
+
+function loadHostKey(roomId: string): string | null {
+  return window.localStorage.getItem(HOST_KEY_STORAGE_PREFIX + roomId);
+}
+
+function saveHostKey(roomId: string, hostKey: string | null): void {
+  if (hostKey == null) {
+    // we aren't the host, so whatever key we had doesn't work anymore
+    window.localStorage.removeItem(HOST_KEY_STORAGE_PREFIX + roomId);
+  } else {
+    window.localStorage.setItem(HOST_KEY_STORAGE_PREFIX + roomId, hostKey);
+  }
+}
+
+// reloading the page shouldn't try the old key after the keys are rotated
+function replaceKeyInUrl(key: string): void {
+  const url = new URL(window.location.href);
+  if (url.searchParams.has("key")) {
+    url.searchParams.set("key", key);
+    window.history.replaceState(null, "", url.toString());
+  }
 }
 
 function nullthrows<T>(value: T | null | undefined): T {
@@ -98,26 +302,71 @@ export default class RemoteGameState extends BaseGameState {
   // what the UI should currently show
   private clientBoardState: LocalBoardState = LocalBoardState.empty();
   // the diffs that make up the difference between the server's board state and
-  // the client's
-  private unconfirmedDiffGroups: BoardDiff[][] = [];
+  // the client's. groups the server rejected are null, since they'll never be
+  // confirmed
+  private unconfirmedDiffGroups: (BoardDiff[] | null)[] = [];
   // sync IDs are used to figure out what diffs we can remove from the
   // unconfirmedDiffGroups queue
   private lastSentSyncId: number = 0;
   private lastReceivedSyncId: number = 0;
+  // the last revision of the board the server told us about, which is what our
+  // diffs are based on
+  private serverRevision: number = 0;
+  // the server keeps track of what we can undo, since other players' changes
+  // have to be taken into account
+  private undoStatus: UndoStatus = { canUndo: false, canRedo: false };
+  // set once the server asks us to reconnect, and cleared when we disconnect
+  private reconnectAfterMs: number | null = null;
   roomId: string | null = null;
+  // the access key we joined with, which is needed to reconnect
+  key: string | null = null;
+  resumeToken: string | null = null;
+  access: Access | null = null;
+  shareLinks: ShareLinks | null = null;
+  locked: boolean = false;
+  host: number | null = null;
+  hostKey: string | null = null;
 
+  // hostKey is only needed to become the host of a room that somebody else
+  // created for us, e.g. through the HTTP API
   connect(
     roomId?: string | null,
-    initialBoard?: LocalBoardState | null
+    key?: string | null,
+    initialBoard?: LocalBoardState | null,
+    hostKey?: string | null
   ): Promise<void> {
     console.log("connect");
-    const ws = new WebSocket(getUri(roomId));
+    this.key = key ?? null;
+    const host = hostKey ?? (roomId == null ? null : loadHostKey(roomId));
+    return this.open(getUri(roomId, { key, host }), initialBoard);
+  }
+
+  private reconnect(): Promise<void> {
+    console.log("reconnect");
+    return this.open(
+      getUri(this.roomId, {
+        key: this.key,
+        resume: this.resumeToken,
+        revision: this.serverRevision,
+        host: this.hostKey,
+      }),
+      null
+    );
+  }
+
+  private open(
+    uri: string,
+    initialBoard?: LocalBoardState | null
+  ): Promise<void> {
+    const ws = new WebSocket(uri);
     this.ws = ws;
     return new Promise((resolve, reject) => {
       ws.onmessage = (rawMsg: MessageEvent) => {
         const msg: ResponseMessage = JSON.parse(rawMsg.data);
         this.onResponseMessage(msg);
-        if (msg.type === "init") {
+        if (msg.type === "resumed") {
+          resolve();
+        } else if (msg.type === "init") {
           // TODO: This is hacky. We should revise the protocol to allow the
           // client to send an init message before the server sends its init
           // message.
@@ -130,7 +379,7 @@ export default class RemoteGameState extends BaseGameState {
             this.clientBoardState = initialBoard;
             this.triggerBoardStateUpdate(initialBoard);
           }
-          // only resolve after we get an init
+          // only resolve after we get an init (or a resumed)
           resolve();
         }
       };
@@ -156,9 +405,26 @@ export default class RemoteGameState extends BaseGameState {
         this.unconfirmedDiffGroups = [];
         this.lastSentSyncId = 0;
         this.lastReceivedSyncId = 0;
+        this.serverRevision = msg.revision;
         this.roomId = msg.roomId;
+        this.resumeToken = msg.resumeToken;
+        this.access = msg.access;
+        this.shareLinks = msg.shareLinks;
+        if (this.key == null && msg.shareLinks != null) {
+          // we created the room, so we only know the key from our share link
+          this.key = new URL(msg.shareLinks.editor).searchParams.get("key");
+        }
+        this.locked = msg.locked;
+        this.host = msg.host;
+        this.hostKey = msg.hostKey;
+        saveHostKey(msg.roomId, msg.hostKey);
+        this.undoStatus = { canUndo: false, canRedo: false };
         this.triggerBoardStateUpdate(this.clientBoardState);
         break;
+      case "resumed":
+        // the server follows this up with the updates we missed
+        this.undoStatus = msg.undoStatus;
+        break;
       case "partialUpdate":
         if (this.serverBoardState == null) {
           throw new Error("got partialUpdate before init");
@@ -167,16 +433,61 @@ export default class RemoteGameState extends BaseGameState {
           this.serverBoardState,
           msg.diffs
         );
+        this.serverRevision = msg.revision;
+        if (msg.undoStatus != null) {
+          this.undoStatus = msg.undoStatus;
+        }
         this.updateClientBoardState(msg.syncId);
         break;
       case "fullUpdate":
         this.serverBoardState = toLocalBoardState(msg.boardState);
         this.clientBoardState = this.serverBoardState;
+        this.serverRevision = msg.revision;
+        this.undoStatus = msg.undoStatus;
         this.updateClientBoardState(msg.syncId);
         break;
+      case "solved":
+        console.log("solved", msg);
+        break;
       case "updateCursor":
         console.log("updateCursor", msg);
         break;
+      case "roster":
+        console.log("roster", msg);
+        break;
+      case "locked":
+        this.locked = msg.locked;
+        break;
+      case "hostChanged":
+        this.host = msg.host;
+        // the old host's key stops working when the role is handed off
+        this.hostKey = msg.hostKey;
+        if (this.roomId != null) {
+          saveHostKey(this.roomId, msg.hostKey);
+        }
+        break;
+      case "kicked":
+        console.log("kicked", msg);
+        this.resumeToken = null;
+        break;
+      case "keysRotated":
+        this.key = msg.key;
+        if (msg.shareLinks != null) {
+          this.shareLinks = msg.shareLinks;
+        }
+        replaceKeyInUrl(msg.key);
+        break;
+      case "serverShuttingDown":
+        console.log("serverShuttingDown", msg);
+        this.reconnectAfterMs = msg.reconnectAfterMs;
+        this.reconnectIfConfirmed();
+        break;
+      case "error":
+        console.error("error from server", msg.message);
+        if (msg.syncId != null) {
+          this.rejectDiffGroup(msg.syncId);
+        }
+        break;
       default:
         throw new Error(
           `Received unsupported response message type from server: ${JSON.stringify(msg)}`
@@ -188,13 +499,23 @@ export default class RemoteGameState extends BaseGameState {
     // use syncId to update unconfirmedDiffGroups
     if (syncId > this.lastReceivedSyncId) {
       this.unconfirmedDiffGroups.splice(0, syncId - this.lastReceivedSyncId);
+      this.lastReceivedSyncId = syncId;
+    }
+    // the server won't confirm groups it rejected, so once every group before
+    // them is confirmed, they can be dropped
+    while (
+      this.unconfirmedDiffGroups.length > 0 &&
+      this.unconfirmedDiffGroups[0] == null
+    ) {
+      this.unconfirmedDiffGroups.shift();
+      this.lastReceivedSyncId++;
     }
-    this.lastReceivedSyncId = syncId;
 
     // apply unconfirmedDiffGroups to serverBoardState to get the new
     // clientBoardState
     const newClientBoardState = this.unconfirmedDiffGroups.reduce(
-      (st, dfGrp) => applyDiffsToLocalBoardState(st, dfGrp),
+      (st, dfGrp) =>
+        dfGrp == null ? st : applyDiffsToLocalBoardState(st, dfGrp),
       nullthrows(this.serverBoardState)
     );
     // this will often differ by identity, but we should only trigger an
@@ -203,9 +524,47 @@ export default class RemoteGameState extends BaseGameState {
       this.clientBoardState = newClientBoardState;
       this.triggerBoardStateUpdate(newClientBoardState);
     }
+    this.reconnectIfConfirmed();
+  }
+
+  // rolls back a group that the server didn't apply, e.g. because the room is
+  // locked or there was nothing to undo
+  private rejectDiffGroup(syncId: number): void {
+    const idx = syncId - this.lastReceivedSyncId - 1;
+    if (idx < 0 || idx >= this.unconfirmedDiffGroups.length) {
+      return;
+    }
+    this.unconfirmedDiffGroups[idx] = null;
+    this.updateClientBoardState(this.lastReceivedSyncId);
+  }
+
+  // once the server is shutting down, wait for it to confirm all of our
+  // changes, and then go find the next server
+  private reconnectIfConfirmed(): void {
+    if (
+      this.reconnectAfterMs == null ||
+      this.unconfirmedDiffGroups.length > 0
+    ) {
+      return;
+    }
+    const delayMs = this.reconnectAfterMs;
+    this.reconnectAfterMs = null;
+    this.close();
+    window.setTimeout(() => {
+      this.reconnect().catch((err) => console.error("reconnect failed", err));
+    }, delayMs);
+  }
+
+  // spectators can't change the board, and nobody can while the room is
+  // locked. the server would reject the change anyway
+  private canChangeBoard(): boolean {
+    return this.access !== "viewer" && !this.locked;
   }
 
   applyDiffs(diffs: BoardDiff[]): void {
+    if (!this.canChangeBoard()) {
+      return;
+    }
     const newClientBoardState = applyDiffsToLocalBoardState(
       this.clientBoardState,
       diffs
@@ -214,14 +573,82 @@ export default class RemoteGameState extends BaseGameState {
     this.sendRequestMessage({
       type: "applyDiffs",
       syncId: ++this.lastSentSyncId,
+      baseRevision: this.serverRevision,
       diffs,
     });
+    // this is what the server will tell us once it applies the diffs
+    this.undoStatus = { canUndo: true, canRedo: false };
     if (newClientBoardState !== this.clientBoardState) {
       this.clientBoardState = newClientBoardState;
       this.triggerBoardStateUpdate(newClientBoardState);
     }
   }
 
+  canUndo(): boolean {
+    return this.canChangeBoard() && this.undoStatus.canUndo;
+  }
+
+  canRedo(): boolean {
+    return this.canChangeBoard() && this.undoStatus.canRedo;
+  }
+
+  undo(): void {
+    this.sendUndo("undo");
+  }
+
+  redo(): void {
+    this.sendUndo("redo");
+  }
+
+  private sendUndo(type: "undo" | "redo"): void {
+    if (!this.canChangeBoard()) {
+      return;
+    }
+    // we can't predict what the server will do, so the group stays empty until
+    // the server sends back the diffs it applied
+    this.unconfirmedDiffGroups.push([]);
+    this.sendRequestMessage({ type, syncId: ++this.lastSentSyncId });
+  }
+
+  // only the host can lock squares and reset pencil marks. like undo, the
+  // server tells us what changed
+  setSquaresLocked(squares: number[], locked: boolean): void {
+    if (!this.canChangeBoard()) {
+      return;
+    }
+    this.unconfirmedDiffGroups.push([]);
+    this.sendRequestMessage({
+      type: "setSquaresLocked",
+      syncId: ++this.lastSentSyncId,
+      squares,
+      locked,
+    });
+  }
+
+  resetPencilMarks(): void {
+    if (!this.canChangeBoard()) {
+      return;
+    }
+    this.unconfirmedDiffGroups.push([]);
+    this.sendRequestMessage({
+      type: "resetPencilMarks",
+      syncId: ++this.lastSentSyncId,
+    });
+  }
+
+  // only the host can do this. kicking somebody doesn't stop them from
+  // rejoining with the same link, but replacing the keys does
+  rotateKeys(): void {
+    this.sendRequestMessage({ type: "rotateKeys" });
+  }
+
+  updateProfile(profile: Partial<Profile>): void {
+    if (this.access === "viewer") {
+      return;
+    }
+    this.sendRequestMessage({ type: "updateProfile", ...profile });
+  }
+
   getBoardState(): LocalBoardState {
     return this.clientBoardState;
   }
@@ -20,13 +20,19 @@ type Props = {
 // HACK: Button isn't typed, and typescript infers the wrong prop types
 const Button: any = ActualButton;
 
-function getUrl(roomId?: string | null) {
+function getUrl(roomId?: string | null, shareLink?: string | null) {
   const newURL = new URL(window.location.href);
   if (roomId == null) {
     newURL.searchParams.delete("room");
+    newURL.searchParams.delete("key");
+    newURL.searchParams.delete("host");
   } else {
     newURL.searchParams.set("room", roomId);
     newURL.searchParams.delete("board");
+    const key = shareLink && new URL(shareLink).searchParams.get("key");
+    if (key) {
+      newURL.searchParams.set("key", key);
+    }
   }
   return newURL;
 }
@@ -43,14 +49,18 @@ export default function SocialSettings({
     (async () => {
       setIsLoading(true);
       const newGs = new RemoteGameState();
-      await newGs.connect(null, gameState.getBoardState());
+      await newGs.connect(null, null, gameState.getBoardState());
       if (canceled) {
         return;
       }
       setIsLoading(false);
       gameState.close();
       onSetGameState(newGs);
-      window.history.replaceState(null, "", getUrl(newGs.roomId).href);
+      window.history.replaceState(
+        null,
+        "",
+        getUrl(newGs.roomId, newGs.shareLinks?.editor).href
+      );
     })();
     return () => {
       canceled = true;
@@ -71,11 +81,21 @@ export default function SocialSettings({
     }
   }, [isRemote, handlePlayOnline, handlePlayOffline]);
 
+  const shareLinks = isRemote
+    ? (gameState as RemoteGameState).shareLinks
+    : null;
+
   const handleCopyToClipboardClick = useCallback(() => {
-    // the current room ID should be in the URL already
+    // the current room ID and key should be in the URL already
     navigator.clipboard.writeText(window.location.href);
   }, []);
 
+  const handleCopyViewOnlyLinkClick = useCallback(() => {
+    if (shareLinks != null) {
+      navigator.clipboard.writeText(shareLinks.viewer);
+    }
+  }, [shareLinks]);
+
   return (
     <SettingsFlex>
       <SettingsGroup>
@@ -97,6 +117,13 @@ export default function SocialSettings({
               </Button>
             </SettingsListItem>
           )}
+          {shareLinks != null && (
+            <SettingsListItem>
+              <Button onClick={handleCopyViewOnlyLinkClick}>
+                Copy View-Only Link to Clipboard
+              </Button>
+            </SettingsListItem>
+          )}
         </SettingsUl>
       </SettingsGroup>
     </SettingsFlex>